@@ -0,0 +1,92 @@
+//! `Lexer`と`SliceLexer`の速度比較
+//! `cargo bench --bench lexer [行数]`で実行する
+
+use std::env::args;
+use std::hint::black_box;
+use std::io::BufReader;
+use std::time::{Duration, Instant};
+use asm_1st::lexer::{Lexer, LexToken};
+use asm_1st::parser::Parser;
+use asm_1st::slice_lexer::{SliceLexer, Token};
+
+/// min-rtのコンパイラ出力に似せた、ラベルとコメントを含むソースを生成する
+fn generate_source(lines: usize) -> String {
+    let body = [
+        "    addi r1, sp, 4",
+        "    lw r2, fp, 8",
+        "    fadd r3, r2, r1",
+        "    fmul r4, r3, r3   # comment",
+        "    iblt r1, r2, LOOP_0",
+        "    sw r4, sp, 12",
+        "    movl r5, 0x1234",
+        "    movh r5, 0b1010",
+        "    call LOOP_0; subi sp, sp, 16",
+    ];
+
+    let mut src = String::new();
+    let mut n = 0;
+    while n < lines {
+        src.push_str(&format!("LOOP_{}:\n", n / body.len()));
+        for line in body {
+            src.push_str(line);
+            src.push('\n');
+        }
+        n += body.len() + 1;
+    }
+    src
+}
+
+fn measure(name: &str, unit: &str, iterations: u32, bytes: usize, mut f: impl FnMut() -> usize) {
+    let mut best = Duration::MAX;
+    let mut count = 0;
+    for _ in 0..iterations {
+        let start = Instant::now();
+        count = black_box(f());
+        best = best.min(start.elapsed());
+    }
+
+    let mb_per_sec = bytes as f64 / best.as_secs_f64() / 1e6;
+    println!("{name:<24} {:>10.3} ms  {count:>9} {unit:<12}  {mb_per_sec:>8.1} MB/s", best.as_secs_f64() * 1e3);
+}
+
+fn main() {
+    // `cargo bench`は`--bench`を引数として渡してくるので、数値として読めるものだけ見る
+    let lines = args().skip(1)
+        .find_map(|s| s.parse().ok())
+        .unwrap_or(300_000);
+    let src = generate_source(lines);
+    let bytes = src.len();
+    let iterations = 5;
+
+    println!("{lines} lines, {bytes} bytes, best of {iterations}");
+
+    measure("Lexer (BufReader)", "tokens", iterations, bytes, || {
+        let mut lex = Lexer::new(BufReader::new(src.as_bytes()));
+        let mut n = 0;
+        while let Some(Ok((token, _, _))) = lex.next() {
+            n += 1;
+            if token == LexToken::LexEof { break; }
+        }
+        n
+    });
+
+    measure("SliceLexer", "tokens", iterations, bytes, || {
+        let mut lex = SliceLexer::new(src.as_bytes());
+        let mut n = 0;
+        while let Some(Ok((token, _))) = lex.next() {
+            n += 1;
+            if token == Token::Eof { break; }
+        }
+        n
+    });
+
+    measure("Parser + Lexer", "instructions", iterations, bytes, || {
+        let lex = Lexer::new(BufReader::new(src.as_bytes()));
+        Parser::new(lex).parse().map(|(inst, _)| inst.len()).unwrap_or(0)
+    });
+
+    measure("Parser + SliceLexer", "instructions", iterations, bytes, || {
+        let lex = SliceLexer::new(src.as_bytes());
+        Parser::new(lex.tokens()).parse().map(|(inst, _)| inst.len()).unwrap_or(0)
+    });
+}
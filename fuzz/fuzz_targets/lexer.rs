@@ -0,0 +1,36 @@
+//! 任意のバイト列を2つのLexerに通し、panicせず同じトークン列を返すことを確かめる
+//! トリビアを残すモードのspanが入力全体を覆うことも確かめる
+
+#![no_main]
+
+use std::io::BufReader;
+use asm_1st::lexer::Lexer;
+use asm_1st::slice_lexer::{SliceLexer, Token};
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../tests/common/tokens.rs"]
+mod tokens;
+
+use tokens::collect_tokens;
+
+fuzz_target!(|data: &[u8]| {
+    let a = collect_tokens(Lexer::new(BufReader::new(data)));
+    let b = collect_tokens(SliceLexer::new(data).tokens());
+    assert_eq!(a, b);
+
+    // トリビアを残すモードでも、Parserに渡るトークン列は変わらない
+    let c = collect_tokens(SliceLexer::with_trivia(data).tokens());
+    assert_eq!(b, c);
+
+    // エラーが無ければ、トリビアを含めたspanは隙間なく入力全体を覆う
+    let mut pos = 0;
+    for item in SliceLexer::with_trivia(data) {
+        let Ok((token, span)) = item else { break; };
+        assert_eq!(span.start, pos);
+        pos = span.end;
+        if token == Token::Eof {
+            assert_eq!(pos, data.len());
+            break;
+        }
+    }
+});
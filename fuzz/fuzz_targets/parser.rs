@@ -0,0 +1,11 @@
+//! 任意のバイト列をParser::parseに通し、panicしないことを確かめる
+
+#![no_main]
+
+use asm_1st::parser::Parser;
+use asm_1st::slice_lexer::SliceLexer;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = Parser::new(SliceLexer::new(data).tokens()).parse();
+});
@@ -0,0 +1,18 @@
+//! 任意のバイト列をparseからencodeまで通し、panicしないことを確かめる
+//! semantic checkを通った命令列は、アドレス解決とencodeでもpanicしてはいけない
+
+#![no_main]
+
+use asm_1st::encoder::encode;
+use asm_1st::parser::Parser;
+use asm_1st::resolver::resolve_without_optimization;
+use asm_1st::semantics::check_semantics;
+use asm_1st::slice_lexer::SliceLexer;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok((inst, labels)) = Parser::new(SliceLexer::new(data).tokens()).parse() else { return; };
+    if check_semantics(&inst, &labels).is_err() { return; }
+    let Ok(inst) = resolve_without_optimization(inst) else { return; };
+    encode(inst);
+});
@@ -0,0 +1 @@
+addi r300, r1, 0x
\ No newline at end of file
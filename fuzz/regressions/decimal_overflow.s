@@ -0,0 +1 @@
+addi r1, r1, 99999999999999999999
@@ -0,0 +1 @@
+addi r1,　r2, 1
@@ -0,0 +1,2 @@
+L: L:
+
@@ -0,0 +1,2 @@
+addi r1, r1, -   
+j X
\ No newline at end of file
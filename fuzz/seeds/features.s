@@ -0,0 +1,16 @@
+# 全角コメント　も書ける
+MAIN:
+    lif r1, 3.14; lif r2, -1e-3
+    lif r3, 0f3f800000
+    addi r4, zero, 'A'
+    usend r4
+    libeq r1, r2, MAIN
+    lfbps r3, END
+    lw r5, sp, 4; sw r5, fp, 0x10
+    movl r6, MSG
+    movh r6, 0b1
+    call MAIN
+END: DATA:
+    j END
+MSG: .ascii "Hi\n\x41"
+    .word -1
@@ -0,0 +1,17 @@
+//! デバッグアダプタ。エディタから標準入出力で起動される
+
+use std::io;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    // 入力は別のスレッドで読むので、ロックせずに渡す
+    let stdin = io::BufReader::new(io::stdin());
+    let stdout = io::stdout();
+    match asm_1st::dap::Server::new().run(stdin, stdout.lock()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("asm-dap: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
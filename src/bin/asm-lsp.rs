@@ -0,0 +1,19 @@
+//! 言語サーバ。エディタから標準入出力で起動される
+
+use std::io;
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut server = asm_1st::lsp::Server::new();
+    match server.run(stdin.lock(), stdout.lock()) {
+        // shutdownを受け取らずに終わった場合は異常終了とする
+        Ok(()) if server.is_shut_down() => ExitCode::SUCCESS,
+        Ok(()) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("asm-lsp: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
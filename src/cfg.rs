@@ -0,0 +1,208 @@
+//! アドレス解決済みの命令列の制御フローグラフ (基本ブロックと、その間の辺)
+//! 関数ごとにGraphvizのDOT形式で書き出せる
+
+use std::collections::BTreeSet;
+use crate::disassembler::format_instruction;
+use crate::lexer::Mnemonic::*;
+use crate::parser::{Instruction, Operand};
+use crate::semantics::{is_conditional_branch, is_conditional_branch_ext};
+
+/// 基本ブロックから出る辺。数値は行き先のブロックの番号
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Edge {
+    /// 次の命令へ進む
+    FallThrough(usize),
+    /// 分岐が成立したとき、またはjで飛ぶ先
+    Taken(usize),
+    /// jrやプログラムの外への分岐のように、行き先が静的には分からない
+    Unknown,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct BasicBlock {
+    /// 最初の命令のアドレス
+    pub start: usize,
+    /// 最後の命令の次のアドレス
+    pub end: usize,
+    pub labels: Vec<String>,
+    pub successors: Vec<Edge>,
+    /// ブロックの最後のcallが呼ぶ関数の先頭のアドレス
+    pub call: Option<usize>,
+}
+
+/// 関数。プログラムの先頭とcallの飛び先を入口とし、そこから辺をたどれるブロックを含む
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub entry: usize,
+    /// ブロックの番号 (昇順)
+    pub blocks: Vec<usize>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Cfg {
+    pub blocks: Vec<BasicBlock>,
+    pub functions: Vec<Function>,
+}
+
+/// 制御を移す命令の、相対アドレスで書かれた行き先
+fn relative_target(instr: &Instruction) -> Option<i64> {
+    let m = instr.mnemonic;
+    let index = if is_conditional_branch(m) {
+        2
+    } else if is_conditional_branch_ext(m) {
+        1
+    } else if m == J || m == Call {
+        0
+    } else {
+        return None;
+    };
+    match instr.operands.get(index) {
+        Some(Operand::OpDigit(n)) => Some(*n),
+        _ => None,
+    }
+}
+
+/// 基本ブロックを終える命令か
+fn ends_block(instr: &Instruction) -> bool {
+    relative_target(instr).is_some() || instr.mnemonic == Jr
+}
+
+impl Cfg {
+    /// `resolve_without_optimization`の出力から作る
+    pub fn build(program: &[Instruction]) -> Self {
+        let len = program.len();
+        let target = |address: usize| -> Option<usize> {
+            let t = address as i64 + relative_target(&program[address])?;
+            if 0 <= t && (t as usize) < len { Some(t as usize) } else { None }
+        };
+
+        // ブロックの先頭: プログラムの先頭、ラベル、飛び先、制御を移す命令の次
+        let mut leaders = BTreeSet::new();
+        if len > 0 {
+            leaders.insert(0);
+        }
+        for (address, instr) in program.iter().enumerate() {
+            if !instr.label.is_empty() {
+                leaders.insert(address);
+            }
+            if ends_block(instr) {
+                if let Some(t) = target(address) {
+                    leaders.insert(t);
+                }
+                if address + 1 < len {
+                    leaders.insert(address + 1);
+                }
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let block_of = |address: usize| starts.partition_point(|s| *s <= address) - 1;
+
+        let mut blocks = vec![];
+        for (i, &start) in starts.iter().enumerate() {
+            let end = starts.get(i + 1).copied().unwrap_or(len);
+            let last_address = end - 1;
+            let last = &program[last_address];
+            let m = last.mnemonic;
+
+            let mut successors = vec![];
+            let mut call = None;
+            let falls_through = m != J && m != Jr;
+            if falls_through && end < len {
+                successors.push(Edge::FallThrough(block_of(end)));
+            }
+            if m == Jr {
+                successors.push(Edge::Unknown);
+            } else if m == Call {
+                call = target(last_address);
+            } else if relative_target(last).is_some() {
+                match target(last_address) {
+                    Some(t) => successors.push(Edge::Taken(block_of(t))),
+                    None => successors.push(Edge::Unknown),
+                }
+            }
+
+            let labels = program[start..end].iter().take(1).flat_map(|i| i.label.clone()).collect();
+            blocks.push(BasicBlock { start, end, labels, successors, call });
+        }
+
+        let mut entries: BTreeSet<usize> = blocks.iter().filter_map(|b| b.call).collect();
+        if len > 0 {
+            entries.insert(0);
+        }
+        let functions = entries.into_iter()
+            .map(|entry| {
+                let entry = block_of(entry);
+                let mut reached = BTreeSet::from([entry]);
+                let mut stack = vec![entry];
+                while let Some(b) = stack.pop() {
+                    for edge in &blocks[b].successors {
+                        if let Edge::FallThrough(t) | Edge::Taken(t) = *edge {
+                            if reached.insert(t) {
+                                stack.push(t);
+                            }
+                        }
+                    }
+                }
+                let name = match blocks[entry].labels.first() {
+                    Some(label) => label.clone(),
+                    None => format!("_{}", blocks[entry].start),
+                };
+                Function { name, entry, blocks: reached.into_iter().collect() }
+            })
+            .collect();
+
+        Self { blocks, functions }
+    }
+
+    /// 1つの関数をDOT形式にする。ノードには各命令をアドレス付きで書く
+    pub fn to_dot(&self, function: &Function, program: &[Instruction]) -> String {
+        let mut out = format!("digraph \"{}\" {{\n", escape(&function.name));
+        out.push_str("    node [shape=box, fontname=\"monospace\"];\n");
+
+        let mut has_unknown = false;
+        for &b in &function.blocks {
+            let block = &self.blocks[b];
+            let mut label = String::new();
+            for l in &block.labels {
+                label.push_str(&format!("{}:\\l", escape(l)));
+            }
+            for address in block.start..block.end {
+                let instr = &program[address];
+                let mut text = format_instruction(instr.mnemonic, &instr.operands);
+                // 飛び先は相対アドレスで書かれているので、絶対アドレスとラベルを添える
+                if let Some(offset) = relative_target(instr) {
+                    let t = address as i64 + offset;
+                    text.push_str(&format!("  # -> {t}"));
+                    if let Some(l) = program.get(t as usize).and_then(|i| i.label.first()) {
+                        text.push_str(&format!(" ({l})"));
+                    }
+                }
+                label.push_str(&format!("{address:>5}  {}\\l", escape(&text)));
+            }
+            out.push_str(&format!("    b{b} [label=\"{label}\"];\n"));
+
+            for edge in &block.successors {
+                match edge {
+                    Edge::FallThrough(t) => out.push_str(&format!("    b{b} -> b{t};\n")),
+                    Edge::Taken(t) => out.push_str(&format!("    b{b} -> b{t} [label=\"taken\"];\n")),
+                    Edge::Unknown => {
+                        has_unknown = true;
+                        out.push_str(&format!("    b{b} -> unknown [style=dashed];\n"));
+                    }
+                }
+            }
+        }
+        if has_unknown {
+            out.push_str("    unknown [label=\"?\", shape=circle];\n");
+        }
+        out.push_str("}\n");
+        out
+    }
+}
+
+/// DOTの文字列リテラルの中に書けるようにする
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
@@ -0,0 +1,512 @@
+//! デバッグアダプタ (Debug Adapter Protocol)。`asm-dap`バイナリから使う
+//! 実行の制御は`Debugger`に任せる。メッセージの形式は言語サーバと同じ`Content-Length`付きのJSON
+//!
+//! 行番号は1始まり。スレッドは1つだけで、レジスタを変数として見せ、usendの出力はデバッグコンソールに送る
+//! `continue`などはすぐに応答し、メッセージを読む合間に少しずつ実行する。止まったら`stopped`イベントを送る
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use crate::debugger::{format_value, Debugger, Stop, Target};
+use crate::diagnostics;
+use crate::json::{obj, Value};
+use crate::lexer::{Mnemonic, Register};
+use crate::lsp::{read_message, write_message};
+use crate::parser::Parser;
+use crate::resolver::resolve_without_optimization;
+use crate::semantics::check_semantics;
+use crate::simulator::DEFAULT_MEMORY_WORDS;
+use crate::slice_lexer::SliceLexer;
+use crate::uart::StreamUart;
+
+const THREAD_ID: i64 = 1;
+/// レジスタのスコープの`variablesReference`
+const REGISTERS_REFERENCE: i64 = 1;
+/// 実行中に、次のメッセージが届いていないかを見るまでに実行する命令の数
+const RUN_SLICE: usize = 1 << 16;
+
+/// usendの出力をためておき、`output`イベントで送る
+#[derive(Clone, Default)]
+struct Console(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// `launch`で始めたプログラム
+struct Session {
+    debugger: Debugger,
+    /// `launch`で渡されたソースのパス。スタックトレースではこれを見せる
+    path: String,
+    /// `setBreakpoints`のソースと比べるために正規化したパス
+    canonical_path: String,
+    console: Console,
+    stop_on_entry: bool,
+    /// `setBreakpoints`と`setFunctionBreakpoints`は毎回すべてを置き換えるので、前の分を覚えておく
+    line_breakpoints: Vec<usize>,
+    function_breakpoints: Vec<usize>,
+    /// 実行中なら、その行き先
+    running: Option<Running>,
+}
+
+/// 実行中の行き先
+#[derive(Clone, Copy)]
+enum Running {
+    /// `continue`と`stepOut`
+    To(Target),
+    /// `next`と`stepIn`。別の行に移るか、関数に出入りするまで進める
+    /// `over`ならcallは戻ってくるまで実行する。`call`はそのときの戻り先
+    Line { line: usize, over: bool, call: Option<Target> },
+}
+
+impl Running {
+    /// 最大`RUN_SLICE`命令ほど実行を進め、止まったらその理由を返す
+    fn advance(&mut self, debugger: &mut Debugger) -> Option<Stop> {
+        let (line, over, call) = match self {
+            Running::To(target) => { return debugger.run(*target, RUN_SLICE); }
+            Running::Line { line, over, call } => (*line, *over, call),
+        };
+        for _ in 0..RUN_SLICE {
+            if let Some(target) = *call {
+                match debugger.run(target, RUN_SLICE)? {
+                    Stop::Step => *call = None,
+                    stop => { return Some(stop); }
+                }
+            } else if let Some(target) = debugger.step_over_target().filter(|_| over) {
+                *call = Some(target);
+                continue;
+            } else {
+                let mnemonic = debugger.program().get(debugger.pc()).map(|i| i.mnemonic);
+                match debugger.step() {
+                    Stop::Step => {}
+                    stop => { return Some(stop); }
+                }
+                // retやlcallはjrになっている
+                if matches!(mnemonic, Some(Mnemonic::Call | Mnemonic::Jr)) {
+                    return Some(Stop::Step);
+                }
+            }
+            if debugger.program().get(debugger.pc()).map_or(0, |i| i.line) != line {
+                return Some(Stop::Step);
+            }
+            if let Some(id) = debugger.breakpoint_at(debugger.pc()) {
+                return Some(Stop::Breakpoint(id));
+            }
+        }
+        None
+    }
+}
+
+/// 比べるためのパス。存在しなければそのまま
+fn canonical(path: &str) -> String {
+    fs::canonicalize(path).map_or(path.to_string(), |p| p.to_string_lossy().into_owned())
+}
+
+/// デバッグアダプタの状態
+pub struct Server {
+    seq: i64,
+    session: Option<Session>,
+    /// レスポンスの後に送るイベント
+    events: Vec<(&'static str, Value)>,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self { seq: 0, session: None, events: vec![] }
+    }
+
+    /// `disconnect`を受け取るか入力が終わるまで、メッセージを処理し続ける
+    /// 入力は別のスレッドで読み、実行中はメッセージが届いていない間だけ実行を進める
+    pub fn run(&mut self, input: impl BufRead + Send + 'static, mut output: impl Write) -> io::Result<()> {
+        let messages = spawn_reader(input);
+        loop {
+            let body = if self.running() {
+                match messages.try_recv() {
+                    Ok(body) => body,
+                    Err(TryRecvError::Empty) => {
+                        for reply in self.poll() {
+                            write_message(&mut output, &reply)?;
+                        }
+                        continue;
+                    }
+                    Err(TryRecvError::Disconnected) => break,
+                }
+            } else {
+                match messages.recv() {
+                    Ok(body) => body,
+                    Err(_) => break,
+                }
+            };
+            // 読めないメッセージには応答のしようが無いので捨てる
+            let Ok(message) = crate::json::parse(&body?) else { continue; };
+            for reply in self.handle(&message) {
+                write_message(&mut output, &reply)?;
+            }
+            if message.get("command").as_str() == Some("disconnect") {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// プログラムを実行しているところか
+    pub fn running(&self) -> bool {
+        self.session.as_ref().is_some_and(|s| s.running.is_some())
+    }
+
+    /// 実行中なら少しだけ実行を進め、止まったらそのイベントを返す
+    pub fn poll(&mut self) -> Vec<Value> {
+        if let Some(mut session) = self.session.take() {
+            if let Some(mut running) = session.running {
+                match running.advance(&mut session.debugger) {
+                    Some(stop) => {
+                        session.running = None;
+                        self.report_stop(&session, stop);
+                    }
+                    // 止まるのを待たずに、ここまでの出力を送る
+                    None => {
+                        session.running = Some(running);
+                        self.flush_console(&session);
+                    }
+                }
+            }
+            self.session = Some(session);
+        }
+        self.take_events()
+    }
+
+    fn next_seq(&mut self) -> i64 {
+        self.seq += 1;
+        self.seq
+    }
+
+    fn event(&mut self, event: &'static str, body: Value) {
+        self.events.push((event, body));
+    }
+
+    /// 1つのリクエストを処理し、レスポンスとその後のイベントを返す
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        if message.get("type").as_str() != Some("request") {
+            return vec![];
+        }
+        let command = message.get("command").as_str().unwrap_or("");
+        let args = message.get("arguments");
+
+        let result = match command {
+            "initialize" => Ok(obj(&[
+                ("supportsConfigurationDoneRequest", true.into()),
+                ("supportsFunctionBreakpoints", true.into()),
+                ("supportsEvaluateForHovers", true.into()),
+                ("supportsTerminateRequest", true.into()),
+            ])),
+            "launch" => self.launch(args),
+            "disconnect" => {
+                self.session = None;
+                Ok(Value::Null)
+            }
+            "threads" => Ok(obj(&[(
+                "threads",
+                Value::Array(vec![obj(&[("id", THREAD_ID.into()), ("name", "main".into())])]),
+            )])),
+            "setExceptionBreakpoints" => Ok(obj(&[("breakpoints", Value::Array(vec![]))])),
+            _ => match self.session.take() {
+                Some(mut session) => {
+                    let result = self.handle_session(&mut session, command, args);
+                    self.session = Some(session);
+                    result
+                }
+                None => Err(format!("\"{command}\" needs a running program.")),
+            },
+        };
+
+        let request_seq = message.get("seq").clone();
+        let mut response = vec![
+            ("seq", self.next_seq().into()),
+            ("type", "response".into()),
+            ("request_seq", request_seq),
+            ("success", result.is_ok().into()),
+            ("command", command.into()),
+        ];
+        match result {
+            Ok(Value::Null) => {}
+            Ok(body) => response.push(("body", body)),
+            Err(message) => response.push(("message", message.into())),
+        }
+        let mut replies = vec![obj(&response)];
+        replies.extend(self.take_events());
+        replies
+    }
+
+    /// たまったイベントに番号を振って、送る形にする
+    fn take_events(&mut self) -> Vec<Value> {
+        let mut events = vec![];
+        for (event, body) in std::mem::take(&mut self.events) {
+            let mut members = vec![("seq", self.next_seq().into()), ("type", "event".into()), ("event", event.into())];
+            if body != Value::Null {
+                members.push(("body", body));
+            }
+            events.push(obj(&members));
+        }
+        events
+    }
+
+    /// プログラムをアセンブルして、最初の命令の前で止めておく。実行は`configurationDone`で始める
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args.get("program").as_str().ok_or("\"program\" is required.".to_string())?;
+        let src = fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+        let (program, errors) = diagnostics::collect(|| {
+            diagnostics::set_source(Some(path), src.as_bytes());
+            let (inst, labels) = Parser::new(SliceLexer::new(src.as_bytes()).tokens()).parse().ok()?;
+            check_semantics(&inst, &labels).ok()?;
+            resolve_without_optimization(inst).ok()
+        });
+        let Some(program) = program else {
+            let messages: Vec<String> = errors.iter().map(|d| d.to_human()).collect();
+            return Err(messages.join("\n"));
+        };
+
+        let console = Console::default();
+        let input = args.get("input").as_str().unwrap_or("").as_bytes().to_vec();
+        let uart = StreamUart::scripted(input, Box::new(console.clone()));
+        self.session = Some(Session {
+            debugger: Debugger::new(program, &src, DEFAULT_MEMORY_WORDS, Box::new(uart)),
+            path: path.to_string(),
+            canonical_path: canonical(path),
+            console,
+            stop_on_entry: args.get("stopOnEntry").as_bool().unwrap_or(false),
+            line_breakpoints: vec![],
+            function_breakpoints: vec![],
+            running: None,
+        });
+        // ブレークポイントはプログラムが決まってから受け取る
+        self.event("initialized", Value::Null);
+        Ok(Value::Null)
+    }
+
+    fn handle_session(&mut self, session: &mut Session, command: &str, args: &Value) -> Result<Value, String> {
+        let debugger = &mut session.debugger;
+        match command {
+            "setBreakpoints" => {
+                let lines: Vec<usize> = args.get("breakpoints").as_array().unwrap_or(&[]).iter()
+                    .filter_map(|b| b.get("line").as_i64())
+                    .map(|l| l.max(0) as usize)
+                    .collect();
+                let path = args.get("source").get("path").as_str().map(canonical);
+                for id in session.line_breakpoints.drain(..) {
+                    debugger.delete_point(id);
+                }
+                let mut breakpoints = vec![];
+                for line in lines {
+                    let address = if path.as_ref() == Some(&session.canonical_path) { debugger.line_address(line) } else { None };
+                    breakpoints.push(match address {
+                        Some(address) => {
+                            let id = debugger.add_breakpoint(address);
+                            session.line_breakpoints.push(id);
+                            obj(&[
+                                ("id", id.into()),
+                                ("verified", true.into()),
+                                ("line", debugger.program()[address].line.into()),
+                            ])
+                        }
+                        None => obj(&[("verified", false.into()), ("message", "no code at or after this line.".into())]),
+                    });
+                }
+                Ok(obj(&[("breakpoints", Value::Array(breakpoints))]))
+            }
+            "setFunctionBreakpoints" => {
+                for id in session.function_breakpoints.drain(..) {
+                    debugger.delete_point(id);
+                }
+                let mut breakpoints = vec![];
+                for b in args.get("breakpoints").as_array().unwrap_or(&[]) {
+                    let name = b.get("name").as_str().unwrap_or("");
+                    breakpoints.push(match debugger.label_address(name) {
+                        Some(address) => {
+                            let id = debugger.add_breakpoint(address);
+                            session.function_breakpoints.push(id);
+                            obj(&[
+                                ("id", id.into()),
+                                ("verified", true.into()),
+                                ("line", debugger.program()[address].line.into()),
+                            ])
+                        }
+                        None => obj(&[("verified", false.into()), ("message", format!("label \"{name}\" not found.").into())]),
+                    });
+                }
+                Ok(obj(&[("breakpoints", Value::Array(breakpoints))]))
+            }
+            "configurationDone" => {
+                if session.stop_on_entry {
+                    self.event("stopped", stopped_body("entry", vec![]));
+                } else if let Some(id) = debugger.breakpoint_at(debugger.pc()) {
+                    // 再開すると今いるアドレスのブレークポイントは飛ばすので、最初の命令のものはここで止まる
+                    self.report_stop(session, Stop::Breakpoint(id));
+                } else {
+                    session.running = Some(Running::To(Target::Resume));
+                }
+                Ok(Value::Null)
+            }
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let mut running = match command {
+                    "continue" => Running::To(Target::Resume),
+                    "stepOut" => Running::To(debugger.step_out_target()),
+                    _ => {
+                        let line = debugger.program().get(debugger.pc()).map_or(0, |i| i.line);
+                        Running::Line { line, over: command == "next", call: None }
+                    }
+                };
+                // 行のステップはたいていすぐ終わるので、待たせずに少し実行してみる
+                let stop = if matches!(running, Running::Line { .. }) { running.advance(debugger) } else { None };
+                match stop {
+                    Some(stop) => self.report_stop(session, stop),
+                    None => session.running = Some(running),
+                }
+                if command == "continue" {
+                    Ok(obj(&[("allThreadsContinued", true.into())]))
+                } else {
+                    Ok(Value::Null)
+                }
+            }
+            "stackTrace" => {
+                let frames: Vec<Value> = debugger.backtrace().iter().enumerate()
+                    .map(|(i, frame)| {
+                        let line = debugger.program().get(frame.address).map_or(0, |instr| instr.line);
+                        obj(&[
+                            ("id", i.into()),
+                            ("name", debugger.symbol(frame.address).into()),
+                            ("source", obj(&[("path", session.path.as_str().into())])),
+                            ("line", line.into()),
+                            ("column", 1_i64.into()),
+                            ("instructionPointerReference", frame.address.to_string().into()),
+                        ])
+                    })
+                    .collect();
+                Ok(obj(&[("totalFrames", frames.len().into()), ("stackFrames", Value::Array(frames))]))
+            }
+            // レジスタはフレームごとに保存していないので、どのフレームでも今の値を見せる
+            "scopes" => Ok(obj(&[(
+                "scopes",
+                Value::Array(vec![obj(&[
+                    ("name", "Registers".into()),
+                    ("presentationHint", "registers".into()),
+                    ("variablesReference", REGISTERS_REFERENCE.into()),
+                    ("expensive", false.into()),
+                ])]),
+            )])),
+            "variables" => {
+                if args.get("variablesReference").as_i64() != Some(REGISTERS_REFERENCE) {
+                    return Ok(obj(&[("variables", Value::Array(vec![]))]));
+                }
+                let variable = |name: String, value: String| obj(&[
+                    ("name", name.into()), ("value", value.into()), ("variablesReference", 0_i64.into()),
+                ]);
+                let mut variables = vec![variable("pc".to_string(), debugger.pc().to_string())];
+                let registers = (0..=252).map(Register::R).chain([Register::Sp, Register::Fp]);
+                for r in registers {
+                    variables.push(variable(r.to_string(), format_value(debugger.machine.reg(r))));
+                }
+                Ok(obj(&[("variables", Value::Array(variables))]))
+            }
+            "evaluate" => {
+                let expression = args.get("expression").as_str().unwrap_or("");
+                let v = debugger.evaluate(expression)?;
+                Ok(obj(&[("result", format_value(v as u32).into()), ("variablesReference", 0_i64.into())]))
+            }
+            "pause" => {
+                if session.running.take().is_some() {
+                    self.report_stop(session, Stop::Interrupted);
+                }
+                Ok(Value::Null)
+            }
+            "terminate" => {
+                self.event("terminated", obj(&[]));
+                Ok(Value::Null)
+            }
+            _ => Err(format!("unsupported request \"{command}\".")),
+        }
+    }
+
+    /// たまったusendの出力を`output`イベントにする
+    fn flush_console(&mut self, session: &Session) {
+        let output = std::mem::take(&mut *session.console.0.borrow_mut());
+        if !output.is_empty() {
+            let output = String::from_utf8_lossy(&output).into_owned();
+            self.event("output", obj(&[("category", "stdout".into()), ("output", output.into())]));
+        }
+    }
+
+    /// 止まった理由をイベントにする。その前にたまったusendの出力を送る
+    fn report_stop(&mut self, session: &Session, stop: Stop) {
+        self.flush_console(session);
+        match stop {
+            Stop::Step => self.event("stopped", stopped_body("step", vec![])),
+            Stop::Breakpoint(id) => {
+                self.event("stopped", stopped_body("breakpoint", vec![("hitBreakpointIds", Value::Array(vec![id.into()]))]));
+            }
+            Stop::Watchpoint { address, old, new, .. } => {
+                let text = format!("address {address} changed from {} to {}", format_value(old), format_value(new));
+                self.event("stopped", stopped_body("data breakpoint", described(text)));
+            }
+            Stop::Halted => {
+                self.event("exited", obj(&[("exitCode", 0_i64.into())]));
+                self.event("terminated", obj(&[]));
+            }
+            Stop::Error(e) => {
+                self.event("output", obj(&[("category", "stderr".into()), ("output", format!("{e}\n").into())]));
+                self.event("stopped", stopped_body("exception", described(e.to_string())));
+            }
+            Stop::Interrupted => self.event("stopped", stopped_body("pause", vec![])),
+        }
+    }
+}
+
+/// メッセージを別のスレッドで読んで渡す。読み込みのエラーも渡して終わる
+fn spawn_reader(mut input: impl BufRead + Send + 'static) -> Receiver<io::Result<String>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        loop {
+            let message = match read_message(&mut input) {
+                Ok(Some(body)) => Ok(body),
+                Ok(None) => { return; }
+                Err(e) => Err(e),
+            };
+            let failed = message.is_err();
+            if sender.send(message).is_err() || failed {
+                return;
+            }
+        }
+    });
+    receiver
+}
+
+/// `stopped`イベントの中身。`extra`は理由ごとに付け加える項目
+fn stopped_body(reason: &str, extra: Vec<(&str, Value)>) -> Value {
+    let mut members = vec![
+        ("reason", reason.into()),
+        ("threadId", THREAD_ID.into()),
+        ("allThreadsStopped", true.into()),
+    ];
+    members.extend(extra);
+    obj(&members)
+}
+
+/// 止まった理由の説明を`description`と`text`に入れる
+fn described(text: String) -> Vec<(&'static str, Value)> {
+    vec![("description", text.clone().into()), ("text", text.into())]
+}
@@ -0,0 +1,547 @@
+//! シミュレータの上で動くデバッガ (`asm debug`)
+//! アドレス解決済みの命令列に残したラベルと行番号を使って、ブレークポイントの位置や止まった場所を表す
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use crate::disassembler::format_instruction;
+use crate::encoder::encode;
+use crate::lexer::{lookup_keyword, LexToken, Mnemonic, Register};
+use crate::parser::Instruction;
+use crate::simulator::{Machine, SimError, StepResult};
+use crate::uart::Uart;
+
+/// 止まった理由
+#[derive(Debug)]
+pub enum Stop {
+    /// 言われた分だけ実行した
+    Step,
+    /// ブレークポイントの番号
+    Breakpoint(usize),
+    /// 監視していたメモリのワードが書き換わった
+    Watchpoint { id: usize, address: usize, old: u32, new: u32 },
+    /// 自分自身へのジャンプを実行した。これ以上は実行できない
+    Halted,
+    /// 実行時エラー。pcはエラーになった命令を指したまま
+    Error(SimError),
+    /// 実行中に、止めるように言われた (GDBのCtrl-CやDAPの`pause`)
+    Interrupted,
+}
+
+/// 少しずつ実行するときの行き先
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Target {
+    /// ブレークポイントかウォッチポイントに着くか、停止するまで
+    Resume,
+    /// callから戻るまで。pcが`address`になり、spが`sp`以上に戻ったら着いたとみなす
+    Return { address: u32, sp: u32 },
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PointKind {
+    Breakpoint,
+    Watchpoint,
+}
+
+/// ブレークポイントとウォッチポイント。番号は両方で共通に振る
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Point {
+    pub id: usize,
+    pub kind: PointKind,
+    pub address: usize,
+    /// (ウォッチポイント) 最後に見た値
+    value: u32,
+}
+
+/// バックトレースの1段
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Frame {
+    /// 一番内側は実行中の命令、それ以外は呼び出したcallのアドレス
+    pub address: usize,
+    /// このフレームのfp。一番内側ではfpレジスタの値
+    pub fp: u32,
+}
+
+pub struct Debugger {
+    pub machine: Machine,
+    /// アドレスごとの命令。ラベルと行番号を持つ
+    program: Vec<Instruction>,
+    labels: HashMap<String, usize>,
+    /// ラベルの付いたアドレスを昇順に並べたもの
+    label_addresses: Vec<(usize, String)>,
+    source: Vec<String>,
+    points: Vec<Point>,
+    next_id: usize,
+    halted: bool,
+}
+
+impl Debugger {
+    /// `program`は`resolve_without_optimization`の出力、`src`はその元のソース
+    pub fn new(program: Vec<Instruction>, src: &str, memory_words: usize, uart: Box<dyn Uart>) -> Self {
+        let machine = Machine::with_uart(&encode(program.clone()), memory_words, uart);
+        let mut labels = HashMap::new();
+        let mut label_addresses = vec![];
+        for (address, instr) in program.iter().enumerate() {
+            for label in &instr.label {
+                labels.insert(label.clone(), address);
+                label_addresses.push((address, label.clone()));
+            }
+        }
+        Self {
+            machine,
+            program,
+            labels,
+            label_addresses,
+            source: src.lines().map(|s| s.to_string()).collect(),
+            points: vec![],
+            next_id: 1,
+            halted: false,
+        }
+    }
+
+    pub fn program(&self) -> &[Instruction] {
+        &self.program
+    }
+
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn pc(&self) -> usize {
+        self.machine.pc as usize
+    }
+
+    pub fn label_address(&self, label: &str) -> Option<usize> {
+        self.labels.get(label).copied()
+    }
+
+    /// 行番号 (1始まり) のソース
+    pub fn source_line(&self, line: usize) -> Option<&str> {
+        self.source.get(line.checked_sub(1)?).map(|s| s.as_str())
+    }
+
+    /// `line`行目以降で最初に命令のある行の、先頭のアドレス
+    pub fn line_address(&self, line: usize) -> Option<usize> {
+        let target = self.program.iter().map(|i| i.line).filter(|l| *l >= line).min()?;
+        self.program.iter().position(|i| i.line == target)
+    }
+
+    /// ブレークポイントの位置。ラベル、行番号、`*アドレス`のどれか
+    pub fn location(&self, s: &str) -> Result<usize, String> {
+        let address = if let Some(address) = s.strip_prefix('*') {
+            parse_number(address).ok_or(format!("invalid address \"{address}\"."))? as usize
+        } else if let Ok(line) = s.parse::<usize>() {
+            self.line_address(line).ok_or(format!("no code at or after line {line}."))?
+        } else {
+            self.label_address(s).ok_or(format!("label \"{s}\" not found."))?
+        };
+        if address >= self.program.len() {
+            return Err(format!("address {address} is outside the program."));
+        }
+        Ok(address)
+    }
+
+    /// レジスタ、ラベル (のアドレス)、数値のどれかに、`+n`や`-n`を付けたもの
+    pub fn evaluate(&self, s: &str) -> Result<i64, String> {
+        let s = s.trim();
+        if let Some(i) = s.rfind(['+', '-']).filter(|i| *i > 0) {
+            let offset = parse_number(s[i + 1..].trim()).ok_or(format!("invalid number \"{}\".", &s[i + 1..]))?;
+            let base = self.evaluate(&s[..i])?;
+            let value = if s.as_bytes()[i] == b'+' { base.checked_add(offset) } else { base.checked_sub(offset) };
+            return value.ok_or(format!("\"{s}\" overflows."));
+        }
+        if let Some(LexToken::LexRegister(r)) = lookup_keyword(s.as_bytes()) {
+            return Ok(self.machine.reg(r) as i64);
+        }
+        if let Some(n) = parse_number(s) {
+            return Ok(n);
+        }
+        self.label_address(s).map(|a| a as i64).ok_or(format!("\"{s}\" is not a register, label or number."))
+    }
+
+    fn memory_address(&self, address: i64) -> Result<usize, String> {
+        if !(0 <= address && address < self.machine.memory.len() as i64) {
+            return Err(format!("address {address} is out of memory."));
+        }
+        Ok(address as usize)
+    }
+
+    pub fn add_breakpoint(&mut self, address: usize) -> usize {
+        self.add_point(PointKind::Breakpoint, address, 0)
+    }
+
+    pub fn add_watchpoint(&mut self, address: i64) -> Result<usize, String> {
+        let address = self.memory_address(address)?;
+        let value = self.machine.memory[address];
+        Ok(self.add_point(PointKind::Watchpoint, address, value))
+    }
+
+    fn add_point(&mut self, kind: PointKind, address: usize, value: u32) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.points.push(Point { id, kind, address, value });
+        id
+    }
+
+    pub fn delete_point(&mut self, id: usize) -> bool {
+        let len = self.points.len();
+        self.points.retain(|p| p.id != id);
+        self.points.len() != len
+    }
+
+    pub fn points(&self) -> &[Point] {
+        &self.points
+    }
+
+    /// 1命令を実行する
+    pub fn step(&mut self) -> Stop {
+        if self.halted {
+            return Stop::Halted;
+        }
+        match self.machine.step() {
+            Ok(StepResult::Halt) => {
+                self.halted = true;
+                Stop::Halted
+            }
+            Ok(StepResult::Continue) => self.check_watchpoints().unwrap_or(Stop::Step),
+            Err(e) => Stop::Error(e),
+        }
+    }
+
+    fn check_watchpoints(&mut self) -> Option<Stop> {
+        let memory = &self.machine.memory;
+        let point = self.points.iter_mut()
+            .find(|p| p.kind == PointKind::Watchpoint && memory[p.address] != p.value)?;
+        let old = point.value;
+        point.value = memory[point.address];
+        Some(Stop::Watchpoint { id: point.id, address: point.address, old, new: point.value })
+    }
+
+    /// `address`にあるブレークポイントの番号
+    pub fn breakpoint_at(&self, address: usize) -> Option<usize> {
+        self.points.iter().find(|p| p.kind == PointKind::Breakpoint && p.address == address).map(|p| p.id)
+    }
+
+    /// `target`に着くか、ブレークポイントなどで止まるまで、最大`max_steps`命令を実行する。まだ着かなければNone
+    /// 今いるアドレスのブレークポイントでは止まらない (少なくとも1命令は実行する)
+    pub fn run(&mut self, target: Target, max_steps: usize) -> Option<Stop> {
+        for _ in 0..max_steps {
+            match self.step() {
+                Stop::Step => {}
+                stop => { return Some(stop); }
+            }
+            if let Target::Return { address, sp } = target {
+                if self.machine.pc == address && self.machine.reg(Register::Sp) >= sp {
+                    return Some(Stop::Step);
+                }
+            }
+            if let Some(id) = self.breakpoint_at(self.pc()) {
+                return Some(Stop::Breakpoint(id));
+            }
+        }
+        None
+    }
+
+    fn run_to(&mut self, target: Target) -> Stop {
+        loop {
+            if let Some(stop) = self.run(target, usize::MAX) {
+                return stop;
+            }
+        }
+    }
+
+    /// `step_over`の行き先。callでなければNone (1命令を実行すれば良い)
+    pub fn step_over_target(&self) -> Option<Target> {
+        let pc = self.machine.pc;
+        if self.program.get(pc as usize).is_none_or(|i| i.mnemonic != Mnemonic::Call) {
+            return None;
+        }
+        // 再帰呼び出しで同じcallの次に戻ってきた場合は、spがまだ元より小さい
+        Some(Target::Return { address: pc.wrapping_add(1), sp: self.machine.reg(Register::Sp) })
+    }
+
+    /// `step_out`の行き先。戻り先はfpのチェーンから求める。呼び出し元が無ければ`Resume`
+    pub fn step_out_target(&self) -> Target {
+        let Some(caller) = self.backtrace().get(1).copied() else {
+            return Target::Resume;
+        };
+        // leaveでspはfpより上に戻る
+        let fp = self.machine.reg(Register::Fp);
+        Target::Return { address: caller.address as u32 + 1, sp: fp.saturating_add(1) }
+    }
+
+    /// 1命令を実行する。callなら戻ってくるまで実行する
+    pub fn step_over(&mut self) -> Stop {
+        match self.step_over_target() {
+            Some(target) => self.run_to(target),
+            None => self.step(),
+        }
+    }
+
+    /// 今の関数から戻るまで実行する
+    pub fn step_out(&mut self) -> Stop {
+        self.run_to(self.step_out_target())
+    }
+
+    /// ブレークポイントかウォッチポイントに着くか、停止するまで実行する
+    pub fn resume(&mut self) -> Stop {
+        self.run_to(Target::Resume)
+    }
+
+    /// fpのチェーンをたどる。`enter`でフレームを作っていない関数は現れない
+    /// (`enter`はfpの指す先に呼び出し元のfpを、その次に戻りアドレスを保存する)
+    pub fn backtrace(&self) -> Vec<Frame> {
+        let memory = &self.machine.memory;
+        let mut fp = self.machine.reg(Register::Fp);
+        let mut frames = vec![Frame { address: self.pc(), fp }];
+        while (fp as usize) + 1 < memory.len() {
+            let saved_fp = memory[fp as usize];
+            let return_address = memory[fp as usize + 1] as usize;
+            // スタックは下位アドレスに伸びるので、呼び出し元のfpは大きい。そうでなければフレームではない
+            if saved_fp <= fp || return_address == 0 || return_address > self.program.len() {
+                break;
+            }
+            frames.push(Frame { address: return_address - 1, fp: saved_fp });
+            fp = saved_fp;
+        }
+        frames
+    }
+
+    /// `label+3`のように、直前のラベルからの位置で表す
+    pub fn symbol(&self, address: usize) -> String {
+        let i = self.label_addresses.partition_point(|(a, _)| *a <= address);
+        match i.checked_sub(1).map(|i| &self.label_addresses[i]) {
+            Some((a, label)) if *a == address => label.clone(),
+            Some((a, label)) => format!("{label}+{}", address - a),
+            None => format!("{address}"),
+        }
+    }
+
+    /// `12 (loop+2), line 5: addi r1, r1, 1`。挿入したnopのように行番号の無い命令は逆アセンブルして書く
+    pub fn describe(&self, address: usize) -> String {
+        let Some(instr) = self.program.get(address) else {
+            return format!("{address} (outside the program)");
+        };
+        let text = match self.source_line(instr.line) {
+            Some(line) if instr.line > 0 => format!("line {}: {}", instr.line, line.trim()),
+            _ => format_instruction(instr.mnemonic, &instr.operands),
+        };
+        format!("{address} ({}), {text}", self.symbol(address))
+    }
+}
+
+/// 10進数か`0x`で始まる16進数。負の数も書ける
+fn parse_number(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let n = match s.strip_prefix("0x") {
+        Some(hex) => i64::from_str_radix(hex, 16).ok()?,
+        None => s.parse().ok()?,
+    };
+    Some(if negative { -n } else { n })
+}
+
+/// 値を符号付き10進数、16進数、浮動小数点数で書く
+pub(crate) fn format_value(v: u32) -> String {
+    format!("{} (0x{v:08x}, {:?})", v as i32, f32::from_bits(v))
+}
+
+const HELP: &str = "\
+break|b <location>   stop at a label, a source line or *address
+watch <expr>         stop when the memory word at <expr> changes
+delete|d <n>         delete breakpoint or watchpoint n
+info breakpoints     list breakpoints and watchpoints
+info registers       print the registers that are not zero
+step|s [n]           execute n instructions (default 1)
+next|n [n]           like step, but run each call until it returns
+finish               run until the current function returns
+continue|c           run until a breakpoint, a watchpoint or the end
+print|p <expr>       print a register, a label address or a number
+x <expr> [n]         print n memory words starting at address <expr>
+backtrace|bt         print the calls on the stack (from the fp chain)
+list|l               print the source around the current line
+quit|q               exit the debugger
+(<expr> is a register, label or number, optionally followed by +n or -n)";
+
+impl Debugger {
+    /// 対話的にコマンドを読んで実行する。空行は直前のコマンドを繰り返す
+    pub fn repl(&mut self, input: &mut dyn BufRead, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "stopped at {}", self.describe(self.pc()))?;
+        let mut last = String::new();
+        loop {
+            write!(out, "(asm) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let line = line.trim();
+            let command = if line.is_empty() { last.clone() } else { line.to_string() };
+            if command == "quit" || command == "q" {
+                return Ok(());
+            }
+            match self.execute(&command, out)? {
+                Ok(()) => {}
+                Err(message) => writeln!(out, "error: {message}")?,
+            }
+            last = command;
+        }
+    }
+
+    /// 1つのコマンドを実行する。コマンドの誤りは`Err`で返す
+    pub fn execute(&mut self, command: &str, out: &mut dyn Write) -> io::Result<Result<(), String>> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {
+            return Ok(Ok(()));
+        };
+        let count = |args: &[&str]| -> Result<usize, String> {
+            match args.first() {
+                Some(n) => n.parse().map_err(|_| format!("invalid count \"{n}\".")),
+                None => Ok(1),
+            }
+        };
+        let arg = |args: &[&str]| -> Result<String, String> {
+            if args.is_empty() {
+                return Err(format!("{name} needs an argument."));
+            }
+            Ok(args.join(" "))
+        };
+
+        match name {
+            "help" | "h" => writeln!(out, "{HELP}").map(Ok),
+            "break" | "b" => match arg(args).and_then(|s| self.location(&s)) {
+                Ok(address) => {
+                    let id = self.add_breakpoint(address);
+                    writeln!(out, "breakpoint {id} at {}", self.describe(address)).map(Ok)
+                }
+                Err(e) => Ok(Err(e)),
+            },
+            "watch" => match arg(args).and_then(|s| self.evaluate(&s)).and_then(|a| self.add_watchpoint(a)) {
+                Ok(id) => {
+                    let p = self.points.iter().find(|p| p.id == id).unwrap();
+                    writeln!(out, "watchpoint {id} at address {} (now {})", p.address, format_value(p.value)).map(Ok)
+                }
+                Err(e) => Ok(Err(e)),
+            },
+            "delete" | "d" => match args.first().and_then(|n| n.parse().ok()) {
+                Some(id) if self.delete_point(id) => Ok(Ok(())),
+                _ => Ok(Err("no such breakpoint or watchpoint.".to_string())),
+            },
+            "info" => match args.first().copied() {
+                Some("breakpoints" | "b" | "watchpoints") => self.print_points(out).map(Ok),
+                Some("registers" | "r") => self.print_registers(out).map(Ok),
+                _ => Ok(Err("usage: info breakpoints | info registers".to_string())),
+            },
+            "step" | "s" | "next" | "n" => match count(args) {
+                Ok(n) => {
+                    let mut stop = Stop::Step;
+                    for _ in 0..n {
+                        stop = if name.starts_with('s') { self.step() } else { self.step_over() };
+                        if !matches!(stop, Stop::Step) {
+                            break;
+                        }
+                    }
+                    self.print_stop(&stop, out).map(Ok)
+                }
+                Err(e) => Ok(Err(e)),
+            },
+            "finish" => {
+                let stop = self.step_out();
+                self.print_stop(&stop, out).map(Ok)
+            }
+            "continue" | "c" => {
+                let stop = self.resume();
+                self.print_stop(&stop, out).map(Ok)
+            }
+            "print" | "p" => match arg(args).and_then(|s| self.evaluate(&s)) {
+                Ok(v) => writeln!(out, "{}", format_value(v as u32)).map(Ok),
+                Err(e) => Ok(Err(e)),
+            },
+            "x" => match args.first().ok_or("x needs an address.".to_string())
+                .and_then(|s| self.evaluate(s))
+                .and_then(|a| self.memory_address(a))
+                .and_then(|a| count(&args[1..]).map(|n| (a, n)))
+            {
+                Ok((address, n)) => self.print_memory(address, n, out).map(Ok),
+                Err(e) => Ok(Err(e)),
+            },
+            "backtrace" | "bt" => {
+                for (i, frame) in self.backtrace().iter().enumerate() {
+                    writeln!(out, "#{i} {}", self.describe(frame.address))?;
+                }
+                Ok(Ok(()))
+            }
+            "list" | "l" => self.print_source(out).map(Ok),
+            _ => Ok(Err(format!("unknown command \"{name}\" (try help)."))),
+        }
+    }
+
+    fn print_stop(&self, stop: &Stop, out: &mut dyn Write) -> io::Result<()> {
+        match stop {
+            Stop::Step => {}
+            Stop::Breakpoint(id) => writeln!(out, "breakpoint {id}")?,
+            Stop::Watchpoint { id, address, old, new } => {
+                writeln!(out, "watchpoint {id}: address {address} changed from {} to {}", format_value(*old), format_value(*new))?;
+            }
+            Stop::Halted => {
+                writeln!(out, "halted at address {} after {} instructions.", self.machine.pc, self.machine.steps)?;
+                return Ok(());
+            }
+            Stop::Error(e) => writeln!(out, "{e}")?,
+            Stop::Interrupted => writeln!(out, "interrupted")?,
+        }
+        writeln!(out, "stopped at {}", self.describe(self.pc()))
+    }
+
+    fn print_points(&self, out: &mut dyn Write) -> io::Result<()> {
+        if self.points.is_empty() {
+            return writeln!(out, "no breakpoints or watchpoints.");
+        }
+        for p in &self.points {
+            match p.kind {
+                PointKind::Breakpoint => writeln!(out, "{} breakpoint at {}", p.id, self.describe(p.address))?,
+                PointKind::Watchpoint => writeln!(out, "{} watchpoint at address {}", p.id, p.address)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn print_registers(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "pc    {}", self.pc())?;
+        for n in 0..=u8::MAX {
+            let r = match n {
+                255 => continue,
+                254 => Register::Fp,
+                253 => Register::Sp,
+                n => Register::R(n),
+            };
+            let v = self.machine.reg(r);
+            if v != 0 || n >= 253 {
+                writeln!(out, "{:<5} {}", r.to_string(), format_value(v))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn print_memory(&self, address: usize, n: usize, out: &mut dyn Write) -> io::Result<()> {
+        let end = address.saturating_add(n).min(self.machine.memory.len());
+        for (a, v) in self.machine.memory[address..end].iter().enumerate() {
+            writeln!(out, "{:>8}: {}", address + a, format_value(*v))?;
+        }
+        Ok(())
+    }
+
+    fn print_source(&self, out: &mut dyn Write) -> io::Result<()> {
+        let line = self.program.get(self.pc()).map_or(0, |i| i.line);
+        if line == 0 {
+            return writeln!(out, "no source line for address {}.", self.pc());
+        }
+        for l in line.saturating_sub(5).max(1)..=line + 5 {
+            let Some(text) = self.source_line(l) else { break };
+            let marker = if l == line { "=>" } else { "  " };
+            writeln!(out, "{marker} {l:>4}  {text}")?;
+        }
+        Ok(())
+    }
+}
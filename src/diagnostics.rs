@@ -0,0 +1,183 @@
+//! エラーと警告の表示。人が読む形式 (`at line .., character ..: ...`) とJSONの2種類がある
+//! 字句解析からアドレス解決までの各段階はここを通して報告するので、表示の形式を1か所で切り替えられる
+
+use std::cell::RefCell;
+use crate::json::escape;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum DiagnosticsFormat {
+    Human,
+    /// 1行に1つのJSONオブジェクト
+    Json,
+}
+
+/// エラーの種類ごとの番号と名前。エラーのenumの各variantと1対1に対応する
+pub trait ErrorCode {
+    /// `E0003`のような番号
+    fn code(&self) -> &'static str;
+    /// `LabelNotFound`のような名前
+    fn name(&self) -> &'static str;
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub file: Option<String>,
+    pub line: usize,
+    pub column: usize,
+    /// 該当する範囲の直後の文字位置
+    pub end_column: usize,
+    pub severity: Severity,
+    pub code: &'static str,
+    pub name: &'static str,
+    /// 人が読む形式で位置の後に付ける見出し (`Syntax Error`など)
+    pub title: &'static str,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn to_human(&self) -> String {
+        format!("at line {}, character {}: {}\n{}", self.line, self.column, self.title, self.message)
+    }
+
+    pub fn to_json(&self) -> String {
+        let file = match &self.file {
+            Some(f) => escape(f),
+            None => "null".to_string(),
+        };
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        format!(
+            "{{\"file\":{file},\"line\":{},\"column\":{},\"end_column\":{},\"severity\":\"{severity}\",\"code\":\"{}\",\"name\":\"{}\",\"message\":{}}}",
+            self.line, self.column, self.end_column, self.code, self.name, escape(&self.message),
+        )
+    }
+}
+
+struct Context {
+    format: DiagnosticsFormat,
+    file: Option<String>,
+    source: Vec<u8>,
+    /// `collect`の中ではここに溜めて表示しない
+    collected: Option<Vec<Diagnostic>>,
+}
+
+thread_local! {
+    static CONTEXT: RefCell<Context> = const { RefCell::new(Context {
+        format: DiagnosticsFormat::Human,
+        file: None,
+        source: vec![],
+        collected: None,
+    }) };
+}
+
+pub fn set_format(format: DiagnosticsFormat) {
+    CONTEXT.with(|c| c.borrow_mut().format = format);
+}
+
+pub fn format() -> DiagnosticsFormat {
+    CONTEXT.with(|c| c.borrow().format)
+}
+
+/// これから報告するエラーがどのファイルのものかを設定する。ソースは範囲の終わりを求めるのに使う
+pub fn set_source(file: Option<&str>, source: &[u8]) {
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        c.file = file.map(|s| s.to_string());
+        c.source = source.to_vec();
+    });
+}
+
+/// `f`の中で報告されたものを表示せずに集めて返す
+pub fn collect<T>(f: impl FnOnce() -> T) -> (T, Vec<Diagnostic>) {
+    let saved = CONTEXT.with(|c| c.borrow_mut().collected.replace(vec![]));
+    let res = f();
+    let diagnostics = CONTEXT.with(|c| std::mem::replace(&mut c.borrow_mut().collected, saved));
+    (res, diagnostics.unwrap_or_default())
+}
+
+/// `column`から始まる命令の終わり (`;`, `#`, 行末の手前) の文字位置を返す
+fn instruction_end(source: &[u8], line: usize, column: usize) -> usize {
+    let text = match source.split(|b| *b == b'\n').nth(line.wrapping_sub(1)) {
+        Some(text) => String::from_utf8_lossy(text),
+        None => { return column; }
+    };
+
+    let mut end = column;
+    let mut quote = None;
+    for (i, c) in text.chars().enumerate().skip(column.saturating_sub(1)) {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == ';' || c == '#' => break,
+            None if c == '"' || c == '\'' => quote = Some(c),
+            None => {}
+        }
+        if !c.is_whitespace() {
+            end = i + 2;
+        }
+    }
+    end
+}
+
+fn emit(mut diagnostic: Diagnostic) {
+    CONTEXT.with(|c| {
+        let mut c = c.borrow_mut();
+        diagnostic.file = c.file.clone();
+        if let Some(collected) = &mut c.collected {
+            collected.push(diagnostic);
+            return;
+        }
+        match c.format {
+            DiagnosticsFormat::Human => eprintln!("{}", diagnostic.to_human()),
+            DiagnosticsFormat::Json => eprintln!("{}", diagnostic.to_json()),
+        }
+    });
+}
+
+/// 範囲の終わりを明示してエラーを報告する
+pub fn report_span<E: ErrorCode>(
+    line: usize, column: usize, end_column: usize, title: &'static str, err: &E, message: impl Into<String>,
+) {
+    emit(Diagnostic {
+        file: None,
+        line,
+        column,
+        end_column: end_column.max(column + 1),
+        severity: Severity::Error,
+        code: err.code(),
+        name: err.name(),
+        title,
+        message: message.into(),
+    });
+}
+
+/// `column`から始まる命令についてのエラーを報告し、そのエラーを返す
+pub fn report<E: ErrorCode>(line: usize, column: usize, title: &'static str, err: E, message: impl Into<String>) -> E {
+    let end_column = CONTEXT.with(|c| instruction_end(&c.borrow().source, line, column));
+    report_span(line, column, end_column, title, &err, message);
+    err
+}
+
+/// `column`から始まる命令についての警告を報告する
+pub fn warn<E: ErrorCode>(line: usize, column: usize, warning: &E, message: impl Into<String>) {
+    let end_column = CONTEXT.with(|c| instruction_end(&c.borrow().source, line, column));
+    emit(Diagnostic {
+        file: None,
+        line,
+        column,
+        end_column: end_column.max(column + 1),
+        severity: Severity::Warning,
+        code: warning.code(),
+        name: warning.name(),
+        title: "Warning",
+        message: message.into(),
+    });
+}
@@ -0,0 +1,87 @@
+use crate::encoder::*;
+use crate::lexer::{Mnemonic, Register};
+use crate::lexer::Mnemonic::*;
+use crate::parser::Operand;
+use crate::parser::Operand::*;
+use crate::semantics::{is_conditional_branch, is_conditional_branch_ext};
+
+/// 実際に機械語になる命令 (疑似命令とディレクティブを除いたもの)
+const MACHINE_MNEMONICS: [Mnemonic; 31] = [
+    Add, Sub, Addi, Subi, Slli, Fabs, Fneg, Fadd, Fsub, Fmul, Fdiv, Fsqrt, Itof, Ftoi,
+    Ibeq, Ibne, Ible, Iblt, Fblt, Fble, Fbps, Fbng,
+    J, Jr, Call, Movl, Movh, Urecv, Usend, Lw, Sw,
+];
+
+fn get_register(n: u8) -> Register {
+    match n {
+        255 => Register::Zero,
+        254 => Register::Fp,
+        253 => Register::Sp,
+        n => Register::R(n),
+    }
+}
+
+/// op/functを表すビットのマスク。条件分岐は下位ビットをオフセットに使うので上位5bitだけを見る
+fn op_funct_mask(m: Mnemonic) -> u32 {
+    if is_conditional_branch(m) || is_conditional_branch_ext(m) { 0xf8000000 } else { 0xff000000 }
+}
+
+/// 1ワードを命令に戻す。どの命令にも当てはまらなければNone
+/// 分岐・ジャンプのオフセットはラベルではなく相対アドレスの数値になる
+pub fn disassemble_word(word: u32) -> Option<(Mnemonic, Vec<Operand>)> {
+    let mnemonic = MACHINE_MNEMONICS.into_iter()
+        .find(|m| word & op_funct_mask(*m) == get_op_funct(*m))?;
+
+    let reg = |field: Field| OpRegister(get_register(field.decode(word) as u8));
+    let imm = |field: Field| OpDigit(field.decode(word));
+
+    let operands = operand_fields(mnemonic).unwrap().iter()
+        .map(|field| if field.is_register() { reg(*field) } else { imm(*field) })
+        .collect();
+
+    Some((mnemonic, operands))
+}
+
+pub fn format_operand(operand: &Operand) -> String {
+    match operand {
+        OpRegister(r) => r.to_string(),
+        OpLabel(s) => s.clone(),
+        OpDigit(n) => n.to_string(),
+        OpFloat(bits) => format!("0f{bits:08x}"),
+        OpString(bytes) => {
+            let mut s = String::from("\"");
+            for b in bytes {
+                match b {
+                    b'\n' => s.push_str("\\n"),
+                    b'\t' => s.push_str("\\t"),
+                    b'\r' => s.push_str("\\r"),
+                    b'\\' => s.push_str("\\\\"),
+                    b'"' => s.push_str("\\\""),
+                    0x20..=0x7e => s.push(*b as char),
+                    _ => s.push_str(&format!("\\x{b:02x}")),
+                }
+            }
+            s.push('"');
+            s
+        }
+    }
+}
+
+/// `addi r1, zero, 5`のような、アセンブラがそのまま読める形式にする
+pub fn format_instruction(mnemonic: Mnemonic, operands: &[Operand]) -> String {
+    if operands.is_empty() {
+        return mnemonic.to_string();
+    }
+    let operands: Vec<_> = operands.iter().map(format_operand).collect();
+    format!("{} {}", mnemonic, operands.join(", "))
+}
+
+/// 機械語列を1ワード1行のアセンブリに戻す。命令として解釈できないワードは.wordにする
+pub fn disassemble(binary: &[u32]) -> Vec<String> {
+    binary.iter()
+        .map(|word| match disassemble_word(*word) {
+            Some((mnemonic, operands)) => format_instruction(mnemonic, &operands),
+            None => format_instruction(Word, &[OpDigit(*word as i64)]),
+        })
+        .collect()
+}
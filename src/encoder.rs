@@ -1,10 +1,12 @@
+use std::cell::Cell;
 use crate::lexer::{Mnemonic, Register};
 use crate::lexer::Mnemonic::*;
 use crate::parser::Instruction;
 use crate::parser::Operand::*;
+use crate::resolver::is_pseudo_instr;
 use crate::semantics::{is_arithmetic, is_arithmetic_ext, is_arithmetic_imm, is_conditional_branch, is_conditional_branch_ext};
 
-fn get_register_num(r: Register) -> u8 {
+pub(crate) fn get_register_num(r: Register) -> u8 {
     match r {
         Register::Zero => 255,
         Register::Fp => 254,
@@ -13,7 +15,7 @@ fn get_register_num(r: Register) -> u8 {
     }
 }
 
-fn get_op_funct(m: Mnemonic) -> u32 {
+pub(crate) fn get_op_funct(m: Mnemonic) -> u32 {
     match m {
         Add => 0x08000000,
         Sub => 0x09000000,
@@ -52,6 +54,150 @@ fn get_op_funct(m: Mnemonic) -> u32 {
     }
 }
 
+/// 命令語中のフィールド。shiftビット目からwidthビットを占める
+/// signedなら2の補数として解釈される
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Field {
+    pub name: &'static str,
+    pub shift: u32,
+    pub width: u32,
+    pub signed: bool,
+    /// 即値ではなくレジスタ番号を入れるフィールドか
+    pub register: bool,
+}
+
+impl Field {
+    /// 表現できる値の範囲 [min, max]
+    pub const fn min(&self) -> i64 {
+        if self.signed { -(1 << (self.width - 1)) } else { 0 }
+    }
+
+    pub const fn max(&self) -> i64 {
+        if self.signed { (1 << (self.width - 1)) - 1 } else { (1 << self.width) - 1 }
+    }
+
+    pub fn is_register(&self) -> bool {
+        self.register
+    }
+
+    pub fn fits(&self, x: i64) -> bool {
+        self.min() <= x && x <= self.max()
+    }
+
+    /// 範囲外の値をエラーメッセージ用に説明する
+    pub fn describe(&self) -> String {
+        let sign = if self.signed { "signed" } else { "unsigned" };
+        format!("{}bit {sign} integer ({}..={})", self.width, self.min(), self.max())
+    }
+
+    /// 命令語からフィールドの値を取り出す。signedなら符号拡張する
+    pub fn decode(&self, word: u32) -> i64 {
+        let x = ((word >> self.shift) as u64 & ((1_u64 << self.width) - 1)) as i64;
+        if self.signed && x >= 1 << (self.width - 1) { x - (1 << self.width) } else { x }
+    }
+
+    /// 値をフィールドに詰める。範囲外の値が来るのはsemantic checkかアドレス解決のバグなので、黙って切り詰めずにpanicする
+    fn encode(&self, x: i64, line: usize) -> u32 {
+        assert!(
+            self.fits(x),
+            "{} = {x} (from line {line}) does not fit in {}", self.name, self.describe(),
+        );
+        let mask = ((1_u64 << self.width) - 1) as u32;
+        ((x as u32) & mask) << self.shift
+    }
+}
+
+/// 対象のコアによって変わる、命令セットの細かい違い。`--isa`で選ぶ
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Isa {
+    /// lw/swのオフセットをコアが符号拡張するか。するなら負のオフセットが書ける
+    pub signed_memory_offset: bool,
+}
+
+impl Isa {
+    /// `--isa`で指定する名前から。`1st`はオフセットを符号拡張しないコア
+    pub fn from_name(name: &str) -> Option<Isa> {
+        match name {
+            "1st" => Some(Isa { signed_memory_offset: false }),
+            "1st-signed-offset" => Some(Isa { signed_memory_offset: true }),
+            _ => None,
+        }
+    }
+
+    pub const fn lw_offset(self) -> Field {
+        if self.signed_memory_offset { LW_OFFSET_SIGNED } else { LW_OFFSET }
+    }
+
+    pub const fn sw_offset(self) -> Field {
+        if self.signed_memory_offset { SW_OFFSET_SIGNED } else { SW_OFFSET }
+    }
+}
+
+thread_local! {
+    static ISA: Cell<Isa> = const { Cell::new(Isa { signed_memory_offset: false }) };
+}
+
+/// これからのsemantic check、エンコード、逆アセンブルで使う命令セットを選ぶ
+pub fn set_isa(isa: Isa) {
+    ISA.with(|i| i.set(isa));
+}
+
+pub fn isa() -> Isa {
+    ISA.with(|i| i.get())
+}
+
+const fn register_field(name: &'static str, shift: u32) -> Field {
+    Field { name, shift, width: 8, signed: false, register: true }
+}
+
+pub const ARITH_IMM: Field = Field { name: "immediate", shift: 0, width: 8, signed: false, register: false };
+pub const BRANCH_OFFSET: Field = Field { name: "branch offset", shift: 16, width: 11, signed: true, register: false };
+pub const JUMP_OFFSET: Field = Field { name: "jump offset", shift: 8, width: 16, signed: true, register: false };
+pub const MOV_IMM: Field = Field { name: "mov immediate", shift: 8, width: 16, signed: false, register: false };
+/// lw/swのオフセット。符号拡張するコアでは`Isa::lw_offset`などが`_SIGNED`の方を返す
+pub const LW_OFFSET: Field = Field { name: "lw offset", shift: 8, width: 8, signed: false, register: false };
+pub const SW_OFFSET: Field = Field { name: "sw offset", shift: 16, width: 8, signed: false, register: false };
+const LW_OFFSET_SIGNED: Field = Field { signed: true, ..LW_OFFSET };
+const SW_OFFSET_SIGNED: Field = Field { signed: true, ..SW_OFFSET };
+pub const WORD: Field = Field { name: "data word", shift: 0, width: 32, signed: false, register: false };
+
+pub(crate) const REG_16: Field = register_field("register", 16);
+pub(crate) const REG_8: Field = register_field("register", 8);
+pub(crate) const REG_0: Field = register_field("register", 0);
+
+/// 機械語になる命令の各オペランドを、命令語のどのフィールドに置くか (オペランドの順に並ぶ)
+/// 疑似命令とディレクティブにはNoneを返す
+pub fn operand_fields(mnemonic: Mnemonic) -> Option<&'static [Field]> {
+    if is_pseudo_instr(mnemonic) || mnemonic == Word {
+        return None;
+    }
+
+    let fields: &[Field] = if is_arithmetic(mnemonic) {
+        if is_arithmetic_imm(mnemonic) { &[REG_16, REG_8, ARITH_IMM] } else { &[REG_16, REG_8, REG_0] }
+    } else if is_arithmetic_ext(mnemonic) {
+        &[REG_16, REG_0]
+    } else if is_conditional_branch(mnemonic) {
+        &[REG_8, REG_0, BRANCH_OFFSET]
+    } else if is_conditional_branch_ext(mnemonic) {
+        &[REG_0, BRANCH_OFFSET]
+    } else if mnemonic == J || mnemonic == Call {
+        &[JUMP_OFFSET]
+    } else if mnemonic == Jr || mnemonic == Usend {
+        &[REG_0]
+    } else if mnemonic == Movl || mnemonic == Movh {
+        &[REG_0, MOV_IMM]
+    } else if mnemonic == Urecv {
+        &[REG_16]
+    } else if mnemonic == Lw {
+        if isa().signed_memory_offset { &[REG_16, REG_0, LW_OFFSET_SIGNED] } else { &[REG_16, REG_0, LW_OFFSET] }
+    } else if mnemonic == Sw {
+        if isa().signed_memory_offset { &[REG_8, REG_0, SW_OFFSET_SIGNED] } else { &[REG_8, REG_0, SW_OFFSET] }
+    } else {
+        unreachable!()
+    };
+    Some(fields)
+}
+
 macro_rules! cast {
     ($target: expr, $pat: path) => {
         { if let $pat(a) = $target { a } else { unreachable!() } }
@@ -64,53 +210,24 @@ pub fn encode(instructions: Vec<Instruction>) -> Vec<u32> {
     let mut binary = vec![];
 
     let it = instructions.into_iter();
-    for Instruction { mnemonic, operands, .. } in it {
-        let mut b = 0_u32;
-
-        if is_arithmetic(mnemonic) {
-            b |= (get_register_num(cast!(operands[0], OpRegister)) as u32) << 16;
-            b |= (get_register_num(cast!(operands[1], OpRegister)) as u32) << 8;
-
-            if is_arithmetic_imm(mnemonic) {
-                b |= cast!(operands[2], OpDigit) as u32;
-            } else {
-                b |= get_register_num(cast!(operands[2], OpRegister)) as u32;
-            }
-        } else if is_arithmetic_ext(mnemonic) {
-            b |= (get_register_num(cast!(operands[0], OpRegister)) as u32) << 16;
-            b |= get_register_num(cast!(operands[1], OpRegister)) as u32;
-        } else if is_conditional_branch(mnemonic) {
-            b |= (get_register_num(cast!(operands[0], OpRegister)) as u32) << 8;
-            b |= get_register_num(cast!(operands[1], OpRegister)) as u32;
-            b |= ((cast!(operands[2], OpDigit) as u32) << 16) & 0x07ffffff;
-        } else if is_conditional_branch_ext(mnemonic) {
-            b |= get_register_num(cast!(operands[0], OpRegister)) as u32;
-            b |= ((cast!(operands[1], OpDigit) as u32) << 16) & 0x07ffffff;
-        } else if mnemonic == J || mnemonic == Call {
-            b |= ((cast!(operands[0], OpDigit) as u32) << 8) & 0x00ffff00;
-        } else if mnemonic == Jr || mnemonic == Usend {
-            b |= get_register_num(cast!(operands[0], OpRegister)) as u32;
-        } else if mnemonic == Movl || mnemonic == Movh {
-            b |= get_register_num(cast!(operands[0], OpRegister)) as u32;
-            b |= ((cast!(operands[1], OpDigit) as u32) << 8) & 0x00ffff00;
-        } else if mnemonic == Urecv {
-            b |= (get_register_num(cast!(operands[0], OpRegister)) as u32) << 16;
-        } else if mnemonic == Lw {
-            b |= (get_register_num(cast!(operands[0], OpRegister)) as u32) << 16;
-            b |= get_register_num(cast!(operands[1], OpRegister)) as u32;
-            b |= ((cast!(operands[2], OpDigit) as u32) << 8) & 0x0000ff00;  // 8bitを超えない保証はあるが怖い
-        } else if mnemonic == Sw {
-            b |= (get_register_num(cast!(operands[0], OpRegister)) as u32) << 8;
-            b |= get_register_num(cast!(operands[1], OpRegister)) as u32;
-            b |= ((cast!(operands[2], OpDigit) as u32) << 16) & 0x00ff0000;  // 8bitを超えない保証はあるが怖い
-            // } else if mnemonic == Put {
-            //     b = cast!(operands[0], OpDigit) as u32;
-        } else {
-            unreachable!();
+    for Instruction { mnemonic, operands, line, .. } in it {
+        if mnemonic == Word {
+            // 命令ではなくデータなので、op/functは付けない
+            // .word -1のように負の値も書けるので、ビット列として解釈する
+            binary.push(WORD.encode(cast!(operands[0], OpDigit) & 0xffffffff, line));
+            continue;
         }
 
-        let op_funct = get_op_funct(mnemonic);
-        b |= op_funct;
+        let mut b = get_op_funct(mnemonic);
+        let fields = operand_fields(mnemonic).unwrap();
+        for (field, operand) in fields.iter().zip(&operands) {
+            let x = match operand {
+                OpRegister(r) => get_register_num(*r) as i64,
+                OpDigit(n) => *n,
+                _ => unreachable!()
+            };
+            b |= field.encode(x, line);
+        }
         binary.push(b);
     }
 
@@ -0,0 +1,163 @@
+//! ソースを決まった形式に整える (`asm fmt`)
+//! 命令の並びは構文解析の結果から作り直し、コメントと数値の書き方 (`0xff`, `'a'`など) はトリビアを残すLexerで元のソースから拾う
+
+use std::collections::HashMap;
+use crate::disassembler::format_operand;
+use crate::lexer::Mnemonic;
+use crate::parser::{Instruction, Operand};
+use crate::slice_lexer::{SliceLexer, Span, Token};
+
+/// 命令の字下げ
+const INDENT: usize = 4;
+/// 行末のコメントをこの文字位置に揃える
+const COMMENT_COLUMN: usize = 40;
+
+/// `#`から行末までのコメント
+struct Comment {
+    text: String,
+    /// `#`の文字位置 (0始まり)
+    column: usize,
+}
+
+/// 元のソースでの書き方を残すオペランドか
+fn keeps_spelling(operand: &Operand) -> bool {
+    matches!(operand, Operand::OpDigit(_) | Operand::OpFloat(_) | Operand::OpString(_))
+}
+
+/// 1命令分の文字列。ラベルとコメントは含まない
+fn format_instr(instr: &Instruction, spellings: Option<&Vec<String>>, width: usize) -> String {
+    let operands: Vec<_> = instr.operands.iter().enumerate()
+        .map(|(i, operand)| match spellings.and_then(|s| s.get(i)) {
+            Some(s) if keeps_spelling(operand) => s.clone(),
+            _ => format_operand(operand),
+        })
+        .collect();
+    if operands.is_empty() {
+        return instr.mnemonic.to_string();
+    }
+    format!("{:<width$} {}", instr.mnemonic.to_string(), operands.join(", "))
+}
+
+/// 構文解析の結果`inst`を、元のソース`src`のコメントを残して整形する
+///
+/// - ラベルは1行に1つずつ行頭に置き、命令は字下げしてオペランドの桁を揃える
+/// - ニーモニックとレジスタは小文字にする
+/// - `;`で区切った行はそのまま1行に保つ
+/// - 空行は続いていれば1行にまとめる
+pub fn format_source(src: &[u8], inst: &[Instruction]) -> String {
+    // コメントは行ごとに分け、それ以外のトークンは順に並べる
+    let mut comments = HashMap::new();
+    let mut tokens: Vec<(Token, Span)> = vec![];
+    for (token, span) in SliceLexer::with_trivia(src).map_while(|res| res.ok()) {
+        match token {
+            Token::Eof => { break; }
+            Token::Comment(text) => {
+                let text = String::from_utf8_lossy(text).trim_end().to_string();
+                comments.insert(span.line, Comment { text, column: span.character - 1 });
+            }
+            Token::Whitespace(_) => {}
+            _ => tokens.push((token, span)),
+        }
+    }
+
+    // ラベルを定義している行と、命令ごとのオペランドの元の書き方
+    let mut label_lines: HashMap<&str, Vec<usize>> = HashMap::new();
+    let mut spellings: HashMap<(usize, usize), Vec<String>> = HashMap::new();
+    for (i, (token, span)) in tokens.iter().enumerate() {
+        match token {
+            Token::Label(name) if matches!(tokens.get(i + 1), Some((Token::Colon, _))) => {
+                label_lines.entry(name).or_default().push(span.line);
+            }
+            Token::Mnemonic(_) => {
+                let operands = tokens[i + 1..].iter()
+                    .take_while(|(t, _)| !matches!(t, Token::Semicolon | Token::Newline))
+                    .filter(|(t, _)| *t != Token::Comma)
+                    .map(|(_, s)| {
+                        let text = String::from_utf8_lossy(&src[s.start..s.end]).into_owned();
+                        // `- 5`のような空白は詰める
+                        if text.starts_with('-') { text.split_whitespace().collect() } else { text }
+                    })
+                    .collect();
+                spellings.insert((span.line, span.character), operands);
+            }
+            _ => {}
+        }
+    }
+
+    // 行ごとに、そこで定義されたラベル (前に付けるもの) と命令をまとめる
+    // ラベルの行番号は、定義された順に命令へ割り当てる
+    let mut used: HashMap<&str, usize> = HashMap::new();
+    let mut labels_by_line: HashMap<usize, Vec<String>> = HashMap::new();
+    let mut inst_by_line: HashMap<usize, Vec<(Vec<String>, &Instruction)>> = HashMap::new();
+    for instr in inst {
+        let mut inline = vec![];
+        for label in &instr.label {
+            let k = used.entry(label).or_insert(0);
+            let line = label_lines.get(label.as_str()).and_then(|lines| lines.get(*k)).copied().unwrap_or(instr.line);
+            *k += 1;
+            let is_first = !inst_by_line.contains_key(&instr.line);
+            if line == instr.line && !is_first {
+                // `;`の後ろで定義されたラベルは命令の前にそのまま書く
+                inline.push(label.clone());
+            } else {
+                labels_by_line.entry(line).or_default().push(label.clone());
+            }
+        }
+        inst_by_line.entry(instr.line).or_default().push((inline, instr));
+    }
+
+    let width = Mnemonic::ALL.iter().map(|m| m.name().len()).max().unwrap();
+    let line_count = src.split(|b| *b == b'\n').count();
+    let mut lines: Vec<String> = vec![];
+    for l in 1..=line_count {
+        let comment = comments.remove(&l);
+        let mut code = vec![];
+        for label in labels_by_line.remove(&l).unwrap_or_default() {
+            code.push(format!("{label}:"));
+        }
+        if let Some(instrs) = inst_by_line.remove(&l) {
+            let segments: Vec<_> = instrs.iter()
+                .enumerate()
+                .map(|(i, (inline, instr))| {
+                    // 揃えるのは行の最初の命令だけ
+                    let width = if i == 0 { width } else { 0 };
+                    let s = format_instr(instr, spellings.get(&(instr.line, instr.ch)), width);
+                    inline.iter().map(|label| format!("{label}: ")).collect::<String>() + &s
+                })
+                .collect();
+            code.push(format!("{}{}", " ".repeat(INDENT), segments.join("; ")));
+        }
+
+        match (code.pop(), comment) {
+            (Some(last), Some(comment)) => {
+                lines.extend(code);
+                let pad = COMMENT_COLUMN.saturating_sub(last.chars().count()).max(1);
+                lines.push(format!("{last}{}{}", " ".repeat(pad), comment.text));
+            }
+            (Some(last), None) => {
+                lines.extend(code);
+                lines.push(last);
+            }
+            (None, Some(comment)) => {
+                // 行頭のコメントは行頭に、それ以外は命令と同じだけ字下げする
+                let indent = if comment.column == 0 { 0 } else { INDENT };
+                lines.push(format!("{}{}", " ".repeat(indent), comment.text));
+            }
+            (None, None) => {
+                if lines.last().is_some_and(|s| !s.is_empty()) {
+                    lines.push(String::new());
+                }
+            }
+        }
+    }
+    while lines.last().is_some_and(|s| s.is_empty()) {
+        lines.pop();
+    }
+
+    let mut out = String::new();
+    for line in lines {
+        out.push_str(&line);
+        out.push('\n');
+    }
+    out
+}
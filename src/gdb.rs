@@ -0,0 +1,443 @@
+//! GDBのリモートシリアルプロトコル (RSP) でシミュレータを操作するスタブ (`asm gdb`)
+//! 実行の制御は`Debugger`に任せ、ここではパケットの読み書きと変換だけをする
+//!
+//! GDBはバイト単位のアドレスを使うので、ワード`w`をバイト`4w..4w+4`にリトルエンディアンで置いて見せる
+//! pcもバイト単位で見せるが、sp, fpなどのレジスタの値はプログラムから見たワード単位のまま
+
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use crate::debugger::{Debugger, PointKind, Stop, Target};
+use crate::lexer::Register;
+use crate::simulator::SimError;
+
+/// ワードのバイト数
+const WORD_BYTES: usize = 4;
+/// GDBから見たレジスタの数。r0..r252, sp, fp, zeroの256個とpc
+const REGISTER_COUNT: usize = 257;
+const PC_REGNUM: usize = 256;
+/// 受け取れるパケットの大きさ。`g`の応答 (レジスタ1つに16進数8文字) が収まるようにする
+const PACKET_SIZE: usize = 0x4000;
+/// `c`で続けて実行する命令の数。この数ごとに割り込み (0x03) が届いていないか見る
+const RESUME_BATCH: usize = 1 << 16;
+/// 実行中に届く割り込み
+const INTERRUPT: u8 = 0x03;
+
+/// GDBのレジスタ番号に対応するレジスタ。番号は機械語でのレジスタ番号と同じ
+fn register(n: usize) -> Option<Register> {
+    match n {
+        0..=252 => Some(Register::R(n as u8)),
+        253 => Some(Register::Sp),
+        254 => Some(Register::Fp),
+        255 => Some(Register::Zero),
+        _ => None,
+    }
+}
+
+/// レジスタの一覧から作ったターゲット記述
+pub fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n  <feature name=\"org.asm1st.core\">\n",
+    );
+    for n in 0..PC_REGNUM {
+        let r = register(n).unwrap();
+        writeln!(xml, "    <reg name=\"{r}\" bitsize=\"32\" type=\"int\" regnum=\"{n}\"/>").unwrap();
+    }
+    writeln!(xml, "    <reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{PC_REGNUM}\"/>").unwrap();
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0, |sum, b| sum.wrapping_add(*b))
+}
+
+/// `$data#xx`の形にする
+pub fn encode_packet(data: &str) -> Vec<u8> {
+    format!("${data}#{:02x}", checksum(data.as_bytes())).into_bytes()
+}
+
+/// バイナリを送る応答で、特別な意味を持つ文字を`}`と0x20のxorにする
+fn escape_binary(data: &str) -> String {
+    let mut out = String::new();
+    for c in data.chars() {
+        if matches!(c, '#' | '$' | '}' | '*') {
+            out.push('}');
+            out.push((c as u8 ^ 0x20) as char);
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex(s: &str) -> Option<usize> {
+    usize::from_str_radix(s, 16).ok()
+}
+
+/// 32bitの値をターゲットのバイト順 (リトルエンディアン) の16進数にする
+fn hex_word(v: u32) -> String {
+    hex(&v.to_le_bytes())
+}
+
+fn parse_hex_word(s: &str) -> Option<u32> {
+    let bytes = parse_hex_bytes(s)?;
+    Some(u32::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// 入力を別のスレッドで読み、届いた分をためておく。実行中でも割り込みに気づけるようにする
+struct Input {
+    receiver: Receiver<io::Result<Vec<u8>>>,
+    buffer: VecDeque<u8>,
+}
+
+impl Input {
+    fn spawn(mut input: impl Read + Send + 'static) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        // 接続が切れるまで読む。受け取る側が先に終わったら、次に届いた分を送れずに終わる
+        thread::spawn(move || {
+            let mut chunk = [0; 4096];
+            loop {
+                let read = match input.read(&mut chunk) {
+                    Ok(0) => { return; }
+                    Ok(n) => Ok(chunk[..n].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => { continue; }
+                    Err(e) => Err(e),
+                };
+                let failed = read.is_err();
+                if sender.send(read).is_err() || failed {
+                    return;
+                }
+            }
+        });
+        Self { receiver, buffer: VecDeque::new() }
+    }
+
+    /// 1バイト読む。届くまで待つ。接続が切れたらNone
+    fn next(&mut self) -> io::Result<Option<u8>> {
+        while self.buffer.is_empty() {
+            match self.receiver.recv() {
+                Ok(chunk) => self.buffer.extend(chunk?),
+                Err(_) => { return Ok(None); }
+            }
+        }
+        Ok(self.buffer.pop_front())
+    }
+
+    /// 待たずに、割り込みが届いているかを見る。届いていれば取り除く
+    fn interrupted(&mut self) -> bool {
+        // 読み込みのエラーは捨てる。接続が切れたことは、次のパケットを読むときに分かる
+        while let Ok(Ok(chunk)) = self.receiver.try_recv() {
+            self.buffer.extend(chunk);
+        }
+        match self.buffer.iter().position(|b| *b == INTERRUPT) {
+            Some(i) => {
+                self.buffer.remove(i);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct GdbStub {
+    pub debugger: Debugger,
+    /// `QStartNoAckMode`の後は`+`/`-`を送らない
+    no_ack: bool,
+}
+
+impl GdbStub {
+    pub fn new(debugger: Debugger) -> Self {
+        Self { debugger, no_ack: false }
+    }
+
+    /// 接続が切れるか、`k`/`D`を受け取るまでパケットに応答する
+    /// 入力は別のスレッドで読むので、`c`で実行している間も割り込みを受け付ける
+    pub fn serve(&mut self, input: impl Read + Send + 'static, output: &mut dyn Write) -> io::Result<()> {
+        let mut input = Input::spawn(input);
+        while let Some(packet) = self.read_packet(&mut input, output)? {
+            let (replies, done) = self.respond(&packet, &mut || input.interrupted());
+            for reply in replies {
+                output.write_all(&encode_packet(&reply))?;
+            }
+            output.flush()?;
+            if done {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// パケットを1つ読む。`$`より前のack (`+`/`-`) や、止まっているときに届いた割り込みは読み飛ばす。接続が切れたらNone
+    fn read_packet(&mut self, input: &mut Input, output: &mut dyn Write) -> io::Result<Option<String>> {
+        loop {
+            loop {
+                match input.next()? {
+                    Some(b'$') => break,
+                    Some(_) => {}
+                    None => { return Ok(None); }
+                }
+            }
+            let mut data = vec![];
+            loop {
+                match input.next()? {
+                    Some(b'#') => break,
+                    Some(b) => data.push(b),
+                    None => { return Ok(None); }
+                }
+            }
+            let (Some(high), Some(low)) = (input.next()?, input.next()?) else {
+                return Ok(None);
+            };
+            let sum = [high, low];
+
+            let valid = std::str::from_utf8(&sum).ok()
+                .and_then(|s| u8::from_str_radix(s, 16).ok())
+                .is_some_and(|s| s == checksum(&data));
+            if !self.no_ack {
+                output.write_all(if valid { b"+" } else { b"-" })?;
+                output.flush()?;
+            }
+            if valid {
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+        }
+    }
+
+    /// 1つのパケットに応答する。返すのは送るパケットの中身と、接続を閉じるか
+    /// 知らないパケットには空のパケットを返す (プロトコルの決まり)。`c`は止まるまで実行する
+    pub fn handle(&mut self, packet: &str) -> (Vec<String>, bool) {
+        self.respond(packet, &mut || false)
+    }
+
+    /// `handle`と同じだが、`c`で実行している間に`interrupted`が成り立ったら`T02`で止まる
+    fn respond(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> (Vec<String>, bool) {
+        let reply = |s: &str| (vec![s.to_string()], false);
+        let Some(kind) = packet.chars().next() else {
+            return reply("");
+        };
+        let args = &packet[kind.len_utf8()..];
+
+        if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            return reply("OK");
+        }
+        if packet.starts_with("qSupported") {
+            return reply(&format!("PacketSize={PACKET_SIZE:x};qXfer:features:read+;QStartNoAckMode+;swbreak+"));
+        }
+        if let Some(rest) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            return reply(&self.read_target_xml(rest).unwrap_or_else(|| "E00".to_string()));
+        }
+        if packet == "qAttached" {
+            return reply("1");
+        }
+        if packet == "qfThreadInfo" {
+            return reply("m1");
+        }
+        if packet == "qsThreadInfo" {
+            return reply("l");
+        }
+        if packet == "qC" {
+            return reply("QC1");
+        }
+
+        match kind {
+            '?' => {
+                let stop = if self.debugger.halted() { Stop::Halted } else { Stop::Step };
+                reply(&self.stop_reply(&stop).1)
+            }
+            'H' | 'T' => reply("OK"),
+            'g' => {
+                let regs: String = (0..REGISTER_COUNT).map(|n| hex_word(self.read_register(n))).collect();
+                reply(&regs)
+            }
+            'G' => {
+                // 16進数でない文字が混ざっていても、文字の境界で切らないようにバイト列で分ける
+                let values: Option<Vec<u32>> = args.as_bytes().chunks(8)
+                    .map(|word| parse_hex_word(std::str::from_utf8(word).ok()?))
+                    .collect();
+                match values {
+                    Some(values) if values.len() == REGISTER_COUNT => {
+                        for (n, v) in values.into_iter().enumerate() {
+                            self.write_register(n, v);
+                        }
+                        reply("OK")
+                    }
+                    _ => reply("E01"),
+                }
+            }
+            'p' => match parse_hex(args).filter(|n| *n < REGISTER_COUNT) {
+                Some(n) => reply(&hex_word(self.read_register(n))),
+                None => reply("E01"),
+            },
+            'P' => {
+                let parsed = args.split_once('=')
+                    .and_then(|(n, v)| Some((parse_hex(n)?, parse_hex_word(v)?)))
+                    .filter(|(n, _)| *n < REGISTER_COUNT);
+                match parsed {
+                    Some((n, v)) => {
+                        self.write_register(n, v);
+                        reply("OK")
+                    }
+                    None => reply("E01"),
+                }
+            }
+            'm' => {
+                let range = args.split_once(',').and_then(|(a, l)| Some((parse_hex(a)?, parse_hex(l)?)));
+                match range.and_then(|(address, len)| self.read_memory(address, len)) {
+                    Some(bytes) => reply(&hex(&bytes)),
+                    None => reply("E01"),
+                }
+            }
+            'M' => {
+                let parsed = args.split_once(':').and_then(|(range, data)| {
+                    let (a, l) = range.split_once(',')?;
+                    let bytes = parse_hex_bytes(data)?;
+                    (parse_hex(l)? == bytes.len()).then_some((parse_hex(a)?, bytes))
+                });
+                match parsed {
+                    Some((address, bytes)) if self.write_memory(address, &bytes) => reply("OK"),
+                    _ => reply("E01"),
+                }
+            }
+            'Z' | 'z' => reply(self.set_point(kind == 'Z', args)),
+            's' | 'c' => {
+                // 再開するアドレスを書くこともできる
+                if let Some(address) = parse_hex(args) {
+                    self.debugger.machine.pc = (address / WORD_BYTES) as u32;
+                }
+                let stop = if kind == 's' { self.debugger.step() } else { self.resume(interrupted) };
+                let (console, stop) = self.stop_reply(&stop);
+                let mut replies: Vec<String> = console.into_iter().collect();
+                replies.push(stop);
+                (replies, false)
+            }
+            'k' => (vec![], true),
+            'D' => (vec!["OK".to_string()], true),
+            _ => reply(""),
+        }
+    }
+
+    /// 止まるまで少しずつ実行し、その合間に割り込みを確かめる
+    fn resume(&mut self, interrupted: &mut dyn FnMut() -> bool) -> Stop {
+        loop {
+            if let Some(stop) = self.debugger.run(Target::Resume, RESUME_BATCH) {
+                return stop;
+            }
+            if interrupted() {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    /// `offset,length`で指定された部分。最後の部分なら`l`、続きがあれば`m`を付ける
+    fn read_target_xml(&self, range: &str) -> Option<String> {
+        let (offset, length) = range.split_once(',')?;
+        let (offset, length) = (parse_hex(offset)?, parse_hex(length)?);
+        let xml = target_xml();
+        let start = offset.min(xml.len());
+        let end = start.saturating_add(length).min(xml.len());
+        let prefix = if end == xml.len() { 'l' } else { 'm' };
+        Some(format!("{prefix}{}", escape_binary(&xml[start..end])))
+    }
+
+    fn read_register(&self, n: usize) -> u32 {
+        match register(n) {
+            Some(r) => self.debugger.machine.reg(r),
+            None => self.debugger.machine.pc * WORD_BYTES as u32,
+        }
+    }
+
+    /// zeroへの書き込みは捨てる
+    fn write_register(&mut self, n: usize, v: u32) {
+        match register(n) {
+            Some(r) => self.debugger.machine.set_reg(r, v),
+            None => self.debugger.machine.pc = v / WORD_BYTES as u32,
+        }
+    }
+
+    fn read_memory(&self, address: usize, len: usize) -> Option<Vec<u8>> {
+        let memory = &self.debugger.machine.memory;
+        (address..address.checked_add(len)?)
+            .map(|a| memory.get(a / WORD_BYTES).map(|w| w.to_le_bytes()[a % WORD_BYTES]))
+            .collect()
+    }
+
+    fn write_memory(&mut self, address: usize, bytes: &[u8]) -> bool {
+        let machine = &mut self.debugger.machine;
+        let Some(end) = address.checked_add(bytes.len()) else {
+            return false;
+        };
+        if end.div_ceil(WORD_BYTES) > machine.memory.len() {
+            return false;
+        }
+        for (i, b) in bytes.iter().enumerate() {
+            let a = address + i;
+            let mut word = machine.memory[a / WORD_BYTES].to_le_bytes();
+            word[a % WORD_BYTES] = *b;
+            machine.store(a / WORD_BYTES, u32::from_le_bytes(word));
+        }
+        true
+    }
+
+    /// `Z0,addr,kind`/`z0,addr,kind`。0と1はブレークポイント、2は書き込みのウォッチポイント
+    fn set_point(&mut self, insert: bool, args: &str) -> &'static str {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(address)) = (fields.next(), fields.next().and_then(parse_hex)) else {
+            return "E01";
+        };
+        let (kind, address) = match kind {
+            "0" | "1" => (PointKind::Breakpoint, address / WORD_BYTES),
+            "2" => (PointKind::Watchpoint, address / WORD_BYTES),
+            _ => { return ""; }
+        };
+        if insert {
+            match kind {
+                PointKind::Breakpoint => { self.debugger.add_breakpoint(address); }
+                PointKind::Watchpoint => {
+                    if self.debugger.add_watchpoint(address as i64).is_err() {
+                        return "E01";
+                    }
+                }
+            }
+        } else {
+            let id = self.debugger.points().iter().find(|p| p.kind == kind && p.address == address).map(|p| p.id);
+            if let Some(id) = id {
+                self.debugger.delete_point(id);
+            }
+        }
+        "OK"
+    }
+
+    /// 止まった理由を停止パケットにする。実行時エラーはコンソールへの出力 (`O`パケット) でも知らせる
+    fn stop_reply(&self, stop: &Stop) -> (Option<String>, String) {
+        match stop {
+            Stop::Step => (None, "S05".to_string()),
+            Stop::Breakpoint(_) => (None, "T05swbreak:;".to_string()),
+            Stop::Watchpoint { address, .. } => (None, format!("T05watch:{:x};", address * WORD_BYTES)),
+            Stop::Halted => (None, "W00".to_string()),
+            Stop::Interrupted => (None, "T02".to_string()),
+            Stop::Error(e) => {
+                let signal = match e {
+                    SimError::InvalidInstructionError { .. } => "04",
+                    SimError::MemoryOutOfRangeError { .. } => "0b",
+                    _ => "05",
+                };
+                (Some(format!("O{}", hex(format!("{e}\n").as_bytes()))), format!("S{signal}"))
+            }
+        }
+    }
+}
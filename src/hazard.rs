@@ -0,0 +1,299 @@
+//! パイプラインのハザード (データハザードと分岐の遅延スロット) の検出と、nopの自動挿入
+//! 命令の種類ごとのレイテンシとフォワーディングの有無は`HazardModel`で設定する
+
+use std::collections::HashMap;
+use crate::diagnostics::{warn, ErrorCode};
+use crate::json::{self, Value};
+use crate::lexer::Mnemonic::*;
+use crate::lexer::{Mnemonic, Register};
+use crate::parser::{Instruction, Operand};
+use crate::resolver::{resolve_without_optimization, ResolutionError};
+use crate::semantics::{is_conditional_branch, is_conditional_branch_ext, read_registers, written_registers};
+
+/// 対象のコアのパイプラインの性質
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct HazardModel {
+    /// 結果を後続の命令にフォワーディングできるか
+    pub forwarding: bool,
+    /// フォワーディングできない場合に、結果がレジスタファイルに書かれるまで余計にかかるサイクル数
+    pub register_file_delay: usize,
+    /// 命令の結果を使えるようになるまでのサイクル数。1なら次の命令ですぐ使える。表に無い命令は1
+    pub latency: HashMap<Mnemonic, usize>,
+    /// 分岐・ジャンプの後で、分岐の成否によらず実行される命令の数
+    pub branch_delay_slots: usize,
+}
+
+impl Default for HazardModel {
+    /// フォワーディングがあり、ロードと浮動小数点演算だけが遅いコア
+    fn default() -> Self {
+        let latency = [
+            (Lw, 2), (Fadd, 2), (Fsub, 2), (Fmul, 2), (Itof, 2), (Ftoi, 2), (Fdiv, 8), (Fsqrt, 8),
+        ];
+        Self {
+            forwarding: true,
+            register_file_delay: 2,
+            latency: latency.into_iter().collect(),
+            branch_delay_slots: 0,
+        }
+    }
+}
+
+impl HazardModel {
+    /// JSONで書いた設定を読む。書かなかった項目は既定値のまま
+    ///
+    /// `{"forwarding": false, "register_file_delay": 2, "branch_delay_slots": 1, "latency": {"lw": 3, "fdiv": 10}}`
+    pub fn from_json(src: &str) -> Result<Self, String> {
+        let mut model = Self::default();
+        for (key, v) in json_object(src, "hazard model")? {
+            if !model.set_json(&key, &v)? {
+                return Err(format!("unknown key \"{key}\"."));
+            }
+        }
+        Ok(model)
+    }
+
+    /// JSONの項目を1つ読む。このモデルの項目でなければfalseを返す
+    pub(crate) fn set_json(&mut self, key: &str, v: &Value) -> Result<bool, String> {
+        match key {
+            "forwarding" => {
+                self.forwarding = v.as_bool().ok_or("\"forwarding\" must be true or false.")?;
+            }
+            "register_file_delay" => self.register_file_delay = json_count(key, v)?,
+            "branch_delay_slots" => self.branch_delay_slots = json_count(key, v)?,
+            "latency" => {
+                let Value::Object(latency) = v else {
+                    return Err("\"latency\" must be an object.".to_string());
+                };
+                for (name, n) in latency {
+                    let m = Mnemonic::ALL.into_iter().find(|m| m.name() == name)
+                        .ok_or(format!("unknown mnemonic \"{name}\" in \"latency\"."))?;
+                    let n = json_count(name, n)?;
+                    if n == 0 {
+                        return Err(format!("the latency of \"{name}\" must be at least 1."));
+                    }
+                    self.latency.insert(m, n);
+                }
+            }
+            _ => { return Ok(false); }
+        }
+        Ok(true)
+    }
+
+    /// `m`の結果を読む命令を、何命令後に置けば止まらずに済むか
+    pub fn distance(&self, m: Mnemonic) -> usize {
+        let latency = self.latency.get(&m).copied().unwrap_or(1);
+        if self.forwarding { latency } else { latency + self.register_file_delay }
+    }
+}
+
+/// 設定のJSONを読み、オブジェクトの項目を返す。`what`はエラーメッセージに使う
+pub(crate) fn json_object(src: &str, what: &str) -> Result<Vec<(String, Value)>, String> {
+    match json::parse(src) {
+        Ok(Value::Object(members)) => Ok(members),
+        Ok(_) => Err(format!("the {what} must be a JSON object.")),
+        Err(e) => Err(format!("invalid JSON at byte {}.", e.position)),
+    }
+}
+
+/// 0以上の整数の項目
+pub(crate) fn json_count(key: &str, v: &Value) -> Result<usize, String> {
+    match v {
+        Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Ok(*n as usize),
+        _ => Err(format!("\"{key}\" must be a non-negative integer.")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum HazardKind {
+    /// 直前の命令の結果を、まだ使えないうちに読んでいる
+    DataHazard,
+    /// 分岐の遅延スロットにnop以外の命令がある
+    DelaySlot,
+}
+
+impl ErrorCode for HazardKind {
+    fn code(&self) -> &'static str {
+        match self {
+            HazardKind::DataHazard => "W0101",
+            HazardKind::DelaySlot => "W0102",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            HazardKind::DataHazard => "DataHazard",
+            HazardKind::DelaySlot => "DelaySlot",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Hazard {
+    pub kind: HazardKind,
+    /// データハザードなら結果を読む命令、遅延スロットなら分岐命令のアドレス
+    pub address: usize,
+    /// データハザードの原因の命令のアドレス
+    pub producer: Option<usize>,
+    pub register: Option<Register>,
+    /// 解消するのに必要なnopの数
+    pub stall: usize,
+}
+
+fn is_nop(instr: &Instruction) -> bool {
+    let zero = Operand::OpRegister(Register::Zero);
+    instr.mnemonic == Add && instr.operands.iter().all(|o| *o == zero)
+}
+
+fn is_control_transfer(m: Mnemonic) -> bool {
+    is_conditional_branch(m) || is_conditional_branch_ext(m) || m == J || m == Jr || m == Call
+}
+
+/// 機械語の命令が読むレジスタ。movhは書き込み先の下位16bitを残すので、それも読む
+pub(crate) fn machine_reads(instr: &Instruction) -> Vec<Register> {
+    let mut regs = read_registers(instr);
+    if instr.mnemonic == Movh {
+        if let Some(Operand::OpRegister(r)) = instr.operands.first() {
+            regs.push(*r);
+        }
+    }
+    // 同じレジスタを2回読んでも、ハザードは1つ
+    let mut unique = vec![];
+    for r in regs {
+        if r != Register::Zero && !unique.contains(&r) {
+            unique.push(r);
+        }
+    }
+    unique
+}
+
+/// アドレス解決済みの命令列のハザードを、プログラムの並び順に沿って探す
+/// 分岐して飛んだ先での依存関係は調べない
+pub fn find_hazards(program: &[Instruction], model: &HazardModel) -> Vec<Hazard> {
+    let mut hazards = vec![];
+    for (j, instr) in program.iter().enumerate() {
+        for r in machine_reads(instr) {
+            // 直前に書き込んだ命令を探す。jとjrの先には戻らない
+            for i in (0..j).rev() {
+                let producer = &program[i];
+                if producer.mnemonic == J || producer.mnemonic == Jr {
+                    break;
+                }
+                if !written_registers(producer).contains(&r) {
+                    continue;
+                }
+                let needed = model.distance(producer.mnemonic);
+                if j - i < needed {
+                    hazards.push(Hazard {
+                        kind: HazardKind::DataHazard,
+                        address: j,
+                        producer: Some(i),
+                        register: Some(r),
+                        stall: needed - (j - i),
+                    });
+                }
+                break;
+            }
+        }
+
+        if model.branch_delay_slots > 0 && is_control_transfer(instr.mnemonic) {
+            let filled = program[j + 1..].iter().take(model.branch_delay_slots).take_while(|i| is_nop(i)).count();
+            if filled < model.branch_delay_slots {
+                hazards.push(Hazard {
+                    kind: HazardKind::DelaySlot,
+                    address: j,
+                    producer: None,
+                    register: None,
+                    stall: model.branch_delay_slots - filled,
+                });
+            }
+        }
+    }
+    hazards
+}
+
+/// ハザードを警告として報告する
+pub fn report_hazards(program: &[Instruction], hazards: &[Hazard]) {
+    for h in hazards {
+        let Instruction { mnemonic, line, ch, .. } = &program[h.address];
+        match h.kind {
+            HazardKind::DataHazard => {
+                let producer = &program[h.producer.unwrap()];
+                let r = h.register.unwrap();
+                let message = format!(
+                    "{mnemonic} reads {r} too soon after {} writes it; the core stalls for {} cycle(s).",
+                    producer.mnemonic, h.stall,
+                );
+                warn(*line, *ch, &h.kind, message);
+            }
+            HazardKind::DelaySlot => {
+                let message = format!("{} instruction(s) after {mnemonic} are in its delay slot and run even when it jumps.", h.stall);
+                warn(*line, *ch, &h.kind, message);
+            }
+        }
+    }
+}
+
+/// nopを挿入し直す回数の上限。挿入しても解消しないハザードが残っていれば諦める
+const MAX_ROUNDS: usize = 64;
+
+/// ハザードが無くなるまで、ソースの命令列にnopを挿入してアドレス解決をやり直す
+/// 挿入したnopの数と、疑似命令の展開の内側にあるなどの理由で解消できなかったハザードも返す
+pub fn insert_nops(
+    mut instructions: Vec<Instruction>, model: &HazardModel,
+) -> Result<(Vec<Instruction>, usize, Vec<Hazard>), ResolutionError> {
+    let mut inserted = 0;
+    for _ in 0..MAX_ROUNDS {
+        let program = resolve_without_optimization(instructions.clone())?;
+        let hazards = find_hazards(&program, model);
+
+        // 解決後の命令は元の命令と同じ位置 (line, ch) を持つ。挿入したnopは位置を持たない
+        let mut index = HashMap::new();
+        for (k, instr) in instructions.iter().enumerate() {
+            index.insert((instr.line, instr.ch), k);
+        }
+        // 命令ごとに、その前に入れるnopの数
+        let mut before: HashMap<usize, usize> = HashMap::new();
+        let mut unfixed = vec![];
+        for h in hazards {
+            let instr = &program[h.address];
+            let Some(&k) = index.get(&(instr.line, instr.ch)) else {
+                unfixed.push(h);
+                continue;
+            };
+            // 展開の先頭かどうか
+            let first = h.address == 0 || {
+                let prev = &program[h.address - 1];
+                (prev.line, prev.ch) != (instr.line, instr.ch)
+            };
+            let last = program.get(h.address + 1).is_none_or(|next| (next.line, next.ch) != (instr.line, instr.ch));
+            let k = match h.kind {
+                HazardKind::DataHazard if first => k,
+                HazardKind::DelaySlot if last => k + 1,
+                _ => {
+                    unfixed.push(h);
+                    continue;
+                }
+            };
+            let n = before.entry(k).or_insert(0);
+            *n = (*n).max(h.stall);
+        }
+
+        if before.is_empty() {
+            return Ok((program, inserted, unfixed));
+        }
+
+        // 後ろから挿入すれば、前の添字はずれない。ラベルは元の命令に残し、飛んできた場合はnopを通らない
+        let mut positions: Vec<_> = before.into_iter().collect();
+        positions.sort_unstable_by_key(|p| std::cmp::Reverse(p.0));
+        for (k, n) in positions {
+            for _ in 0..n {
+                instructions.insert(k, Instruction { label: vec![], mnemonic: Nop, operands: vec![], line: 0, ch: 0 });
+            }
+            inserted += n;
+        }
+    }
+
+    let program = resolve_without_optimization(instructions)?;
+    let hazards = find_hazards(&program, model);
+    Ok((program, inserted, hazards))
+}
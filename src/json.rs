@@ -0,0 +1,286 @@
+//! 言語サーバなどで使う最小限のJSON。依存クレートを増やしたくないので自前で書いている
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    /// キーの順番を保つためにVecで持つ
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// オブジェクトのキーを引く。オブジェクトでなかったりキーが無かったりすればNull
+    pub fn get(&self, key: &str) -> &Value {
+        match self {
+            Value::Object(members) => members.iter()
+                .find(|(k, _)| k == key)
+                .map(|(_, v)| v)
+                .unwrap_or(&Value::Null),
+            _ => &Value::Null,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        if let Value::String(s) = self { Some(s) } else { None }
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        if let Value::Number(n) = self { Some(*n as i64) } else { None }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        if let Value::Bool(b) = self { Some(*b) } else { None }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        if let Value::Array(a) = self { Some(a) } else { None }
+    }
+}
+
+/// `obj(&[("a", 1.into())])`のようにオブジェクトを組み立てる
+pub fn obj(members: &[(&str, Value)]) -> Value {
+    Value::Object(members.iter().map(|(k, v)| (k.to_string(), v.clone())).collect())
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self { Value::String(s.to_string()) }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self { Value::String(s) }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self { Value::Bool(b) }
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self { Value::Number(n as f64) }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Self { Value::Number(n as f64) }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(a: Vec<Value>) -> Self { Value::Array(a) }
+}
+
+/// 文字列をJSONの文字列リテラルにする
+pub fn escape(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Null => write!(f, "null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            // 整数はそのまま、それ以外は小数で書く
+            Value::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Value::Number(n) if n.is_finite() => write!(f, "{n}"),
+            Value::Number(_) => write!(f, "null"),
+            Value::String(s) => write!(f, "{}", escape(s)),
+            Value::Array(a) => {
+                write!(f, "[")?;
+                for (i, v) in a.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "{v}")?;
+                }
+                write!(f, "]")
+            }
+            Value::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (k, v)) in members.iter().enumerate() {
+                    if i > 0 { write!(f, ",")?; }
+                    write!(f, "{}:{v}", escape(k))?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    /// エラーになったバイト位置
+    pub position: usize,
+}
+
+pub fn parse(src: &str) -> Result<Value, ParseError> {
+    let mut p = JsonParser { src: src.as_bytes(), pos: 0 };
+    let value = p.value(0)?;
+    p.skip_space();
+    if p.pos != p.src.len() {
+        return Err(p.err());
+    }
+    Ok(value)
+}
+
+/// 悪意のある入力でスタックが溢れないように、入れ子の深さを制限する
+const MAX_DEPTH: usize = 128;
+
+struct JsonParser<'a> {
+    src: &'a [u8],
+    pos: usize,
+}
+
+impl JsonParser<'_> {
+    fn err(&self) -> ParseError {
+        ParseError { position: self.pos }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, s: &[u8]) -> Result<(), ParseError> {
+        if self.src[self.pos..].starts_with(s) {
+            self.pos += s.len();
+            Ok(())
+        } else {
+            Err(self.err())
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, ParseError> {
+        if depth > MAX_DEPTH {
+            return Err(self.err());
+        }
+        self.skip_space();
+        match self.peek() {
+            Some(b'n') => { self.expect(b"null")?; Ok(Value::Null) }
+            Some(b't') => { self.expect(b"true")?; Ok(Value::Bool(true)) }
+            Some(b'f') => { self.expect(b"false")?; Ok(Value::Bool(false)) }
+            Some(b'"') => Ok(Value::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut a = vec![];
+                self.skip_space();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Value::Array(a));
+                }
+                loop {
+                    a.push(self.value(depth + 1)?);
+                    self.skip_space();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b']') => { self.pos += 1; return Ok(Value::Array(a)); }
+                        _ => { return Err(self.err()); }
+                    }
+                }
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = vec![];
+                self.skip_space();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                loop {
+                    self.skip_space();
+                    if self.peek() != Some(b'"') {
+                        return Err(self.err());
+                    }
+                    let key = self.string()?;
+                    self.skip_space();
+                    self.expect(b":")?;
+                    members.push((key, self.value(depth + 1)?));
+                    self.skip_space();
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        Some(b'}') => { self.pos += 1; return Ok(Value::Object(members)); }
+                        _ => { return Err(self.err()); }
+                    }
+                }
+            }
+            Some(b'-' | b'0'..=b'9') => {
+                let start = self.pos;
+                while let Some(b'-' | b'+' | b'.' | b'e' | b'E' | b'0'..=b'9') = self.peek() {
+                    self.pos += 1;
+                }
+                let s = std::str::from_utf8(&self.src[start..self.pos]).unwrap();
+                s.parse().map(Value::Number).map_err(|_| ParseError { position: start })
+            }
+            _ => Err(self.err()),
+        }
+    }
+
+    fn hex4(&mut self) -> Result<u32, ParseError> {
+        let s = self.src.get(self.pos..self.pos + 4).ok_or(self.err())?;
+        let s = std::str::from_utf8(s).map_err(|_| self.err())?;
+        let n = u32::from_str_radix(s, 16).map_err(|_| self.err())?;
+        self.pos += 4;
+        Ok(n)
+    }
+
+    fn string(&mut self) -> Result<String, ParseError> {
+        self.pos += 1;
+        let mut buf = vec![];
+        loop {
+            match self.peek() {
+                None => { return Err(self.err()); }
+                Some(b'"') => { self.pos += 1; break; }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = self.peek().ok_or(self.err())?;
+                    self.pos += 1;
+                    let c = match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut n = self.hex4()?;
+                            // サロゲートペア
+                            if (0xd800..0xdc00).contains(&n) && self.src[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex4()?;
+                                n = 0x10000 + ((n - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+                            char::from_u32(n).unwrap_or('\u{fffd}')
+                        }
+                        _ => { return Err(self.err()); }
+                    };
+                    let mut tmp = [0; 4];
+                    buf.extend_from_slice(c.encode_utf8(&mut tmp).as_bytes());
+                }
+                Some(a) => {
+                    buf.push(a);
+                    self.pos += 1;
+                }
+            }
+        }
+        String::from_utf8(buf).map_err(|_| self.err())
+    }
+}
@@ -1,12 +1,19 @@
+use std::fmt;
+use std::io;
 use std::io::{BufReader, Bytes, Read};
 use std::iter::Peekable;
+use crate::diagnostics::{report_span, ErrorCode};
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum LexToken {
     LexMnemonic(Mnemonic),
     LexRegister(Register),
     LexDigit(i64),
-    LexLabel(Vec<u8>),
+    /// 浮動小数点数リテラル。IEEE-754単精度のビット列で持つ
+    LexFloat(u32),
+    /// 文字列リテラル。エスケープシーケンスは展開済み
+    LexString(Vec<u8>),
+    LexLabel(String),
     LexColon,
     LexComma,
     LexNewline,
@@ -14,7 +21,7 @@ pub enum LexToken {
     LexSemicolon,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Mnemonic {
     Add,
     Sub,
@@ -41,7 +48,7 @@ pub enum Mnemonic {
     J,
     Jr,
     Call,
-    // Ret,
+    Ret,
     Movl,
     Movh,
     Urecv,
@@ -58,9 +65,30 @@ pub enum Mnemonic {
     Lfble,
     Lfbps,
     Lfbng,
+    Lif,
+    /// .ascii "..." : 文字列を1文字1ワードで配置する
+    Ascii,
+    /// .word n : 32bitの値をそのまま配置する
+    Word,
+    Push,
+    Pop,
+    Enter,
+    Leave,
+    Nop,
+    Mov,
+    Fmov,
+    Neg,
+    Not,
+    B,
+    Ibgt,
+    Ibge,
+    Fbgt,
+    Fbge,
+    Lj,
+    Lcall,
 }
 
-#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
 pub enum Register {
     Zero,
     Sp,
@@ -68,6 +96,106 @@ pub enum Register {
     R(u8),
 }
 
+impl Mnemonic {
+    /// 全てのニーモニック (疑似命令とディレクティブを含む)
+    pub const ALL: [Mnemonic; 59] = [
+        Self::Add, Self::Sub, Self::Addi, Self::Subi, Self::Slli, Self::Fabs, Self::Fneg,
+        Self::Fadd, Self::Fsub, Self::Fmul, Self::Fdiv, Self::Fsqrt, Self::Itof, Self::Ftoi,
+        Self::Ibeq, Self::Ibne, Self::Ible, Self::Iblt, Self::Fble, Self::Fblt, Self::Fbps,
+        Self::Fbng, Self::J, Self::Jr, Self::Call, Self::Ret, Self::Movl, Self::Movh,
+        Self::Urecv, Self::Usend, Self::Lw, Self::Sw, Self::Libeq, Self::Libne, Self::Lible,
+        Self::Liblt, Self::Lfblt, Self::Lfble, Self::Lfbps, Self::Lfbng, Self::Lif, Self::Ascii,
+        Self::Word, Self::Push, Self::Pop, Self::Enter, Self::Leave, Self::Nop, Self::Mov,
+        Self::Fmov, Self::Neg, Self::Not, Self::B, Self::Ibgt, Self::Ibge, Self::Fbgt, Self::Fbge,
+        Self::Lj, Self::Lcall,
+    ];
+
+    pub fn name(self) -> &'static str {
+        use Mnemonic::*;
+        match self {
+            Add => "add",
+            Sub => "sub",
+            Addi => "addi",
+            Subi => "subi",
+            Slli => "slli",
+            Fabs => "fabs",
+            Fneg => "fneg",
+            Fadd => "fadd",
+            Fsub => "fsub",
+            Fmul => "fmul",
+            Fdiv => "fdiv",
+            Fsqrt => "fsqrt",
+            Itof => "itof",
+            Ftoi => "ftoi",
+            Ibeq => "ibeq",
+            Ibne => "ibne",
+            Ible => "ible",
+            Iblt => "iblt",
+            Fble => "fble",
+            Fblt => "fblt",
+            Fbps => "fbps",
+            Fbng => "fbng",
+            J => "j",
+            Jr => "jr",
+            Call => "call",
+            Ret => "ret",
+            Movl => "movl",
+            Movh => "movh",
+            Urecv => "urecv",
+            Usend => "usend",
+            Lw => "lw",
+            Sw => "sw",
+            Libeq => "libeq",
+            Libne => "libne",
+            Lible => "lible",
+            Liblt => "liblt",
+            Lfblt => "lfblt",
+            Lfble => "lfble",
+            Lfbps => "lfbps",
+            Lfbng => "lfbng",
+            Lif => "lif",
+            Ascii => ".ascii",
+            Word => ".word",
+            Push => "push",
+            Pop => "pop",
+            Enter => "enter",
+            Leave => "leave",
+            Nop => "nop",
+            Mov => "mov",
+            Fmov => "fmov",
+            Neg => "neg",
+            Not => "not",
+            B => "b",
+            Ibgt => "ibgt",
+            Ibge => "ibge",
+            Fbgt => "fbgt",
+            Fbge => "fbge",
+            Lj => "lj",
+            Lcall => "lcall",
+        }
+    }
+}
+
+impl fmt::Display for Mnemonic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::Zero => write!(f, "zero"),
+            Register::Sp => write!(f, "sp"),
+            Register::Fp => write!(f, "fp"),
+            Register::R(n) => write!(f, "r{n}"),
+        }
+    }
+}
+
+/// Lexerが返す要素。トークンと、その開始位置の行番号・文字番号の組
+pub type LexItem = Result<(LexToken, usize, usize), SyntaxError>;
+
 pub struct Lexer<T: Read> {
     br: Peekable<Bytes<BufReader<T>>>,
     pub line: usize,
@@ -78,6 +206,13 @@ pub struct Lexer<T: Read> {
 pub enum SyntaxError {
     UnknownCharacterError,
     MalformedTokenError,
+    InvalidCharacterError(char),
+    InvalidUtf8Error,
+    UnterminatedLiteralError,
+    InvalidEscapeError(char),
+    InvalidCharLiteralError,
+    UnknownDirectiveError(String),
+    IoError(io::Error),
 }
 
 impl<T: Read> Lexer<T> {
@@ -91,126 +226,127 @@ impl<T: Read> Lexer<T> {
         ch == b' ' || ch == b'\t'
     }
 
-    fn get_digit(&mut self) -> Result<i64, SyntaxError> {
-        let a = self.br.peek();
-        if a.is_none() { return Err(SyntaxError::UnknownCharacterError); }
-        let a = *a.unwrap().as_ref().unwrap();
-
-        let is_minus = a == b'-';
-        if is_minus {
-            self.br.next();
-            self.character += 1;
-            loop {
-                let a = self.br.peek();
-                if a.is_none() { return Err(SyntaxError::MalformedTokenError); }
-                let a = *a.unwrap().as_ref().unwrap();
-                if Self::is_space(a) {
-                    self.br.next();
-                    self.character += 1;
-                } else {
-                    if a.is_ascii_digit() {
-                        break;
-                    } else {
-                        return Err(SyntaxError::MalformedTokenError);
-                    }
-                }
+    /// 次の1バイトを覗く。読み込みエラーはここでSyntaxError::IoErrorに変換する
+    fn peek(&mut self) -> Result<Option<u8>, SyntaxError> {
+        match self.br.peek() {
+            None => Ok(None),
+            Some(Ok(a)) => Ok(Some(*a)),
+            Some(Err(_)) => {
+                let e = self.br.next().unwrap().unwrap_err();
+                Err(SyntaxError::IoError(e))
             }
         }
+    }
 
-        let a = *self.br.peek().unwrap().as_ref().unwrap();
-        if !a.is_ascii_digit() { return Err(SyntaxError::UnknownCharacterError); }
+    fn consume(&mut self) {
         self.br.next();
         self.character += 1;
+    }
 
-        let mut res = (a - b'0') as i64;
-
-        let a = self.br.peek();
-        if a.is_none() { return Ok(if is_minus { -res } else { res }); }
-        let a = *a.unwrap().as_ref().unwrap();
-
-        if a == b'x' {
-            if res != 0 { return Err(SyntaxError::MalformedTokenError); }
+    /// 条件を満たすバイトが続く限り読み進め、bufに追加する
+    fn take_while(&mut self, buf: &mut Vec<u8>, f: impl Fn(u8) -> bool) -> Result<(), SyntaxError> {
+        while let Some(a) = self.peek()? {
+            if !f(a) { break; }
+            buf.push(a);
+            self.consume();
+        }
+        Ok(())
+    }
 
-            self.br.next();
-            self.character += 1;
-            let a = self.br.peek();
-            if a.is_none() { return Err(SyntaxError::MalformedTokenError); }
-            let mut a = *a.unwrap().as_ref().unwrap();
-            if !a.is_ascii_hexdigit() { return Err(SyntaxError::MalformedTokenError); }
+    fn get_digit(&mut self) -> Result<LexToken, SyntaxError> {
+        let a = match self.peek()? {
+            Some(a) => a,
+            None => { return Err(SyntaxError::UnknownCharacterError); }
+        };
 
+        let is_minus = a == b'-';
+        if is_minus {
+            self.consume();
             loop {
-                res *= 16;
-                res += if a.is_ascii_digit() {
-                    a - b'0'
-                } else if a.is_ascii_uppercase() {
-                    a - b'A' + 10
+                let a = match self.peek()? {
+                    Some(a) => a,
+                    None => { return Err(SyntaxError::MalformedTokenError); }
+                };
+                if Self::is_space(a) {
+                    self.consume();
+                } else if a.is_ascii_digit() {
+                    break;
                 } else {
-                    a - b'a' + 10
-                } as i64;
-
-                self.br.next();
-                self.character += 1;
-
-                let next_a = self.br.peek();
-                if next_a.is_none() { return Ok(if is_minus { -res } else { res }); }
-                a = *next_a.unwrap().as_ref().unwrap();
-                if !a.is_ascii_hexdigit() { return Ok(if is_minus { -res } else { res }); }
+                    return Err(SyntaxError::MalformedTokenError);
+                }
             }
-        } else if a == b'b' {
-            if res != 0 { return Err(SyntaxError::MalformedTokenError); }
-
-            self.br.next();
-            self.character += 1;
-            let a = self.br.peek();
-            if a.is_none() { return Err(SyntaxError::MalformedTokenError); }
-            let mut a = *a.unwrap().as_ref().unwrap();
-            if !(a == b'0' || a == b'1') { return Err(SyntaxError::MalformedTokenError); }
-
-            loop {
-                res *= 2;
-                res += (a - b'0') as i64;
+        }
 
-                self.br.next();
-                self.character += 1;
+        let first = match self.peek()? {
+            Some(a) if a.is_ascii_digit() => a,
+            _ => { return Err(SyntaxError::UnknownCharacterError); }
+        };
+        self.consume();
 
-                let next_a = self.br.peek();
-                if next_a.is_none() { return Ok(if is_minus { -res } else { res }); }
-                a = *next_a.unwrap().as_ref().unwrap();
-                if !(a == b'0' || a == b'1') { return Ok(if is_minus { -res } else { res }); }
+        let mut buf = vec![];
+        let kind = match self.peek()? {
+            Some(b'x') | Some(b'b') | Some(b'f') if first != b'0' => {
+                return Err(SyntaxError::MalformedTokenError);
             }
-        } else {
-            if !a.is_ascii_digit() { return Ok(if is_minus { -res } else { res }); }
-
-            let mut a = a;
-            loop {
-                res *= 10;
-                res += (a - b'0') as i64;
-
-                self.br.next();
-                self.character += 1;
-
-                let next_a = self.br.peek();
-                if next_a.is_none() { return Ok(if is_minus { -res } else { res }); }
-                a = *next_a.unwrap().as_ref().unwrap();
-                if !a.is_ascii_digit() { return Ok(if is_minus { -res } else { res }); }
+            Some(b'x') => {
+                self.consume();
+                self.take_while(&mut buf, |a| a.is_ascii_hexdigit())?;
+                NumberKind::Hex
             }
-        }
+            Some(b'b') => {
+                self.consume();
+                self.take_while(&mut buf, |a| a == b'0' || a == b'1')?;
+                NumberKind::Binary
+            }
+            Some(b'f') => {
+                self.consume();
+                self.take_while(&mut buf, |a| a.is_ascii_hexdigit())?;
+                NumberKind::FloatBits
+            }
+            _ => {
+                buf.push(first);
+                self.take_while(&mut buf, |a| a.is_ascii_digit())?;
+
+                let mut kind = NumberKind::Decimal;
+                if self.peek()? == Some(b'.') {
+                    buf.push(b'.');
+                    self.consume();
+                    let len = buf.len();
+                    self.take_while(&mut buf, |a| a.is_ascii_digit())?;
+                    if buf.len() == len { return Err(SyntaxError::MalformedTokenError); }
+                    kind = NumberKind::Float;
+                }
+                if let Some(a @ (b'e' | b'E')) = self.peek()? {
+                    buf.push(a);
+                    self.consume();
+                    if let Some(a @ (b'+' | b'-')) = self.peek()? {
+                        buf.push(a);
+                        self.consume();
+                    }
+                    let len = buf.len();
+                    self.take_while(&mut buf, |a| a.is_ascii_digit())?;
+                    if buf.len() == len { return Err(SyntaxError::MalformedTokenError); }
+                    kind = NumberKind::Float;
+                }
+                kind
+            }
+        };
+
+        number_token(kind, &buf, is_minus)
     }
 
-    fn get_identifier(&mut self) -> Result<Vec<u8>, SyntaxError> {
-        let a = self.br.peek();
-        if a.is_none() { return Err(SyntaxError::UnknownCharacterError); }
-        let a = *a.unwrap().as_ref().unwrap();
-        if !(a.is_ascii_alphabetic() || a == b'_') { return Err(SyntaxError::UnknownCharacterError); }
+    fn get_identifier(&mut self) -> Result<String, SyntaxError> {
+        let a = match self.peek()? {
+            // '.'で始まるものはディレクティブ
+            Some(a) if a.is_ascii_alphabetic() || a == b'_' || a == b'.' => a,
+            _ => { return Err(SyntaxError::UnknownCharacterError); }
+        };
         self.br.next();
 
-        let mut buf = vec![a];
-        loop {
-            let a = self.br.peek();
-            if a.is_none() { break; }
-            let a = *a.unwrap().as_ref().unwrap();
+        let mut buf = String::from(a as char);
+        while let Some(a) = self.peek()? {
             if a.is_ascii_alphanumeric() || a == b'_' {
-                buf.push(a);
+                buf.push(a as char);
                 self.br.next();
             } else {
                 break;
@@ -221,10 +357,50 @@ impl<T: Read> Lexer<T> {
         Ok(buf)
     }
 
+    /// 'A'や"..."を読む。中身はエスケープシーケンスを展開せずにそのまま集め、quoted_tokenに渡す
+    fn get_quoted(&mut self) -> Result<LexToken, SyntaxError> {
+        let quote = match self.peek()? {
+            Some(a @ (b'\'' | b'"')) => a,
+            _ => { return Err(SyntaxError::UnknownCharacterError); }
+        };
+        self.consume();
+
+        let mut raw = vec![];
+        loop {
+            match self.peek()? {
+                None | Some(b'\n') => { return Err(SyntaxError::UnterminatedLiteralError); }
+                Some(a) if a == quote => {
+                    self.consume();
+                    break;
+                }
+                Some(b'\\') => {
+                    raw.push(b'\\');
+                    self.consume();
+                    match self.peek()? {
+                        None | Some(b'\n') => { return Err(SyntaxError::UnterminatedLiteralError); }
+                        Some(a) => {
+                            raw.push(a);
+                            self.consume();
+                        }
+                    }
+                }
+                Some(a) => {
+                    raw.push(a);
+                    self.br.next();
+                    // UTF-8の継続バイトは文字数に数えない
+                    if a & 0xc0 != 0x80 { self.character += 1; }
+                }
+            }
+        }
+
+        quoted_token(quote, &raw)
+    }
+
     fn get_control(&mut self) -> Result<LexToken, SyntaxError> {
-        let a = self.br.peek();
-        if a.is_none() { return Ok(LexToken::LexEof); }
-        let a = *a.unwrap().as_ref().unwrap();
+        let a = match self.peek()? {
+            Some(a) => a,
+            None => { return Ok(LexToken::LexEof); }
+        };
 
         let res = match a {
             b':' => LexToken::LexColon,
@@ -244,118 +420,366 @@ impl<T: Read> Lexer<T> {
         Ok(res)
     }
 
-    fn skip_space(&mut self) {
-        loop {
-            let a = self.br.peek();
-            if a.is_none() { return; }
-            let a = *a.unwrap().as_ref().unwrap();
+    /// ASCII以外の文字を1文字分読み、UTF-8としてデコードする
+    fn get_non_ascii_char(&mut self) -> Result<char, SyntaxError> {
+        let a = match self.peek()? {
+            Some(a) => a,
+            None => { return Err(SyntaxError::UnknownCharacterError); }
+        };
+        let len = match a.leading_ones() {
+            2 => 2,
+            3 => 3,
+            4 => 4,
+            _ => {
+                self.consume();
+                return Err(SyntaxError::InvalidUtf8Error);
+            }
+        };
 
-            if a == b'#' {
-                loop {
+        let mut buf = vec![a];
+        self.br.next();
+        while buf.len() < len {
+            match self.peek()? {
+                Some(a) if a & 0xc0 == 0x80 => {
+                    buf.push(a);
                     self.br.next();
-                    self.character += 1;
-                    let a = self.br.peek();
-                    if a.is_none() { return; }
-                    let a = *a.unwrap().as_ref().unwrap();
-                    if a == b'\n' { return; }
                 }
+                _ => { break; }
             }
+        }
+        self.character += 1;
 
-            if !Self::is_space(a) { return; }
+        match std::str::from_utf8(&buf) {
+            Ok(s) => Ok(s.chars().next().unwrap()),
+            Err(_) => Err(SyntaxError::InvalidUtf8Error),
+        }
+    }
 
-            self.br.next();
-            self.character += 1;
+    /// 空白とコメントを読み飛ばす
+    /// コメント中はUTF-8として正しいかどうかも含めて中身を見ないので、全角文字を書いても良い
+    fn skip_space(&mut self) -> Result<(), SyntaxError> {
+        while let Some(a) = self.peek()? {
+            if a == b'#' {
+                while let Some(a) = self.peek()? {
+                    if a == b'\n' { return Ok(()); }
+                    self.consume();
+                }
+                return Ok(());
+            }
+
+            if !Self::is_space(a) { return Ok(()); }
+
+            self.consume();
         }
+
+        Ok(())
     }
-}
 
-impl<T: Read> Iterator for Lexer<T> {
-    type Item = (LexToken, usize, usize);
+    fn next_token(&mut self) -> Result<LexToken, SyntaxError> {
+        let a = match self.peek()? {
+            Some(a) => a,
+            None => { return Ok(LexToken::LexEof); }
+        };
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.skip_space();
-        let (line, ch) = (self.line, self.character);
+        if !a.is_ascii() {
+            let c = self.get_non_ascii_char()?;
+            return Err(SyntaxError::InvalidCharacterError(c));
+        }
 
-        let token = self.get_control();
-        if token.is_ok() { return Some((token.unwrap(), line, ch)); }
+        match self.get_control() {
+            Err(SyntaxError::UnknownCharacterError) => {}
+            token => { return token; }
+        }
 
-        let token = self.get_digit();
-        if token.is_ok() { return Some((LexToken::LexDigit(token.unwrap()), line, ch)); }
+        if a == b'\'' || a == b'"' {
+            return self.get_quoted();
+        }
 
-        if let Err(SyntaxError::MalformedTokenError) = token {
-            println!("at line {}, character {}: Syntax Error", self.line, self.character);
-            println!("malformed number.");
-            return None;
+        match self.get_digit() {
+            Err(SyntaxError::UnknownCharacterError) => {}
+            token => { return token; }
         }
 
-        let token = self.get_identifier();
-        if token.is_err() {
-            println!("at line {}, character {}: Syntax Error", self.line, self.character);
-            println!("invalid character.");
-            return None;
+        let token = match self.get_identifier() {
+            Err(SyntaxError::UnknownCharacterError) => {
+                self.consume();
+                return Err(SyntaxError::InvalidCharacterError(a as char));
+            }
+            token => token?,
+        };
+
+        classify_identifier(token)
+    }
+}
+
+fn classify_identifier(token: String) -> Result<LexToken, SyntaxError> {
+    match lookup_keyword(token.as_bytes()) {
+        Some(keyword) => Ok(keyword),
+        None if token.starts_with('.') => Err(SyntaxError::UnknownDirectiveError(token)),
+        None => Ok(LexToken::LexLabel(token)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum NumberKind {
+    /// 0x...
+    Hex,
+    /// 0b...
+    Binary,
+    /// 0f... (浮動小数点数のビット列を16進で直接書いたもの)
+    FloatBits,
+    Decimal,
+    /// 3.14, 1e-3など
+    Float,
+}
+
+/// 数値リテラルの本体(接頭辞と符号を除いた部分)からトークンを作る
+/// 範囲外の値は、黙って丸めずにMalformedTokenErrorにする
+pub(crate) fn number_token(kind: NumberKind, digits: &[u8], is_minus: bool) -> Result<LexToken, SyntaxError> {
+    // 数字と'.', 'e', 符号しか含まないのでUTF-8として正しい
+    let text = std::str::from_utf8(digits).unwrap();
+    if text.is_empty() { return Err(SyntaxError::MalformedTokenError); }
+
+    let radix = match kind {
+        NumberKind::Hex => 16,
+        NumberKind::Binary => 2,
+        NumberKind::Decimal => 10,
+        NumberKind::FloatBits => {
+            if digits.len() > 8 { return Err(SyntaxError::MalformedTokenError); }
+            let bits = u32::from_str_radix(text, 16).unwrap();
+            return Ok(LexToken::LexFloat(if is_minus { bits ^ 0x80000000 } else { bits }));
+        }
+        NumberKind::Float => {
+            let x = match text.parse::<f32>() {
+                Ok(x) if x.is_finite() => x,
+                _ => { return Err(SyntaxError::MalformedTokenError); }
+            };
+            return Ok(LexToken::LexFloat(if is_minus { -x } else { x }.to_bits()));
         }
+    };
 
-        let token = token.unwrap();
-        if token.eq_ignore_ascii_case(b"zero") { return Some((LexToken::LexRegister(Register::Zero), line, ch)); }
-        if token.eq_ignore_ascii_case(b"sp") { return Some((LexToken::LexRegister(Register::Sp), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fp") { return Some((LexToken::LexRegister(Register::Fp), line, ch)); }
-        if token[0] == b'r' {
-            let mut n = 0;
-            let mut is_label = false;
-            for i in 1..token.len() {
-                if !token[i].is_ascii_digit() {
-                    is_label = true;
-                    break;
+    match i64::from_str_radix(text, radix) {
+        Ok(n) => Ok(LexToken::LexDigit(if is_minus { -n } else { n })),
+        Err(_) => Err(SyntaxError::MalformedTokenError),
+    }
+}
+
+/// 文字列・文字リテラルの中身のエスケープシーケンスを展開する
+/// 使えるのは \n, \t, \r, \0, \\, \', \", \xHH
+pub(crate) fn unescape(raw: &[u8]) -> Result<Vec<u8>, SyntaxError> {
+    let mut res = vec![];
+    let mut it = raw.iter().copied();
+    while let Some(a) = it.next() {
+        if a != b'\\' {
+            res.push(a);
+            continue;
+        }
+
+        let a = match it.next() {
+            Some(a) => a,
+            None => { return Err(SyntaxError::UnterminatedLiteralError); }
+        };
+        res.push(match a {
+            b'n' => b'\n',
+            b't' => b'\t',
+            b'r' => b'\r',
+            b'0' => 0,
+            b'\\' | b'\'' | b'"' => a,
+            b'x' => {
+                let hi = it.next().and_then(|a| (a as char).to_digit(16));
+                let lo = it.next().and_then(|a| (a as char).to_digit(16));
+                match (hi, lo) {
+                    (Some(hi), Some(lo)) => (hi * 16 + lo) as u8,
+                    _ => { return Err(SyntaxError::InvalidEscapeError('x')); }
                 }
-                n *= 10;
-                n += token[i] - b'0';
             }
-            if !is_label { return Some((LexToken::LexRegister(Register::R(n)), line, ch)); }
+            _ => { return Err(SyntaxError::InvalidEscapeError(a as char)); }
+        });
+    }
+
+    Ok(res)
+}
+
+/// クォートの中身からトークンを作る。文字リテラルはその文字のコードを値とする整数になる
+pub(crate) fn quoted_token(quote: u8, raw: &[u8]) -> Result<LexToken, SyntaxError> {
+    let bytes = unescape(raw)?;
+    if quote == b'"' {
+        return Ok(LexToken::LexString(bytes));
+    }
+
+    // '\xff'のような1バイトの値はそのまま使う
+    if bytes.len() == 1 {
+        return Ok(LexToken::LexDigit(bytes[0] as i64));
+    }
+
+    let s = std::str::from_utf8(&bytes).map_err(|_| SyntaxError::InvalidUtf8Error)?;
+    let mut chars = s.chars();
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(LexToken::LexDigit(c as i64)),
+        _ => Err(SyntaxError::InvalidCharLiteralError),
+    }
+}
+
+impl ErrorCode for SyntaxError {
+    fn code(&self) -> &'static str {
+        match self {
+            SyntaxError::UnknownCharacterError => "E0201",
+            SyntaxError::MalformedTokenError => "E0202",
+            SyntaxError::InvalidCharacterError(_) => "E0203",
+            SyntaxError::InvalidUtf8Error => "E0204",
+            SyntaxError::UnterminatedLiteralError => "E0205",
+            SyntaxError::InvalidEscapeError(_) => "E0206",
+            SyntaxError::InvalidCharLiteralError => "E0207",
+            SyntaxError::UnknownDirectiveError(_) => "E0208",
+            SyntaxError::IoError(_) => "E0209",
         }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SyntaxError::UnknownCharacterError => "UnknownCharacter",
+            SyntaxError::MalformedTokenError => "MalformedToken",
+            SyntaxError::InvalidCharacterError(_) => "InvalidCharacter",
+            SyntaxError::InvalidUtf8Error => "InvalidUtf8",
+            SyntaxError::UnterminatedLiteralError => "UnterminatedLiteral",
+            SyntaxError::InvalidEscapeError(_) => "InvalidEscape",
+            SyntaxError::InvalidCharLiteralError => "InvalidCharLiteral",
+            SyntaxError::UnknownDirectiveError(_) => "UnknownDirective",
+            SyntaxError::IoError(_) => "Io",
+        }
+    }
+}
 
-        if token.eq_ignore_ascii_case(b"add") { return Some((LexToken::LexMnemonic(Mnemonic::Add), line, ch)); }
-        if token.eq_ignore_ascii_case(b"sub") { return Some((LexToken::LexMnemonic(Mnemonic::Sub), line, ch)); }
-        if token.eq_ignore_ascii_case(b"addi") { return Some((LexToken::LexMnemonic(Mnemonic::Addi), line, ch)); }
-        if token.eq_ignore_ascii_case(b"subi") { return Some((LexToken::LexMnemonic(Mnemonic::Subi), line, ch)); }
-        if token.eq_ignore_ascii_case(b"slli") { return Some((LexToken::LexMnemonic(Mnemonic::Slli), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fabs") { return Some((LexToken::LexMnemonic(Mnemonic::Fabs), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fneg") { return Some((LexToken::LexMnemonic(Mnemonic::Fneg), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fadd") { return Some((LexToken::LexMnemonic(Mnemonic::Fadd), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fsub") { return Some((LexToken::LexMnemonic(Mnemonic::Fsub), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fmul") { return Some((LexToken::LexMnemonic(Mnemonic::Fmul), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fdiv") { return Some((LexToken::LexMnemonic(Mnemonic::Fdiv), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fsqrt") { return Some((LexToken::LexMnemonic(Mnemonic::Fsqrt), line, ch)); }
-        if token.eq_ignore_ascii_case(b"itof") { return Some((LexToken::LexMnemonic(Mnemonic::Itof), line, ch)); }
-        if token.eq_ignore_ascii_case(b"ftoi") { return Some((LexToken::LexMnemonic(Mnemonic::Ftoi), line, ch)); }
-        if token.eq_ignore_ascii_case(b"ibeq") { return Some((LexToken::LexMnemonic(Mnemonic::Ibeq), line, ch)); }
-        if token.eq_ignore_ascii_case(b"ibne") { return Some((LexToken::LexMnemonic(Mnemonic::Ibne), line, ch)); }
-        if token.eq_ignore_ascii_case(b"ible") { return Some((LexToken::LexMnemonic(Mnemonic::Ible), line, ch)); }
-        if token.eq_ignore_ascii_case(b"iblt") { return Some((LexToken::LexMnemonic(Mnemonic::Iblt), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fble") { return Some((LexToken::LexMnemonic(Mnemonic::Fble), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fblt") { return Some((LexToken::LexMnemonic(Mnemonic::Fblt), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fbps") { return Some((LexToken::LexMnemonic(Mnemonic::Fbps), line, ch)); }
-        if token.eq_ignore_ascii_case(b"fbng") { return Some((LexToken::LexMnemonic(Mnemonic::Fbng), line, ch)); }
-        if token.eq_ignore_ascii_case(b"j") { return Some((LexToken::LexMnemonic(Mnemonic::J), line, ch)); }
-        if token.eq_ignore_ascii_case(b"jr") { return Some((LexToken::LexMnemonic(Mnemonic::Jr), line, ch)); }
-        if token.eq_ignore_ascii_case(b"call") { return Some((LexToken::LexMnemonic(Mnemonic::Call), line, ch)); }
-        // if token.eq_ignore_ascii_case(b"ret") { return Some((LexToken::LexMnemonic(Mnemonic::Ret), line, ch)); }
-        if token.eq_ignore_ascii_case(b"movl") { return Some((LexToken::LexMnemonic(Mnemonic::Movl), line, ch)); }
-        if token.eq_ignore_ascii_case(b"movh") { return Some((LexToken::LexMnemonic(Mnemonic::Movh), line, ch)); }
-        if token.eq_ignore_ascii_case(b"urecv") { return Some((LexToken::LexMnemonic(Mnemonic::Urecv), line, ch)); }
-        if token.eq_ignore_ascii_case(b"usend") { return Some((LexToken::LexMnemonic(Mnemonic::Usend), line, ch)); }
-        if token.eq_ignore_ascii_case(b"lw") { return Some((LexToken::LexMnemonic(Mnemonic::Lw), line, ch)); }
-        if token.eq_ignore_ascii_case(b"sw") { return Some((LexToken::LexMnemonic(Mnemonic::Sw), line, ch)); }
-        // if token.eq_ignore_ascii_case(b"lwn") { return Some((LexToken::LexMnemonic(Mnemonic::Lwn), line, ch)); }
-        // if token.eq_ignore_ascii_case(b"swn") { return Some((LexToken::LexMnemonic(Mnemonic::Swn), line, ch)); }
-        if token.eq_ignore_ascii_case(b"libeq") { return Some((LexToken::LexMnemonic(Mnemonic::Libeq), line, ch)); }
-        if token.eq_ignore_ascii_case(b"libne") { return Some((LexToken::LexMnemonic(Mnemonic::Libne), line, ch)); }
-        if token.eq_ignore_ascii_case(b"lible") { return Some((LexToken::LexMnemonic(Mnemonic::Lible), line, ch)); }
-        if token.eq_ignore_ascii_case(b"liblt") { return Some((LexToken::LexMnemonic(Mnemonic::Liblt), line, ch)); }
-        if token.eq_ignore_ascii_case(b"lfblt") { return Some((LexToken::LexMnemonic(Mnemonic::Lfblt), line, ch)); }
-        if token.eq_ignore_ascii_case(b"lfble") { return Some((LexToken::LexMnemonic(Mnemonic::Lfble), line, ch)); }
-        if token.eq_ignore_ascii_case(b"lfbps") { return Some((LexToken::LexMnemonic(Mnemonic::Lfbps), line, ch)); }
-        if token.eq_ignore_ascii_case(b"lfbng") { return Some((LexToken::LexMnemonic(Mnemonic::Lfbng), line, ch)); }
-
-        Some((LexToken::LexLabel(token), line, ch))
+/// `ch`から`end`の手前までにある字句のエラーを報告する
+pub(crate) fn print_syntax_error(line: usize, ch: usize, end: usize, e: &SyntaxError) {
+    let (title, message) = match e {
+        SyntaxError::IoError(e) => ("I/O Error", format!("could not read the source: {e}")),
+        SyntaxError::InvalidCharacterError(c) => {
+            let mut message = format!("invalid character U+{:04X} ('{c}').", *c as u32);
+            match c {
+                '\u{3000}' => message.push_str("\nfull-width space is not allowed here; use an ASCII space instead."),
+                '\u{ff0c}' => message.push_str("\nfull-width comma is not allowed here; use ',' instead."),
+                _ => {}
+            }
+            ("Syntax Error", message)
+        }
+        SyntaxError::InvalidUtf8Error => ("Syntax Error", "the source is not valid UTF-8.".to_string()),
+        SyntaxError::MalformedTokenError => ("Syntax Error", "malformed number.".to_string()),
+        SyntaxError::UnterminatedLiteralError => ("Syntax Error", "unterminated string or character literal.".to_string()),
+        SyntaxError::InvalidEscapeError(c) => ("Syntax Error", format!("invalid escape sequence '\\{c}'.")),
+        SyntaxError::InvalidCharLiteralError => ("Syntax Error", "a character literal must contain exactly one character.".to_string()),
+        SyntaxError::UnknownDirectiveError(name) => ("Syntax Error", format!("unknown directive \"{name}\".")),
+        SyntaxError::UnknownCharacterError => ("Syntax Error", "invalid character.".to_string()),
+    };
+    report_span(line, ch, end, title, e, message);
+}
+
+/// 識別子がレジスタ名かニーモニックであれば、対応するトークンを返す
+/// ニーモニックとzero, sp, fpは大文字小文字を区別しない
+pub fn lookup_keyword(ident: &[u8]) -> Option<LexToken> {
+    if let Some(digits) = ident.strip_prefix(b"r") {
+        if !digits.is_empty() && digits.iter().all(|a| a.is_ascii_digit()) {
+            // 数字しか含まないのでUTF-8として正しい
+            if let Ok(n) = std::str::from_utf8(digits).unwrap().parse::<u8>() {
+                return Some(LexToken::LexRegister(Register::R(n)));
+            }
+        }
+    }
+
+    // キーワードは高々8文字なので、小文字にしたものをスタック上に作ってmatchで引く
+    let mut buf = [0_u8; 8];
+    if ident.len() > buf.len() { return None; }
+    for (b, a) in buf.iter_mut().zip(ident) {
+        *b = a.to_ascii_lowercase();
+    }
+
+    let token = match &buf[..ident.len()] {
+        b"zero" => LexToken::LexRegister(Register::Zero),
+        b"sp" => LexToken::LexRegister(Register::Sp),
+        b"fp" => LexToken::LexRegister(Register::Fp),
+        b"add" => LexToken::LexMnemonic(Mnemonic::Add),
+        b"sub" => LexToken::LexMnemonic(Mnemonic::Sub),
+        b"addi" => LexToken::LexMnemonic(Mnemonic::Addi),
+        b"subi" => LexToken::LexMnemonic(Mnemonic::Subi),
+        b"slli" => LexToken::LexMnemonic(Mnemonic::Slli),
+        b"fabs" => LexToken::LexMnemonic(Mnemonic::Fabs),
+        b"fneg" => LexToken::LexMnemonic(Mnemonic::Fneg),
+        b"fadd" => LexToken::LexMnemonic(Mnemonic::Fadd),
+        b"fsub" => LexToken::LexMnemonic(Mnemonic::Fsub),
+        b"fmul" => LexToken::LexMnemonic(Mnemonic::Fmul),
+        b"fdiv" => LexToken::LexMnemonic(Mnemonic::Fdiv),
+        b"fsqrt" => LexToken::LexMnemonic(Mnemonic::Fsqrt),
+        b"itof" => LexToken::LexMnemonic(Mnemonic::Itof),
+        b"ftoi" => LexToken::LexMnemonic(Mnemonic::Ftoi),
+        b"ibeq" => LexToken::LexMnemonic(Mnemonic::Ibeq),
+        b"ibne" => LexToken::LexMnemonic(Mnemonic::Ibne),
+        b"ible" => LexToken::LexMnemonic(Mnemonic::Ible),
+        b"iblt" => LexToken::LexMnemonic(Mnemonic::Iblt),
+        b"fble" => LexToken::LexMnemonic(Mnemonic::Fble),
+        b"fblt" => LexToken::LexMnemonic(Mnemonic::Fblt),
+        b"fbps" => LexToken::LexMnemonic(Mnemonic::Fbps),
+        b"fbng" => LexToken::LexMnemonic(Mnemonic::Fbng),
+        b"j" => LexToken::LexMnemonic(Mnemonic::J),
+        b"jr" => LexToken::LexMnemonic(Mnemonic::Jr),
+        b"call" => LexToken::LexMnemonic(Mnemonic::Call),
+        b"ret" => LexToken::LexMnemonic(Mnemonic::Ret),
+        b"movl" => LexToken::LexMnemonic(Mnemonic::Movl),
+        b"movh" => LexToken::LexMnemonic(Mnemonic::Movh),
+        b"urecv" => LexToken::LexMnemonic(Mnemonic::Urecv),
+        b"usend" => LexToken::LexMnemonic(Mnemonic::Usend),
+        b"lw" => LexToken::LexMnemonic(Mnemonic::Lw),
+        b"sw" => LexToken::LexMnemonic(Mnemonic::Sw),
+        // b"lwn" => LexToken::LexMnemonic(Mnemonic::Lwn),
+        // b"swn" => LexToken::LexMnemonic(Mnemonic::Swn),
+        b"libeq" => LexToken::LexMnemonic(Mnemonic::Libeq),
+        b"libne" => LexToken::LexMnemonic(Mnemonic::Libne),
+        b"lible" => LexToken::LexMnemonic(Mnemonic::Lible),
+        b"liblt" => LexToken::LexMnemonic(Mnemonic::Liblt),
+        b"lfblt" => LexToken::LexMnemonic(Mnemonic::Lfblt),
+        b"lfble" => LexToken::LexMnemonic(Mnemonic::Lfble),
+        b"lfbps" => LexToken::LexMnemonic(Mnemonic::Lfbps),
+        b"lfbng" => LexToken::LexMnemonic(Mnemonic::Lfbng),
+        b"lif" => LexToken::LexMnemonic(Mnemonic::Lif),
+        b".ascii" => LexToken::LexMnemonic(Mnemonic::Ascii),
+        b".word" => LexToken::LexMnemonic(Mnemonic::Word),
+        b"push" => LexToken::LexMnemonic(Mnemonic::Push),
+        b"pop" => LexToken::LexMnemonic(Mnemonic::Pop),
+        b"enter" => LexToken::LexMnemonic(Mnemonic::Enter),
+        b"leave" => LexToken::LexMnemonic(Mnemonic::Leave),
+        b"nop" => LexToken::LexMnemonic(Mnemonic::Nop),
+        b"mov" => LexToken::LexMnemonic(Mnemonic::Mov),
+        b"fmov" => LexToken::LexMnemonic(Mnemonic::Fmov),
+        b"neg" => LexToken::LexMnemonic(Mnemonic::Neg),
+        b"not" => LexToken::LexMnemonic(Mnemonic::Not),
+        b"b" => LexToken::LexMnemonic(Mnemonic::B),
+        b"ibgt" => LexToken::LexMnemonic(Mnemonic::Ibgt),
+        b"ibge" => LexToken::LexMnemonic(Mnemonic::Ibge),
+        b"fbgt" => LexToken::LexMnemonic(Mnemonic::Fbgt),
+        b"fbge" => LexToken::LexMnemonic(Mnemonic::Fbge),
+        b"lj" => LexToken::LexMnemonic(Mnemonic::Lj),
+        b"lcall" => LexToken::LexMnemonic(Mnemonic::Lcall),
+        _ => { return None; }
+    };
+
+    Some(token)
+}
+
+impl<T: Read> Iterator for Lexer<T> {
+    type Item = LexItem;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Err(e) = self.skip_space() {
+            print_syntax_error(self.line, self.character, self.character, &e);
+            return Some(Err(e));
+        }
+
+        let (line, ch) = (self.line, self.character);
+        match self.next_token() {
+            Ok(token) => Some(Ok((token, line, ch))),
+            Err(e) => {
+                print_syntax_error(line, ch, self.character, &e);
+                Some(Err(e))
+            }
+        }
     }
 }
@@ -1,6 +1,22 @@
+pub mod diagnostics;
 pub mod lexer;
+pub mod slice_lexer;
 pub mod parser;
 pub mod semantics;
 pub mod resolver;
 // pub mod encoder_old;
-pub mod encoder;
\ No newline at end of file
+pub mod encoder;
+pub mod disassembler;
+pub mod cfg;
+pub mod dap;
+pub mod debugger;
+pub mod formatter;
+pub mod gdb;
+pub mod lint;
+pub mod hazard;
+pub mod peephole;
+pub mod simulator;
+pub mod timing;
+pub mod uart;
+pub mod json;
+pub mod lsp;
@@ -0,0 +1,148 @@
+//! 文法上は正しいが、バグの可能性が高いコードへの警告 (`--lint`で有効になる)
+//! semantic checkを通った命令列を対象にする。警告は種類ごとに無効にできる
+
+use std::collections::HashSet;
+use crate::diagnostics::{warn, ErrorCode};
+use crate::lexer::Mnemonic::*;
+use crate::lexer::Register;
+use crate::parser::{Instruction, LabelPositions, Operand};
+use crate::semantics::{is_conditional_branch, is_conditional_branch_ext, read_registers, written_registers};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Lint {
+    /// 定義したが参照していないラベル
+    UnusedLabel,
+    /// 無条件ジャンプの直後にあって、ラベルの付いていない命令
+    Unreachable,
+    /// どこでも書き込んでいないレジスタを読んでいる
+    UninitializedRegister,
+    /// プロローグ・エピローグの形以外でspやfpに書き込んでいる
+    StackPointerWrite,
+    /// 直後の命令への分岐
+    BranchToNext,
+    /// 32以上のslli
+    ShiftOverflow,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedLabel, Lint::Unreachable, Lint::UninitializedRegister,
+        Lint::StackPointerWrite, Lint::BranchToNext, Lint::ShiftOverflow,
+    ];
+
+    /// `-A`で指定するときの名前
+    pub fn flag_name(self) -> &'static str {
+        match self {
+            Lint::UnusedLabel => "unused-label",
+            Lint::Unreachable => "unreachable",
+            Lint::UninitializedRegister => "uninitialized-register",
+            Lint::StackPointerWrite => "stack-pointer-write",
+            Lint::BranchToNext => "branch-to-next",
+            Lint::ShiftOverflow => "shift-overflow",
+        }
+    }
+
+    pub fn from_flag_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|l| l.flag_name() == name)
+    }
+}
+
+impl ErrorCode for Lint {
+    fn code(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel => "W0001",
+            Lint::Unreachable => "W0002",
+            Lint::UninitializedRegister => "W0003",
+            Lint::StackPointerWrite => "W0004",
+            Lint::BranchToNext => "W0005",
+            Lint::ShiftOverflow => "W0006",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Lint::UnusedLabel => "UnusedLabel",
+            Lint::Unreachable => "Unreachable",
+            Lint::UninitializedRegister => "UninitializedRegister",
+            Lint::StackPointerWrite => "StackPointerWrite",
+            Lint::BranchToNext => "BranchToNext",
+            Lint::ShiftOverflow => "ShiftOverflow",
+        }
+    }
+}
+
+/// `allowed`以外の警告を報告し、その数を返す
+/// `labels`は`Parser::parse_with_label_positions`が返す、ラベルを定義した位置
+pub fn lint(instructions: &[Instruction], labels: &LabelPositions, allowed: &HashSet<Lint>) -> usize {
+    let mut count = 0;
+    let mut report = |lint: Lint, (line, ch): (usize, usize), message: String| {
+        if !allowed.contains(&lint) {
+            warn(line, ch, &lint, message);
+            count += 1;
+        }
+    };
+
+    let referenced: HashSet<&str> = instructions.iter()
+        .flat_map(|i| &i.operands)
+        .filter_map(|o| if let Operand::OpLabel(l) = o { Some(l.as_str()) } else { None })
+        .collect();
+    // spとfpは初期化されていて、zeroは常に0
+    let mut written: HashSet<Register> = instructions.iter().flat_map(written_registers).collect();
+    written.extend([Register::Zero, Register::Sp, Register::Fp]);
+
+    for (i, instr) in instructions.iter().enumerate() {
+        let Instruction { label, mnemonic: m, operands, .. } = instr;
+        let m = *m;
+        let next = instructions.get(i + 1);
+
+        // 先頭の命令のラベルやmainは、参照しなくても実行の入口になる
+        for l in label.iter().filter(|l| i > 0 && *l != "main") {
+            if !referenced.contains(l.as_str()) {
+                let position = labels.get(l).copied().unwrap_or((instr.line, instr.ch));
+                report(Lint::UnusedLabel, position, format!("label \"{l}\" is defined but never referenced."));
+            }
+        }
+
+        // ラベルが無ければ、どこからも飛んでこない。データは実行しないので除く
+        if m == J || m == B || m == Jr || m == Ret || m == Lj {
+            if let Some(next) = next.filter(|n| n.label.is_empty() && n.mnemonic != Word && n.mnemonic != Ascii) {
+                report(Lint::Unreachable, (next.line, next.ch), format!("this instruction is unreachable (it follows {m} and has no label)."));
+            }
+        }
+
+        for r in read_registers(instr) {
+            // 報告は最初の1回だけ
+            if written.insert(r) {
+                report(Lint::UninitializedRegister, (instr.line, instr.ch), format!("register {r} is read but never written."));
+            }
+        }
+
+        // spやfpをspとfpだけから計算するのは、スタックフレームを作る・戻す形とみなす
+        for r in written_registers(instr) {
+            let explicit = operands.contains(&Operand::OpRegister(r));
+            if (r == Register::Sp || r == Register::Fp) && explicit && m != Pop {
+                let reads = read_registers(instr);
+                if reads.is_empty() || reads.iter().any(|s| *s != Register::Sp && *s != Register::Fp) {
+                    report(Lint::StackPointerWrite, (instr.line, instr.ch), format!("{r} is written outside a prologue or epilogue."));
+                }
+            }
+        }
+
+        if m == J || m == B || is_conditional_branch(m) || is_conditional_branch_ext(m) {
+            if let (Some(Operand::OpLabel(target)), Some(next)) = (operands.last(), next) {
+                if next.label.contains(target) {
+                    report(Lint::BranchToNext, (instr.line, instr.ch), format!("{m} jumps to the next instruction, which is executed anyway."));
+                }
+            }
+        }
+
+        if m == Slli {
+            if let Some(Operand::OpDigit(n)) = operands.get(2) {
+                if *n >= 32 {
+                    report(Lint::ShiftOverflow, (instr.line, instr.ch), format!("shifting by {n} always gives 0."));
+                }
+            }
+        }
+    }
+    count
+}
@@ -0,0 +1,446 @@
+//! 言語サーバ (Language Server Protocol)。`asm-lsp`バイナリから使う
+//! 標準入出力でJSON-RPCのメッセージをやり取りする。ドキュメントは毎回全文を受け取って解析し直す
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+use crate::diagnostics::{self, Diagnostic, Severity};
+use crate::encoder::{get_op_funct, get_register_num, operand_fields};
+use crate::json::{obj, Value};
+use crate::lexer::{Mnemonic, Register};
+use crate::parser::Parser;
+use crate::resolver::{is_pseudo_instr, report_relaxed_jumps, resolve_without_optimization};
+use crate::semantics::{check_semantics, kind_name, operand_kinds};
+use crate::slice_lexer::{SliceLexer, Token};
+
+/// 受け取るメッセージの大きさの上限。壊れたヘッダで巨大なメモリを確保しないようにする
+const MAX_MESSAGE_LENGTH: usize = 64 << 20;
+
+/// `Content-Length`ヘッダの付いたメッセージを1つ読む。入力が終わっていればNone
+/// 長さが数でないか大きすぎる場合は`io::ErrorKind::InvalidData`
+pub fn read_message(r: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if r.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            if length.is_some() { break; }
+            // メッセージの間の空行は読み飛ばす
+            continue;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                // 数でなければ、ヘッダの終わりで不正な長さとして扱う
+                length = Some(value.trim().parse::<usize>().unwrap_or(usize::MAX));
+            }
+        }
+    }
+
+    let Some(length) = length.filter(|n| *n <= MAX_MESSAGE_LENGTH) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid Content-Length header"));
+    };
+    let mut body = vec![0; length];
+    r.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+pub fn write_message(w: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(w, "Content-Length: {}\r\n\r\n{body}", body.len())?;
+    w.flush()
+}
+
+/// ラベルの出現位置。lineとcharacterは0始まりで、characterは文字単位
+#[derive(Debug, Clone, Eq, PartialEq)]
+struct LabelRef {
+    name: String,
+    line: usize,
+    start: usize,
+    end: usize,
+    /// `name:`の形で定義している
+    is_definition: bool,
+}
+
+/// 位置が分かっている字句。言語サーバで使うものだけを区別する
+#[derive(Debug, Clone, Eq, PartialEq)]
+enum LexemeKind {
+    Mnemonic(Mnemonic),
+    Register(Register),
+    Label(String),
+    Colon,
+    Other,
+}
+
+#[derive(Debug, Clone)]
+struct Lexeme {
+    kind: LexemeKind,
+    line: usize,
+    start: usize,
+    end: usize,
+}
+
+/// 開いているファイル1つ分の解析結果
+struct Document {
+    lines: Vec<String>,
+    lexemes: Vec<Lexeme>,
+    labels: Vec<LabelRef>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+impl Document {
+    fn new(text: &str) -> Self {
+        let lines: Vec<String> = text.split('\n').map(|s| s.to_string()).collect();
+
+        // 字句解析のエラーは下のcheckで報告するので、ここでは読み飛ばす
+        let (lexemes, _) = diagnostics::collect(|| {
+            let mut lexemes = vec![];
+            for item in SliceLexer::new(text.as_bytes()) {
+                let Ok((token, span)) = item else { continue; };
+                if token == Token::Eof { break; }
+                // 1行に収まる字句しか無いので、終わりの文字位置は開始位置から数えれば良い
+                let len = String::from_utf8_lossy(&text.as_bytes()[span.start..span.end]).chars().count();
+                let kind = match token {
+                    Token::Mnemonic(m) => LexemeKind::Mnemonic(m),
+                    Token::Register(r) => LexemeKind::Register(r),
+                    Token::Label(s) => LexemeKind::Label(s.to_string()),
+                    Token::Colon => LexemeKind::Colon,
+                    Token::Newline => { continue; }
+                    _ => LexemeKind::Other,
+                };
+                let start = span.character - 1;
+                lexemes.push(Lexeme { kind, line: span.line - 1, start, end: start + len });
+            }
+            lexemes
+        });
+
+        let mut labels = vec![];
+        for (i, lexeme) in lexemes.iter().enumerate() {
+            if let LexemeKind::Label(name) = &lexeme.kind {
+                let is_definition = matches!(lexemes.get(i + 1), Some(Lexeme { kind: LexemeKind::Colon, .. }));
+                labels.push(LabelRef {
+                    name: name.clone(),
+                    line: lexeme.line,
+                    start: lexeme.start,
+                    end: lexeme.end,
+                    is_definition,
+                });
+            }
+        }
+
+        let (_, diagnostics) = diagnostics::collect(|| {
+            diagnostics::set_source(None, text.as_bytes());
+            let (inst, labels) = Parser::new(SliceLexer::new(text.as_bytes()).tokens()).parse().ok()?;
+            check_semantics(&inst, &labels).ok()?;
+            report_relaxed_jumps(&inst);
+            resolve_without_optimization(inst).ok()
+        });
+
+        Self { lines, lexemes, labels, diagnostics }
+    }
+
+    /// LSPの位置 (UTF-16単位) を文字単位に直す
+    fn to_char(&self, line: usize, utf16: usize) -> usize {
+        let Some(text) = self.lines.get(line) else { return utf16; };
+        let mut units = 0;
+        for (i, c) in text.chars().enumerate() {
+            if units >= utf16 { return i; }
+            units += c.len_utf16();
+        }
+        text.chars().count()
+    }
+
+    /// 文字単位の位置をLSPの位置 (UTF-16単位) に直す
+    fn to_utf16(&self, line: usize, ch: usize) -> usize {
+        match self.lines.get(line) {
+            Some(text) => text.chars().take(ch).map(|c| c.len_utf16()).sum(),
+            None => ch,
+        }
+    }
+
+    fn range(&self, line: usize, start: usize, end: usize) -> Value {
+        obj(&[
+            ("start", obj(&[("line", line.into()), ("character", self.to_utf16(line, start).into())])),
+            ("end", obj(&[("line", line.into()), ("character", self.to_utf16(line, end).into())])),
+        ])
+    }
+
+    fn lexeme_at(&self, line: usize, utf16: usize) -> Option<&Lexeme> {
+        let ch = self.to_char(line, utf16);
+        // 字句の上に無ければ、直後にカーソルがある字句を探す
+        self.lexemes.iter().find(|l| l.line == line && l.start <= ch && ch < l.end)
+            .or_else(|| self.lexemes.iter().find(|l| l.line == line && ch == l.end))
+    }
+
+    fn label_at(&self, line: usize, utf16: usize) -> Option<&str> {
+        match &self.lexeme_at(line, utf16)?.kind {
+            LexemeKind::Label(name) => Some(name),
+            _ => None,
+        }
+    }
+}
+
+/// ニーモニックの説明。オペランドの種類はsemantic checkの表から、エンコーディングはencoderの表から作る
+pub fn describe_mnemonic(m: Mnemonic) -> String {
+    let kinds: Vec<_> = operand_kinds(m).iter().map(|k| kind_name(*k)).collect();
+    let operands = if kinds.is_empty() {
+        "no operands".to_string()
+    } else if m == Mnemonic::Push || m == Mnemonic::Pop {
+        "one or more registers".to_string()
+    } else {
+        kinds.join(", ")
+    };
+    let mut s = format!("**{m}** — {operands}\n\n");
+
+    match operand_fields(m) {
+        Some(fields) => {
+            s.push_str(&format!("encoding: op/funct `0x{:08x}`", get_op_funct(m)));
+            for field in fields {
+                let hi = field.shift + field.width - 1;
+                s.push_str(&format!("\n- {} `[{hi}:{}]`", field.name, field.shift));
+                if !field.is_register() {
+                    s.push_str(&format!(" {}", field.describe()));
+                }
+            }
+        }
+        None if is_pseudo_instr(m) => s.push_str("pseudo-instruction, expanded by the assembler"),
+        None => s.push_str("directive"),
+    }
+    s
+}
+
+fn describe_register(r: Register) -> String {
+    format!("**{r}** — register number {}", get_register_num(r))
+}
+
+const ERROR_INVALID_REQUEST: i64 = -32600;
+const ERROR_METHOD_NOT_FOUND: i64 = -32601;
+const ERROR_PARSE: i64 = -32700;
+
+/// 言語サーバの状態
+pub struct Server {
+    documents: HashMap<String, Document>,
+    shutdown: bool,
+}
+
+impl Default for Server {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Server {
+    pub fn new() -> Self {
+        Self { documents: HashMap::new(), shutdown: false }
+    }
+
+    /// `shutdown`を受け取ったか。`exit`の後の終了コードはこれで決まる
+    pub fn is_shut_down(&self) -> bool {
+        self.shutdown
+    }
+
+    /// `exit`を受け取るか入力が終わるまで、メッセージを処理し続ける
+    pub fn run(&mut self, mut input: impl BufRead, mut output: impl Write) -> io::Result<()> {
+        while let Some(body) = read_message(&mut input)? {
+            let message = match crate::json::parse(&body) {
+                Ok(m) => m,
+                Err(_) => {
+                    let error = obj(&[("code", ERROR_PARSE.into()), ("message", "parse error".into())]);
+                    write_message(&mut output, &obj(&[("jsonrpc", "2.0".into()), ("id", Value::Null), ("error", error)]))?;
+                    continue;
+                }
+            };
+            if message.get("method").as_str() == Some("exit") {
+                break;
+            }
+            for reply in self.handle(&message) {
+                write_message(&mut output, &reply)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 1つのメッセージを処理し、送り返すメッセージを返す
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message.get("method").as_str().unwrap_or("");
+        let params = message.get("params");
+        let id = message.get("id");
+
+        // shutdownの後は、exit以外の通知を無視してリクエストはエラーにする
+        if self.shutdown {
+            if *id == Value::Null {
+                return vec![];
+            }
+            let error = obj(&[("code", ERROR_INVALID_REQUEST.into()), ("message", "server is shut down".into())]);
+            return vec![obj(&[("jsonrpc", "2.0".into()), ("id", id.clone()), ("error", error)])];
+        }
+
+        // 通知 (idが無いもの)
+        if *id == Value::Null {
+            let uri = params.get("textDocument").get("uri").as_str().unwrap_or("").to_string();
+            return match method {
+                "textDocument/didOpen" => {
+                    let text = params.get("textDocument").get("text").as_str().unwrap_or("");
+                    self.documents.insert(uri.clone(), Document::new(text));
+                    vec![self.publish_diagnostics(&uri)]
+                }
+                "textDocument/didChange" => {
+                    // 全文同期なので、最後の変更が新しい全文
+                    let changes = params.get("contentChanges").as_array().unwrap_or(&[]);
+                    if let Some(text) = changes.last().and_then(|c| c.get("text").as_str()) {
+                        self.documents.insert(uri.clone(), Document::new(text));
+                    }
+                    vec![self.publish_diagnostics(&uri)]
+                }
+                "textDocument/didClose" => {
+                    self.documents.remove(&uri);
+                    let params = obj(&[("uri", uri.into()), ("diagnostics", Value::Array(vec![]))]);
+                    vec![notification("textDocument/publishDiagnostics", params)]
+                }
+                _ => vec![],
+            };
+        }
+
+        let result = match method {
+            "initialize" => Ok(obj(&[
+                ("capabilities", obj(&[
+                    ("textDocumentSync", 1_i64.into()),
+                    ("definitionProvider", true.into()),
+                    ("referencesProvider", true.into()),
+                    ("hoverProvider", true.into()),
+                    ("completionProvider", obj(&[])),
+                ])),
+                ("serverInfo", obj(&[("name", "asm-lsp".into())])),
+            ])),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Value::Null)
+            }
+            "textDocument/definition" => Ok(self.definition(params)),
+            "textDocument/references" => Ok(self.references(params)),
+            "textDocument/hover" => Ok(self.hover(params)),
+            "textDocument/completion" => Ok(self.completion(params)),
+            _ => Err((ERROR_METHOD_NOT_FOUND, format!("unknown method \"{method}\""))),
+        };
+
+        let reply = match result {
+            Ok(result) => obj(&[("jsonrpc", "2.0".into()), ("id", id.clone()), ("result", result)]),
+            Err((code, message)) => {
+                let error = obj(&[("code", code.into()), ("message", message.into())]);
+                obj(&[("jsonrpc", "2.0".into()), ("id", id.clone()), ("error", error)])
+            }
+        };
+        vec![reply]
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Value {
+        let diagnostics = match self.documents.get(uri) {
+            Some(doc) => doc.diagnostics.iter()
+                .map(|d| {
+                    let line = d.line.saturating_sub(1);
+                    let severity: i64 = match d.severity {
+                        Severity::Error => 1,
+                        Severity::Warning => 2,
+                    };
+                    obj(&[
+                        ("range", doc.range(line, d.column.saturating_sub(1), d.end_column.saturating_sub(1))),
+                        ("severity", severity.into()),
+                        ("code", format!("{} {}", d.code, d.name).into()),
+                        ("source", "asm".into()),
+                        ("message", d.message.clone().into()),
+                    ])
+                })
+                .collect(),
+            None => vec![],
+        };
+        let params = obj(&[("uri", uri.into()), ("diagnostics", Value::Array(diagnostics))]);
+        notification("textDocument/publishDiagnostics", params)
+    }
+
+    /// paramsが指すドキュメントと位置 (行, UTF-16単位の文字位置)
+    fn position<'a>(&'a self, params: &Value) -> Option<(&'a str, &'a Document, usize, usize)> {
+        let uri = params.get("textDocument").get("uri").as_str()?;
+        let (uri, doc) = self.documents.get_key_value(uri)?;
+        let pos = params.get("position");
+        Some((uri, doc, pos.get("line").as_i64()? as usize, pos.get("character").as_i64()? as usize))
+    }
+
+    fn location(uri: &str, doc: &Document, label: &LabelRef) -> Value {
+        obj(&[("uri", uri.into()), ("range", doc.range(label.line, label.start, label.end))])
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let Some((uri, doc, line, ch)) = self.position(params) else { return Value::Null; };
+        let Some(name) = doc.label_at(line, ch) else { return Value::Null; };
+        let locations: Vec<_> = doc.labels.iter()
+            .filter(|l| l.is_definition && l.name == name)
+            .map(|l| Self::location(uri, doc, l))
+            .collect();
+        Value::Array(locations)
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let Some((uri, doc, line, ch)) = self.position(params) else { return Value::Null; };
+        let Some(name) = doc.label_at(line, ch) else { return Value::Null; };
+        let include_declaration = params.get("context").get("includeDeclaration").as_bool().unwrap_or(true);
+        let locations: Vec<_> = doc.labels.iter()
+            .filter(|l| l.name == name && (include_declaration || !l.is_definition))
+            .map(|l| Self::location(uri, doc, l))
+            .collect();
+        Value::Array(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let Some((_, doc, line, ch)) = self.position(params) else { return Value::Null; };
+        let Some(lexeme) = doc.lexeme_at(line, ch) else { return Value::Null; };
+        let text = match &lexeme.kind {
+            LexemeKind::Mnemonic(m) => describe_mnemonic(*m),
+            LexemeKind::Register(r) => describe_register(*r),
+            LexemeKind::Label(name) => match doc.labels.iter().find(|l| l.is_definition && l.name == *name) {
+                Some(def) => format!("**{name}** — label defined at line {}", def.line + 1),
+                None => format!("**{name}** — undefined label"),
+            },
+            _ => { return Value::Null; }
+        };
+        obj(&[
+            ("contents", obj(&[("kind", "markdown".into()), ("value", text.into())])),
+            ("range", doc.range(lexeme.line, lexeme.start, lexeme.end)),
+        ])
+    }
+
+    fn completion(&self, params: &Value) -> Value {
+        // CompletionItemKindの値
+        const KEYWORD: i64 = 14;
+        const VARIABLE: i64 = 6;
+        const REFERENCE: i64 = 18;
+
+        let item = |label: String, kind: i64, detail: String| obj(&[
+            ("label", label.into()), ("kind", kind.into()), ("detail", detail.into()),
+        ]);
+
+        let mut items = vec![];
+        for m in Mnemonic::ALL {
+            let kinds: Vec<_> = operand_kinds(m).iter().map(|k| kind_name(*k)).collect();
+            items.push(item(m.to_string(), KEYWORD, kinds.join(", ")));
+        }
+        let registers = [Register::Zero, Register::Sp, Register::Fp].into_iter()
+            .chain((0..=252).map(Register::R));
+        for r in registers {
+            items.push(item(r.to_string(), VARIABLE, "register".to_string()));
+        }
+        if let Some((_, doc, _, _)) = self.position(params) {
+            let mut names: Vec<_> = doc.labels.iter().filter(|l| l.is_definition).map(|l| l.name.clone()).collect();
+            names.sort();
+            names.dedup();
+            for name in names {
+                items.push(item(name, REFERENCE, "label".to_string()));
+            }
+        }
+        Value::Array(items)
+    }
+}
+
+fn notification(method: &str, params: Value) -> Value {
+    obj(&[("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)])
+}
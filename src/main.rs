@@ -1,78 +1,712 @@
-use std::env::args;
-use std::fs::File;
-use std::io::{BufReader};
+use std::collections::HashSet;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::process::exit;
 use std::thread;
-use asm_1st::encoder::encode;
-use asm_1st::lexer::Lexer;
-use asm_1st::parser::Parser;
-use asm_1st::resolver::resolve_without_optimization;
-use asm_1st::semantics::check_semantics;
-
-fn main_inner() {
-    let args: Vec<String> = args().collect();
-    if args.len() != 2 {
-        println!("[usage:] ./asm_1st path/to/assembly");
-        return;
-    }
-
-    let path = &args[1];
-    let f = match File::open(path) {
-        Ok(f) => f,
-        Err(e) => {
-            println!("could not open file: {}", e);
-            return;
+use std::time::Duration;
+use asm_1st::cfg::Cfg;
+use asm_1st::debugger::Debugger;
+use asm_1st::diagnostics::{self, report, DiagnosticsFormat};
+use asm_1st::disassembler::disassemble;
+use asm_1st::encoder::{encode, set_isa, Isa};
+use asm_1st::formatter::format_source;
+use asm_1st::gdb::GdbStub;
+use asm_1st::hazard::{find_hazards, insert_nops, report_hazards, Hazard, HazardModel};
+use asm_1st::lint::{lint, Lint};
+use asm_1st::parser::{Instruction, Parser};
+use asm_1st::peephole::optimize;
+use asm_1st::resolver::{report_relaxed_jumps, resolve_without_optimization};
+use asm_1st::semantics::{check_semantics, SemanticError};
+use asm_1st::simulator::{Machine, DEFAULT_MEMORY_WORDS};
+use asm_1st::slice_lexer::SliceLexer;
+use asm_1st::timing::TimingModel;
+use asm_1st::uart::{write_transcript, StreamUart};
+
+const USAGE: &str = "\
+usage: asm_1st [command] [options] <input>...
+       (`-` as <input> or <path> means stdin or stdout)
+
+commands:
+  asm       assemble a source file (default)
+  check     check a source file without emitting any code
+  disasm    disassemble machine code
+  sim       assemble a source file and run it on the simulator
+  link      assemble several source files as one program
+  fmt       print a source file in the canonical format
+  cfg       print the control-flow graph of each function in Graphviz DOT
+  debug     run a source file on the simulator under an interactive debugger
+  gdb       serve a source file on the simulator to gdb (remote protocol over stdio or TCP)
+
+options:
+  -o, --output <path>   write the output to <path> instead of stdout
+  --format <hex|bin>    format of machine code (default: hex)
+  --isa <name>          target instruction set: 1st (default) or 1st-signed-offset
+  --lint                warn about suspicious code (not with link)
+  -A, --allow <lint>    disable one kind of warning (e.g. unused-label)
+  -O                    remove redundant instructions before resolving addresses
+  --hazards             warn about pipeline hazards of the target core (not with link)
+  --hazard-model <path> read the hazard model from a JSON file (implies --hazards)
+  --auto-nop            insert nops to remove pipeline hazards (not with link)
+  -W <none|error>       disable warnings, or treat warnings as errors
+  --diagnostics-format <human|json>
+                        print errors as text (default) or one JSON object per line
+  --max-steps <n>       (sim) give up after executing n instructions
+  --timing              (sim) estimate the cycle count on the pipelined core
+  --timing-model <path> (sim) read the timing model from a JSON file (implies --timing)
+  --port <n>            (gdb) listen on 127.0.0.1:<n> instead of using stdin/stdout
+  --uart-in <path>      (sim) read urecv input from <path> (default: stdin)
+  --uart-script <text>  (sim) read urecv input from <text>
+  --uart-timeout <ms>   (sim) fail if urecv waits longer than <ms> milliseconds
+  --uart-transcript <path>
+                        (sim) record every byte sent and received to <path>
+  --check               (fmt) exit with 1 if the input is not formatted
+  -h, --help            print this message";
+
+/// 終了コード。Makefileから失敗の種類を見分けられるように、段階ごとに分けている
+mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    /// `fmt --check`で整形済みでなかった
+    pub const UNFORMATTED: i32 = 1;
+    pub const USAGE: i32 = 2;
+    pub const IO: i32 = 3;
+    pub const SYNTAX: i32 = 4;
+    pub const SEMANTIC: i32 = 5;
+    pub const RESOLUTION: i32 = 6;
+    pub const RUNTIME: i32 = 7;
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Command {
+    Asm,
+    Check,
+    Disasm,
+    Sim,
+    Link,
+    Fmt,
+    Cfg,
+    Debug,
+    Gdb,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Format {
+    Hex,
+    Bin,
+}
+
+/// 警告 (`--lint`と`--hazards`) の扱い
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Warnings {
+    On,
+    Off,
+    Error,
+}
+
+struct Options {
+    command: Command,
+    inputs: Vec<String>,
+    output: Option<String>,
+    format: Format,
+    warnings: Warnings,
+    /// lintを走らせる
+    lint: bool,
+    /// `-A`で無効にした警告
+    allowed: HashSet<Lint>,
+    /// 覗き穴最適化をする
+    optimize: bool,
+    /// パイプラインのハザードを調べるときのモデル
+    hazard_model: Option<HazardModel>,
+    /// ハザードをnopの挿入で解消する
+    auto_nop: bool,
+    max_steps: Option<u64>,
+    /// (gdb) 待ち受けるTCPのポート。省略すると標準入出力を使う
+    port: Option<u16>,
+    /// (sim) サイクル数を見積もるときのモデル
+    timing_model: Option<TimingModel>,
+    /// (sim, debug, gdb) urecvの入力。省略するとsimでは標準入力、それ以外では空
+    uart_input: Option<UartInput>,
+    /// (sim) urecvで待つ時間の上限
+    uart_timeout: Option<Duration>,
+    /// (sim) 送受信の記録の書き出し先
+    uart_transcript: Option<String>,
+    /// (fmt) 整形せずに、整形済みかどうかだけを調べる
+    check: bool,
+}
+
+/// (sim) urecvの入力
+enum UartInput {
+    /// ファイル。`-`なら標準入力
+    Path(String),
+    Script(Vec<u8>),
+}
+
+/// 失敗した段階。メッセージはその段階で表示済み
+#[derive(Debug)]
+enum Failure {
+    Unformatted,
+    Usage,
+    Io,
+    Syntax,
+    Semantic,
+    Resolution,
+    Runtime,
+}
+
+impl Failure {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Failure::Unformatted => exit_code::UNFORMATTED,
+            Failure::Usage => exit_code::USAGE,
+            Failure::Io => exit_code::IO,
+            Failure::Syntax => exit_code::SYNTAX,
+            Failure::Semantic => exit_code::SEMANTIC,
+            Failure::Resolution => exit_code::RESOLUTION,
+            Failure::Runtime => exit_code::RUNTIME,
         }
+    }
+}
+
+fn usage_error(msg: &str) -> Failure {
+    eprintln!("error: {msg}");
+    eprintln!("{USAGE}");
+    Failure::Usage
+}
+
+fn parse_args(args: &[String]) -> Result<Options, Failure> {
+    // コマンドを省略した場合は、これまで通りasmとして扱う
+    let (command, rest) = match args.first().map(|s| s.as_str()) {
+        Some("asm") => (Command::Asm, &args[1..]),
+        Some("check") => (Command::Check, &args[1..]),
+        Some("disasm") => (Command::Disasm, &args[1..]),
+        Some("sim") => (Command::Sim, &args[1..]),
+        Some("link") => (Command::Link, &args[1..]),
+        Some("fmt") => (Command::Fmt, &args[1..]),
+        Some("cfg") => (Command::Cfg, &args[1..]),
+        Some("debug") => (Command::Debug, &args[1..]),
+        Some("gdb") => (Command::Gdb, &args[1..]),
+        _ => (Command::Asm, args),
     };
 
-    let br = BufReader::new(f);
-    let lex = Lexer::new(br);
-    let par = Parser::new(lex);
-    // let (inst, map) = match par.parse() {
-    //     Ok(ok) => ok,
-    //     Err(_e) => { return; }
-    // };
-    // let binary = match encoder_old::encode(inst, map) {
-    //     Ok(ok) => ok,
-    //     Err(_e) => { return; }
-    // };
-
-    let (inst, labels) = match par.parse() {
-        Ok(ok) => ok,
-        Err(_) => { return; }
+    let mut options = Options {
+        command,
+        inputs: vec![],
+        output: None,
+        format: Format::Hex,
+        warnings: Warnings::On,
+        lint: false,
+        allowed: HashSet::new(),
+        optimize: false,
+        hazard_model: None,
+        auto_nop: false,
+        max_steps: None,
+        timing_model: None,
+        port: None,
+        uart_input: None,
+        uart_timeout: None,
+        uart_transcript: None,
+        check: false,
     };
-    match check_semantics(&inst, &labels) {
-        Ok(()) => {}
-        Err(_) => { return; }
+
+    let mut it = rest.iter();
+    while let Some(arg) = it.next() {
+        // `--format=bin`と`--format bin`のどちらでも書ける
+        let (name, inline_value) = match arg.split_once('=') {
+            Some((name, value)) if arg.starts_with("--") => (name, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = |name: &str| match inline_value.clone().or_else(|| it.next().cloned()) {
+            Some(v) => Ok(v),
+            None => Err(usage_error(&format!("{name} needs a value."))),
+        };
+
+        match name {
+            "-h" | "--help" => {
+                println!("{USAGE}");
+                exit(exit_code::SUCCESS);
+            }
+            "-o" | "--output" => options.output = Some(value(name)?),
+            "--format" => {
+                options.format = match value(name)?.as_str() {
+                    "hex" => Format::Hex,
+                    "bin" => Format::Bin,
+                    v => { return Err(usage_error(&format!("unknown format \"{v}\"."))); }
+                };
+            }
+            "--isa" => {
+                let v = value(name)?;
+                match Isa::from_name(&v) {
+                    Some(isa) => set_isa(isa),
+                    None => { return Err(usage_error(&format!("unknown instruction set \"{v}\"."))); }
+                }
+            }
+            "-W" => {
+                options.warnings = match value(name)?.as_str() {
+                    "none" => Warnings::Off,
+                    "error" => Warnings::Error,
+                    v => { return Err(usage_error(&format!("unknown warning option \"{v}\"."))); }
+                };
+            }
+            "--lint" => options.lint = true,
+            "-A" | "--allow" => {
+                let v = value(name)?;
+                match Lint::from_flag_name(&v) {
+                    Some(lint) => { options.allowed.insert(lint); }
+                    None => { return Err(usage_error(&format!("unknown lint \"{v}\"."))); }
+                }
+            }
+            "-O" => options.optimize = true,
+            "--hazards" => {
+                options.hazard_model.get_or_insert_with(HazardModel::default);
+            }
+            "--hazard-model" => {
+                let path = value(name)?;
+                match HazardModel::from_json(&read_model(&path)?) {
+                    Ok(model) => options.hazard_model = Some(model),
+                    Err(e) => { return Err(usage_error(&format!("invalid hazard model in {path}: {e}"))); }
+                }
+            }
+            "--auto-nop" => options.auto_nop = true,
+            "--diagnostics-format" => {
+                let format = match value(name)?.as_str() {
+                    "human" => DiagnosticsFormat::Human,
+                    "json" => DiagnosticsFormat::Json,
+                    v => { return Err(usage_error(&format!("unknown diagnostics format \"{v}\"."))); }
+                };
+                diagnostics::set_format(format);
+            }
+            "--max-steps" => {
+                let v = value(name)?;
+                match v.parse() {
+                    Ok(n) => options.max_steps = Some(n),
+                    Err(_) => { return Err(usage_error(&format!("invalid number \"{v}\"."))); }
+                }
+            }
+            "--port" => {
+                let v = value(name)?;
+                match v.parse() {
+                    Ok(port) => options.port = Some(port),
+                    Err(_) => { return Err(usage_error(&format!("invalid port \"{v}\"."))); }
+                }
+            }
+            "--timing" => {
+                options.timing_model.get_or_insert_with(TimingModel::default);
+            }
+            "--timing-model" => {
+                let path = value(name)?;
+                match TimingModel::from_json(&read_model(&path)?) {
+                    Ok(model) => options.timing_model = Some(model),
+                    Err(e) => { return Err(usage_error(&format!("invalid timing model in {path}: {e}"))); }
+                }
+            }
+            "--uart-in" => options.uart_input = Some(UartInput::Path(value(name)?)),
+            "--uart-script" => options.uart_input = Some(UartInput::Script(value(name)?.into_bytes())),
+            "--uart-timeout" => {
+                let v = value(name)?;
+                match v.parse() {
+                    Ok(ms) => options.uart_timeout = Some(Duration::from_millis(ms)),
+                    Err(_) => { return Err(usage_error(&format!("invalid number \"{v}\"."))); }
+                }
+            }
+            "--uart-transcript" => options.uart_transcript = Some(value(name)?),
+            "--check" if options.command == Command::Fmt => options.check = true,
+            _ if name.starts_with('-') && name != "-" => {
+                return Err(usage_error(&format!("unknown option \"{name}\".")));
+            }
+            _ => options.inputs.push(arg.clone()),
+        }
     }
-    let inst = match resolve_without_optimization(inst) {
-        Ok(ok) => ok,
-        Err(_) => { return; }
+
+    if options.inputs.is_empty() {
+        return Err(usage_error("no input file."));
+    }
+    if options.command == Command::Link {
+        // ラベルがファイル間で共有されるので、ファイルごとには調べられない
+        if options.lint {
+            return Err(usage_error("--lint cannot be used with link."));
+        }
+        if options.hazard_model.is_some() || options.auto_nop {
+            return Err(usage_error("--hazards and --auto-nop cannot be used with link."));
+        }
+    }
+    if options.command != Command::Link && options.inputs.len() > 1 {
+        return Err(usage_error("only one input file can be given (use `link` for several files)."));
+    }
+
+    Ok(options)
+}
+
+/// モデルのJSONファイルを読む
+fn read_model(path: &str) -> Result<String, Failure> {
+    // 標準入力はソースやurecvの入力に使うので、モデルには使わせない
+    if path == "-" {
+        return Err(usage_error("a model must be read from a file, not stdin."));
+    }
+    Ok(String::from_utf8_lossy(&read_input(path)?).into_owned())
+}
+
+/// `-`は標準入力を表す
+fn read_input(path: &str) -> Result<Vec<u8>, Failure> {
+    let res = if path == "-" {
+        let mut buf = vec![];
+        io::stdin().read_to_end(&mut buf).map(|_| buf)
+    } else {
+        fs::read(path)
+    };
+    res.map_err(|e| {
+        eprintln!("could not open file: {}", e);
+        Failure::Io
+    })
+}
+
+/// 出力先を省略するか`-`を指定すると標準出力に書く
+fn write_output(options: &Options, bytes: &[u8]) -> Result<(), Failure> {
+    let res = match options.output.as_deref() {
+        Some("-") | None => io::stdout().lock().write_all(bytes),
+        Some(path) => fs::write(path, bytes),
     };
-    let binary = encode(inst);
+    match res {
+        // パイプの先が読むのをやめただけなので、エラーにしない
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => { return Ok(()); }
+        _ => {}
+    }
+    res.map_err(|e| {
+        eprintln!("could not write output: {}", e);
+        Failure::Io
+    })
+}
 
-    // let mut g = match File::create("asm.out") {
-    //     Ok(f) => f,
-    //     Err(e) => {
-    //         println!("could not create file: {}", e);
-    //         return;
-    //     }
-    // };
-    // let mut bin = vec![];
-    // for b in binary {
-    //     for i in 0..4 {
-    //         bin.push(((b >> (i * 8)) & 0xff) as u8);
-    //     }
-    // }
-    // g.write_all(&mut bin);
+fn parse(src: &[u8]) -> Result<(Vec<Instruction>, HashSet<String>), Failure> {
+    let lex = SliceLexer::new(src);
+    Parser::new(lex.tokens()).parse().map_err(|_| Failure::Syntax)
+}
+
+/// 診断に表示するファイル名
+fn file_name(path: &str) -> &str {
+    if path == "-" { "<stdin>" } else { path }
+}
 
-    for b in binary {
-        println!("{:<08x}", b);
+/// 人が読む形式のときだけ、どのファイルのエラーかを補足する (JSONには`file`がある)
+fn note_file(path: &str) {
+    if diagnostics::format() == DiagnosticsFormat::Human {
+        eprintln!("in file {}", file_name(path));
+    }
+}
+
+/// 1つのファイルをアドレス解決までする
+fn resolve(options: &Options, path: &str, src: &[u8]) -> Result<Vec<Instruction>, Failure> {
+    diagnostics::set_source(Some(file_name(path)), src);
+    let (inst, labels) = Parser::new(SliceLexer::new(src).tokens()).parse_with_label_positions().map_err(|_| Failure::Syntax)?;
+    check_semantics(&inst, &labels.keys().cloned().collect()).map_err(|_| Failure::Semantic)?;
+    if options.lint && options.warnings != Warnings::Off {
+        let count = lint(&inst, &labels, &options.allowed);
+        if count > 0 && options.warnings == Warnings::Error {
+            eprintln!("{count} warning(s) treated as errors.");
+            return Err(Failure::Semantic);
+        }
+    }
+    let inst = if options.optimize { optimize_and_report(inst) } else { inst };
+    check_relaxed_jumps(options, &inst)?;
+
+    let model = options.hazard_model.clone().unwrap_or_default();
+    if options.auto_nop {
+        // nopを入れるとアドレスが変わるので、アドレス解決はinsert_nopsの中でやり直す
+        let (program, inserted, unfixed) = insert_nops(inst, &model).map_err(|_| Failure::Resolution)?;
+        if inserted > 0 {
+            eprintln!("inserted {inserted} nop(s) to avoid pipeline hazards.");
+        }
+        check_hazards(options, &program, &unfixed)?;
+        return Ok(program);
+    }
+    let program = resolve_without_optimization(inst).map_err(|_| Failure::Resolution)?;
+    if options.hazard_model.is_some() {
+        check_hazards(options, &program, &find_hazards(&program, &model))?;
+    }
+    Ok(program)
+}
+
+/// 覗き穴最適化をして、取り除いた命令の数を標準エラー出力に書く
+fn optimize_and_report(inst: Vec<Instruction>) -> Vec<Instruction> {
+    let (inst, removed) = optimize(inst);
+    eprintln!("removed {removed} redundant instruction(s).");
+    inst
+}
+
+/// lj/lcallに置き換わるj/callを警告する。`-W error`なら失敗にする
+fn check_relaxed_jumps(options: &Options, inst: &[Instruction]) -> Result<(), Failure> {
+    if options.warnings == Warnings::Off {
+        return Ok(());
+    }
+    let count = report_relaxed_jumps(inst);
+    if count > 0 && options.warnings == Warnings::Error {
+        eprintln!("{count} warning(s) treated as errors.");
+        return Err(Failure::Resolution);
+    }
+    Ok(())
+}
+
+/// ハザードを警告する。`-W error`なら失敗にする
+fn check_hazards(options: &Options, program: &[Instruction], hazards: &[Hazard]) -> Result<(), Failure> {
+    if options.warnings == Warnings::Off || hazards.is_empty() {
+        return Ok(());
+    }
+    report_hazards(program, hazards);
+    if options.warnings == Warnings::Error {
+        eprintln!("{} warning(s) treated as errors.", hazards.len());
+        return Err(Failure::Resolution);
+    }
+    Ok(())
+}
+
+/// 1つのファイルを機械語までアセンブルする
+fn assemble(options: &Options, path: &str, src: &[u8]) -> Result<Vec<u32>, Failure> {
+    Ok(encode(resolve(options, path, src)?))
+}
+
+/// 複数のファイルを、並べた順に1つのプログラムとしてアセンブルする。ラベルはファイル間で共有される
+fn link(options: &Options, paths: &[String]) -> Result<Vec<u32>, Failure> {
+    let mut files = vec![];
+    let mut labels = HashSet::new();
+    for path in paths {
+        let src = read_input(path)?;
+        diagnostics::set_source(Some(file_name(path)), &src);
+        let (inst, file_labels) = parse(&src).inspect_err(|_| note_file(path))?;
+        for label in &file_labels {
+            if labels.contains(label) {
+                let Instruction { line, ch, .. } = inst.iter().find(|i| i.label.contains(label)).unwrap();
+                let message = format!("label \"{label}\" is already defined in another file.");
+                report(*line, *ch, "Error", SemanticError::DuplicateLabelError, message);
+                note_file(path);
+                return Err(Failure::Semantic);
+            }
+        }
+        labels.extend(file_labels);
+        files.push((path, src, inst));
+    }
+
+    let mut program = vec![];
+    for (path, src, inst) in files {
+        diagnostics::set_source(Some(file_name(path)), &src);
+        check_semantics(&inst, &labels).map_err(|_| {
+            note_file(path);
+            Failure::Semantic
+        })?;
+        program.extend(inst);
+    }
+    let program = if options.optimize { optimize_and_report(program) } else { program };
+    // アドレス解決のエラーはどのファイルのものか分からない
+    diagnostics::set_source(None, &[]);
+    check_relaxed_jumps(options, &program)?;
+    let inst = resolve_without_optimization(program).map_err(|_| Failure::Resolution)?;
+    Ok(encode(inst))
+}
+
+fn format_binary(binary: &[u32], format: Format) -> Vec<u8> {
+    match format {
+        Format::Hex => binary.iter().flat_map(|b| format!("{:<08x}\n", b).into_bytes()).collect(),
+        // リトルエンディアンで1ワード4バイト
+        Format::Bin => binary.iter().flat_map(|b| b.to_le_bytes()).collect(),
+    }
+}
+
+/// asmの出力を読み戻す
+fn parse_binary(bytes: &[u8], format: Format) -> Result<Vec<u32>, Failure> {
+    match format {
+        Format::Hex => {
+            let text = String::from_utf8_lossy(bytes);
+            let mut binary = vec![];
+            for (i, line) in text.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() { continue; }
+                match u32::from_str_radix(line, 16) {
+                    Ok(word) => binary.push(word),
+                    Err(_) => {
+                        eprintln!("at line {}, character 1: Syntax Error", i + 1);
+                        eprintln!("\"{line}\" is not a hexadecimal word.");
+                        return Err(Failure::Syntax);
+                    }
+                }
+            }
+            Ok(binary)
+        }
+        Format::Bin => {
+            if !bytes.len().is_multiple_of(4) {
+                eprintln!("Syntax Error");
+                eprintln!("the size of the binary ({} bytes) is not a multiple of 4.", bytes.len());
+                return Err(Failure::Syntax);
+            }
+            Ok(bytes.chunks(4).map(|c| u32::from_le_bytes([c[0], c[1], c[2], c[3]])).collect())
+        }
+    }
+}
+
+/// `fmt --check`。整形済みでなければ、最初に違う行を報告する
+fn check_formatted(path: &str, src: &[u8], formatted: &str) -> Result<(), Failure> {
+    let src = String::from_utf8_lossy(src);
+    if src == formatted {
+        return Ok(());
+    }
+    let line = src.lines().zip(formatted.lines()).position(|(a, b)| a != b)
+        .unwrap_or_else(|| src.lines().count().min(formatted.lines().count()));
+    eprintln!("{} is not formatted (first difference at line {}).", file_name(path), line + 1);
+    Err(Failure::Unformatted)
+}
+
+fn run(options: &Options) -> Result<(), Failure> {
+    match options.command {
+        Command::Asm => {
+            let binary = assemble(options, &options.inputs[0], &read_input(&options.inputs[0])?)?;
+            write_output(options, &format_binary(&binary, options.format))
+        }
+        Command::Check => {
+            assemble(options, &options.inputs[0], &read_input(&options.inputs[0])?)?;
+            Ok(())
+        }
+        Command::Link => {
+            let binary = link(options, &options.inputs)?;
+            write_output(options, &format_binary(&binary, options.format))
+        }
+        Command::Disasm => {
+            let binary = parse_binary(&read_input(&options.inputs[0])?, options.format)?;
+            let text: String = disassemble(&binary).into_iter().map(|s| s + "\n").collect();
+            write_output(options, text.as_bytes())
+        }
+        Command::Fmt => {
+            let src = read_input(&options.inputs[0])?;
+            diagnostics::set_source(Some(file_name(&options.inputs[0])), &src);
+            let (inst, _) = parse(&src)?;
+            let formatted = format_source(&src, &inst);
+            if options.check {
+                return check_formatted(&options.inputs[0], &src, &formatted);
+            }
+            write_output(options, formatted.as_bytes())
+        }
+        Command::Cfg => {
+            let program = resolve(options, &options.inputs[0], &read_input(&options.inputs[0])?)?;
+            let cfg = Cfg::build(&program);
+            let dot: String = cfg.functions.iter().map(|f| cfg.to_dot(f, &program)).collect();
+            write_output(options, dot.as_bytes())
+        }
+        Command::Gdb => {
+            if options.port.is_none() && options.inputs[0] == "-" {
+                return Err(usage_error("gdb talks over stdin without --port, so the source must be a file."));
+            }
+            let src = read_input(&options.inputs[0])?;
+            let program = resolve(options, &options.inputs[0], &src)?;
+            let uart = open_uart(options)?;
+            let debugger = Debugger::new(program, &String::from_utf8_lossy(&src), DEFAULT_MEMORY_WORDS, Box::new(uart));
+            let mut stub = GdbStub::new(debugger);
+            let res = match options.port {
+                Some(port) => TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+                    // 0を指定した場合は空いているポートになるので、どこで待っているかを知らせる
+                    eprintln!("listening on {}", listener.local_addr()?);
+                    let (stream, _) = listener.accept()?;
+                    stub.serve(stream.try_clone()?, &mut &stream)
+                }),
+                None => stub.serve(io::stdin(), &mut io::stdout().lock()),
+            };
+            res.map_err(|e| {
+                eprintln!("{e}");
+                Failure::Io
+            })
+        }
+        Command::Debug => {
+            if options.inputs[0] == "-" {
+                return Err(usage_error("debug reads commands from stdin, so the source must be a file."));
+            }
+            let src = read_input(&options.inputs[0])?;
+            let program = resolve(options, &options.inputs[0], &src)?;
+            let uart = open_uart(options)?;
+            let mut debugger = Debugger::new(program, &String::from_utf8_lossy(&src), DEFAULT_MEMORY_WORDS, Box::new(uart));
+            let res = debugger.repl(&mut io::stdin().lock(), &mut io::stdout().lock());
+            res.map_err(|e| {
+                eprintln!("{e}");
+                Failure::Io
+            })
+        }
+        Command::Sim => {
+            let binary = assemble(options, &options.inputs[0], &read_input(&options.inputs[0])?)?;
+            let mut machine = Machine::with_uart(&binary, DEFAULT_MEMORY_WORDS, Box::new(open_uart(options)?));
+            if options.uart_transcript.is_some() {
+                machine.record_transcript();
+            }
+            if let Some(model) = &options.timing_model {
+                machine.enable_timing(model.clone());
+            }
+            let res = machine.run(options.max_steps);
+            // 実行時エラーで止まった場合も、原因を調べられるように記録は書き出す
+            if let (Some(path), Some(events)) = (&options.uart_transcript, &machine.transcript) {
+                let res = fs::File::create(path)
+                    .and_then(|f| write_transcript(events, &mut io::BufWriter::new(f)));
+                if let Err(e) = res {
+                    eprintln!("could not write transcript: {}", e);
+                    return Err(Failure::Io);
+                }
+            }
+            if let Some(timing) = &machine.timing {
+                eprint!("{timing}");
+            }
+            match res {
+                Ok(()) => {
+                    eprintln!("halted at address {} after {} instructions.", machine.pc, machine.steps);
+                    Ok(())
+                }
+                Err(e) => {
+                    eprintln!("{e}");
+                    Err(Failure::Runtime)
+                }
+            }
+        }
+    }
+}
+
+/// (sim, debug, gdb) オプションに従ってUARTをつなぐ
+/// 標準出力はプログラムのusendに使うので、結果の要約は標準エラー出力に書く
+fn open_uart(options: &Options) -> Result<StreamUart, Failure> {
+    let output: Box<dyn Write> = match options.output.as_deref() {
+        // 標準入出力でGDBと話している場合は、usendの出力でパケットを壊さないようにする
+        None if options.command == Command::Gdb && options.port.is_none() => Box::new(io::stderr()),
+        Some("-") | None => Box::new(io::stdout()),
+        Some(path) => match fs::File::create(path) {
+            Ok(f) => Box::new(io::BufWriter::new(f)),
+            Err(e) => {
+                eprintln!("could not create file: {}", e);
+                return Err(Failure::Io);
+            }
+        },
+    };
+    // debugとgdbは標準入力からコマンドを読むので、プログラムの入力にはしない
+    let default = if options.command == Command::Debug || options.command == Command::Gdb { UartInput::Script(vec![]) } else { UartInput::Path("-".to_string()) };
+    let input: Box<dyn Read + Send> = match options.uart_input.as_ref().unwrap_or(&default) {
+        UartInput::Path(path) if path == "-" => Box::new(io::stdin()),
+        UartInput::Path(path) => match fs::File::open(path) {
+            Ok(f) => Box::new(io::BufReader::new(f)),
+            Err(e) => {
+                eprintln!("could not open file: {}", e);
+                return Err(Failure::Io);
+            }
+        },
+        UartInput::Script(script) => Box::new(io::Cursor::new(script.clone())),
+    };
+    Ok(match options.uart_timeout {
+        Some(timeout) => StreamUart::with_timeout(input, output, timeout),
+        None => StreamUart::new(input, output),
+    })
+}
+
+fn main_inner() -> i32 {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let res = parse_args(&args).and_then(|options| run(&options));
+    match res {
+        Ok(()) => exit_code::SUCCESS,
+        Err(failure) => failure.exit_code(),
     }
 }
 
 fn main() {
     let stack_size = 100_000_000usize;
-    thread::Builder::new().stack_size(stack_size).spawn(move || {
+    let code = thread::Builder::new().stack_size(stack_size).spawn(move || {
         main_inner()
     }).unwrap().join().unwrap();
-}
\ No newline at end of file
+    exit(code);
+}
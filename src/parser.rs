@@ -1,17 +1,20 @@
-use std::collections::HashSet;
-use std::io::Read;
+use std::collections::{HashMap, HashSet};
 use std::iter::Peekable;
-use std::str::from_utf8;
-use crate::lexer::{Lexer, LexToken, Mnemonic, Register};
+use crate::diagnostics::{report, ErrorCode};
+use crate::lexer::{LexItem, LexToken, Mnemonic, Register};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Operand {
     OpRegister(Register),
     OpLabel(String),
     OpDigit(i64),
+    /// 浮動小数点数。IEEE-754単精度のビット列で持つ
+    OpFloat(u32),
+    /// 文字列。エスケープシーケンスは展開済み
+    OpString(Vec<u8>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Instruction {
     pub label: Vec<String>,
     pub mnemonic: Mnemonic,
@@ -26,87 +29,112 @@ pub enum ParseError {
     LexicalError,
 }
 
-pub struct Parser<T: Read> {
-    lexer: Peekable<Lexer<T>>,
+impl ErrorCode for ParseError {
+    fn code(&self) -> &'static str {
+        match self {
+            ParseError::MalformedSentenceError => "E0301",
+            ParseError::LexicalError => "E0302",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ParseError::MalformedSentenceError => "MalformedSentence",
+            ParseError::LexicalError => "Lexical",
+        }
+    }
+}
+
+/// ラベルと、それを定義した位置 (行, 列)
+pub type LabelPositions = HashMap<String, (usize, usize)>;
+
+/// `Lexer`や`SliceLexer::tokens`など、`LexItem`を返すイテレータなら何でも構文解析できる
+pub struct Parser<I: Iterator<Item = LexItem>> {
+    lexer: Peekable<I>,
     instructions: Vec<Instruction>,
-    labels: HashSet<String>,
+    labels: LabelPositions,
     line: usize,
     character: usize,
 }
 
-impl<T: Read> Parser<T> {
-    pub fn new(lexer: Lexer<T>) -> Self {
+impl<I: Iterator<Item = LexItem>> Parser<I> {
+    pub fn new(lexer: I) -> Self {
         Self {
             lexer: lexer.peekable(),
             instructions: vec![],
-            labels: HashSet::new(),
+            labels: HashMap::new(),
             line: 1,
             character: 1,
         }
     }
 
-    pub fn parse(mut self) -> Result<(Vec<Instruction>, HashSet<String>), ParseError> {
+    pub fn parse(self) -> Result<(Vec<Instruction>, HashSet<String>), ParseError> {
+        let (instructions, labels) = self.parse_with_label_positions()?;
+        Ok((instructions, labels.into_keys().collect()))
+    }
+
+    /// `parse`と同じだが、ラベルを定義した位置も返す。同じラベルが複数あれば最初の位置
+    pub fn parse_with_label_positions(mut self) -> Result<(Vec<Instruction>, LabelPositions), ParseError> {
         self.asm_program()?;
         let Parser { instructions, labels, .. } = self;
         Ok((instructions, labels))
     }
 
     fn peek(&mut self) -> Result<LexToken, ParseError> {
-        let a = self.lexer.peek();
-        if a.is_none() {
-            Err(ParseError::LexicalError)
-        } else {
-            let (a, b, c) = a.unwrap();
-            self.line = *b;
-            self.character = *c;
-            Ok(a.clone())
+        match self.lexer.peek() {
+            Some(Ok((a, b, c))) => {
+                self.line = *b;
+                self.character = *c;
+                Ok(a.clone())
+            }
+            // エラーの内容はLexerが表示済み
+            _ => Err(ParseError::LexicalError),
         }
     }
 
     fn print_err(&self, msg: &str) {
-        println!("at line {}, character {}: Syntax Error", self.line, self.character);
-        println!("{}", msg);
+        report(self.line, self.character, "Syntax Error", ParseError::MalformedSentenceError, msg);
     }
 
     // 以下、再帰下降構文解析
+    // ただし末尾再帰になる規則はループで書く (巨大な入力でスタックが溢れないように)
 
     fn asm_program(&mut self) -> Result<(), ParseError> {
-        self.asm_program_line()?;
-        let a = self.peek()?;
-        if a == LexToken::LexEof { return Ok(()); }
-        self.asm_program()
+        loop {
+            self.asm_program_line()?;
+            let a = self.peek()?;
+            if a == LexToken::LexEof { return Ok(()); }
+        }
     }
 
     fn asm_program_line(&mut self) -> Result<(), ParseError> {
-        let a = self.peek()?;
-        if a == LexToken::LexEof || a == LexToken::LexNewline {
-            self.lexer.next();
-            return Ok(());
-        }
+        loop {
+            let a = self.peek()?;
+            if a == LexToken::LexEof || a == LexToken::LexNewline {
+                self.lexer.next();
+                return Ok(());
+            }
 
-        self.labeled_single_instr(vec![])?;
+            self.labeled_single_instr(vec![])?;
 
-        let a = self.peek()?;
-        if a == LexToken::LexEof || a == LexToken::LexNewline {
-            self.lexer.next();
-            return Ok(());
-        }
+            let a = self.peek()?;
+            if a == LexToken::LexEof || a == LexToken::LexNewline {
+                self.lexer.next();
+                return Ok(());
+            }
 
-        if a == LexToken::LexSemicolon {
+            if a != LexToken::LexSemicolon {
+                self.print_err("expected ';', newline or EOF.");
+                return Err(ParseError::MalformedSentenceError);
+            }
             self.lexer.next();
-            return self.asm_program_line();
         }
-
-        self.print_err("expected ';', newline or EOF.");
-        Err(ParseError::MalformedSentenceError)
     }
 
     fn labeled_single_instr(&mut self, mut labels: Vec<String>) -> Result<(), ParseError> {
-        let a = self.peek()?;
-        if let LexToken::LexLabel(label) = a {
-            let s = from_utf8(&label).unwrap().to_string();
-            self.labels.insert(s.clone());
-            labels.push(s);
+        while let LexToken::LexLabel(label) = self.peek()? {
+            self.labels.entry(label.clone()).or_insert((self.line, self.character));
+            labels.push(label);
             self.lexer.next();
 
             let a = self.peek()?;
@@ -119,8 +147,6 @@ impl<T: Read> Parser<T> {
             while self.peek()? == LexToken::LexNewline {
                 self.lexer.next();
             }
-
-            return self.labeled_single_instr(labels);
         }
 
         self.single_instr(labels)
@@ -132,10 +158,13 @@ impl<T: Read> Parser<T> {
         if let LexToken::LexMnemonic(mnemonic) = a {
             self.lexer.next();
 
+            // retやleaveのように、オペランドを取らない命令もある
             let mut operands = vec![];
-            operands.push(self.operand()?);
-
-            self.operand_list(&mut operands)?;
+            let a = self.peek()?;
+            if !(a == LexToken::LexEof || a == LexToken::LexNewline || a == LexToken::LexSemicolon) {
+                operands.push(self.operand()?);
+                self.operand_list(&mut operands)?;
+            }
 
             self.instructions.push(Instruction { label, mnemonic, operands, line, ch });
             return Ok(());
@@ -153,9 +182,15 @@ impl<T: Read> Parser<T> {
         } else if let LexToken::LexDigit(n) = a {
             self.lexer.next();
             Ok(Operand::OpDigit(n))
+        } else if let LexToken::LexFloat(bits) = a {
+            self.lexer.next();
+            Ok(Operand::OpFloat(bits))
+        } else if let LexToken::LexString(bytes) = a {
+            self.lexer.next();
+            Ok(Operand::OpString(bytes))
         } else if let LexToken::LexLabel(label) = a {
             self.lexer.next();
-            Ok(Operand::OpLabel(from_utf8(&label).unwrap().to_string()))
+            Ok(Operand::OpLabel(label))
         } else {
             self.print_err("expected some operands.");
             Err(ParseError::MalformedSentenceError)
@@ -163,13 +198,11 @@ impl<T: Read> Parser<T> {
     }
 
     fn operand_list(&mut self, operands: &mut Vec<Operand>) -> Result<(), ParseError> {
-        let a = self.peek()?;
-        if a == LexToken::LexComma {
+        while self.peek()? == LexToken::LexComma {
             self.lexer.next();
             operands.push(self.operand()?);
-            self.operand_list(operands)
-        } else {
-            Ok(())
         }
+
+        Ok(())
     }
 }
@@ -0,0 +1,90 @@
+//! 命令列の覗き穴最適化 (`-O`)
+//! semantic checkの後、アドレス解決の前に、何もしない命令を取り除く。消した命令のラベルは次の命令に移す
+
+use crate::lexer::Mnemonic::*;
+use crate::lexer::Register;
+use crate::parser::{Instruction, Operand};
+use crate::semantics::{is_conditional_branch, is_conditional_branch_ext};
+
+/// レジスタの値を変えない命令か。nopはハザードを避けるために書いたものかもしれないので残す
+fn is_identity(instr: &Instruction) -> bool {
+    let Instruction { mnemonic: m, operands, .. } = instr;
+    let m = *m;
+    let zero = Operand::OpRegister(Register::Zero);
+    let Some(d) = operands.first() else {
+        return false;
+    };
+
+    if m == Add {
+        // add rX, zero, rX / add rX, rX, zero
+        (operands[1] == zero && operands[2] == *d) || (operands[1] == *d && operands[2] == zero)
+    } else if m == Sub {
+        operands[1] == *d && operands[2] == zero
+    } else if m == Addi || m == Subi || m == Slli {
+        operands[1] == *d && operands[2] == Operand::OpDigit(0)
+    } else if m == Mov || m == Fmov {
+        operands[1] == *d
+    } else {
+        false
+    }
+}
+
+/// 直後の命令へ飛ぶ分岐・ジャンプか。分岐の成否によらず次の命令を実行するので、消しても同じ
+fn jumps_to_next(instr: &Instruction, next: Option<&Instruction>) -> bool {
+    let m = instr.mnemonic;
+    if !(m == J || m == B || m == Lj || is_conditional_branch(m) || is_conditional_branch_ext(m)) {
+        return false;
+    }
+    match (instr.operands.last(), next) {
+        (Some(Operand::OpLabel(target)), Some(next)) => next.label.contains(target),
+        _ => false,
+    }
+}
+
+/// `movl rX, n`の直後の`movh rX, 0`か。movlは上位16bitを0にするので要らない
+/// movhにラベルがあると、そこへ飛んできた場合はmovlを通らないので残す
+fn is_redundant_movh(instr: &Instruction, prev: Option<&Instruction>) -> bool {
+    let Some(prev) = prev else {
+        return false;
+    };
+    instr.mnemonic == Movh && prev.mnemonic == Movl
+        && instr.label.is_empty()
+        && instr.operands.first() == prev.operands.first()
+        && instr.operands.get(1) == Some(&Operand::OpDigit(0))
+}
+
+/// 取り除けるものが無くなるまで繰り返し、取り除いた命令の数を返す
+/// 分岐を消すと、その先の分岐が直後への分岐になることがあるので、1回では終わらない
+pub fn optimize(mut instructions: Vec<Instruction>) -> (Vec<Instruction>, usize) {
+    let mut removed = 0;
+    loop {
+        let mut out: Vec<Instruction> = Vec::with_capacity(instructions.len());
+        // 消した命令のラベル。次に残す命令の前に付ける
+        let mut pending: Vec<String> = vec![];
+        let before = removed;
+        for i in 0..instructions.len() {
+            let instr = &instructions[i];
+            let next = instructions.get(i + 1);
+            // 消した命令のラベルを受け取ると、そこへ飛んできた場合は直前の命令を通らない
+            let prev = if pending.is_empty() { out.last() } else { None };
+            // 最後の命令はラベルを移す先が無いので、ラベルがあれば残す
+            let movable = next.is_some() || (instr.label.is_empty() && pending.is_empty());
+            let redundant = is_identity(instr) || jumps_to_next(instr, next) || is_redundant_movh(instr, prev);
+            if redundant && movable {
+                pending.extend(instr.label.iter().cloned());
+                removed += 1;
+                continue;
+            }
+            let mut instr = instr.clone();
+            if !pending.is_empty() {
+                pending.append(&mut instr.label);
+                instr.label = std::mem::take(&mut pending);
+            }
+            out.push(instr);
+        }
+        instructions = out;
+        if removed == before {
+            return (instructions, removed);
+        }
+    }
+}
@@ -1,22 +1,72 @@
 use std::collections::HashMap;
+use crate::diagnostics::{report, warn, ErrorCode};
 use crate::lexer::Mnemonic::*;
 use crate::lexer::{Mnemonic, Register};
 use crate::parser::{Instruction, Operand};
 use crate::parser::Operand::{OpDigit, OpRegister};
+use crate::encoder::{BRANCH_OFFSET, JUMP_OFFSET, MOV_IMM};
 use crate::semantics::{is_conditional_branch, is_conditional_branch_ext};
 
+/// `call`が戻りアドレスを書き込むレジスタ。`ret`はここへ戻る
+pub const LINK_REGISTER: Register = Register::R(252);
+
+/// `lj`と`lcall`が飛び先の絶対アドレスを読み込むのに使うレジスタ。展開すると値が壊れる
+pub const SCRATCH_REGISTER: Register = Register::R(251);
+
+/// アドレス解決で見つかる、エラーではないが知らせるべきもの
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ResolutionWarning {
+    /// 届かないj/callをlj/lcallに置き換えた。スクラッチレジスタが壊れる
+    RelaxedJump,
+}
+
+impl ErrorCode for ResolutionWarning {
+    fn code(&self) -> &'static str {
+        match self {
+            ResolutionWarning::RelaxedJump => "W0201",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ResolutionWarning::RelaxedJump => "RelaxedJump",
+        }
+    }
+}
+
+/// 1ワードあたりのアドレスの増分。メモリはワード単位でアドレスが振られている
+pub const WORD_SIZE: i64 = 1;
+
+#[derive(Debug)]
 pub enum ResolutionError {
     ImmTooLargeError,
     LabelTooFarError,
 }
 
+impl ErrorCode for ResolutionError {
+    fn code(&self) -> &'static str {
+        match self {
+            ResolutionError::ImmTooLargeError => "E0101",
+            ResolutionError::LabelTooFarError => "E0102",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ResolutionError::ImmTooLargeError => "ImmTooLarge",
+            ResolutionError::LabelTooFarError => "LabelTooFar",
+        }
+    }
+}
+
 
 pub fn is_pseudo_instr(m: Mnemonic) -> bool {
-    match m {
+    matches!(m,
         Libeq | Libne | Liblt | Lible |
-        Lfblt | Lfble | Lfbps | Lfbng => true,
-        _ => false
-    }
+        Lfblt | Lfble | Lfbps | Lfbng | Lif | Ascii |
+        Ret | Push | Pop | Enter | Leave |
+        Nop | Mov | Fmov | Neg | Not | B |
+        Ibgt | Ibge | Fbgt | Fbge | Lj | Lcall)
 }
 
 pub fn neg_pseudo_branch_instr(m: Mnemonic) -> Mnemonic {
@@ -33,40 +83,170 @@ pub fn neg_pseudo_branch_instr(m: Mnemonic) -> Mnemonic {
     }
 }
 
-/// semantic checkが済んだ命令列に対して、最適化をせずに疑似命令を展開し、アドレス解決をする
-/// 出力された命令列にはラベルは含まれない
-pub fn resolve_without_optimization(
-    instructions: Vec<Instruction>
-) -> Result<Vec<Instruction>, ResolutionError> {
-    let mut addr_map = HashMap::new();
-    let mut addr_padding = 0_i64;
+/// レジスタを入れ替えれば既存の命令になる分岐
+pub fn swap_pseudo_branch_instr(m: Mnemonic) -> Mnemonic {
+    match m {
+        Ibgt => Iblt,
+        Ibge => Ible,
+        Fbgt => Fblt,
+        Fbge => Fble,
+        _ => unreachable!()
+    }
+}
 
-    let it = instructions.iter().enumerate();
-    for (address, Instruction { label, mnemonic, .. }) in it {
-        let mnemonic = *mnemonic;
+/// 1命令にそのまま置き換えられる疑似命令を展開する。それ以外はそのまま返す
+fn rewrite_alias(instruction: Instruction) -> Instruction {
+    let Instruction { label, mnemonic, mut operands, line, ch } = instruction;
+    let zero = OpRegister(Register::Zero);
 
-        for s in label {
-            addr_map.insert(s.clone(), address as i64 + addr_padding);
+    let mnemonic = match mnemonic {
+        Nop => {
+            operands = vec![zero.clone(), zero.clone(), zero];
+            Add
+        }
+        // レジスタは整数と浮動小数点数で共通なので、fmovもビット列をそのまま写せば良い
+        Mov | Fmov => {
+            operands.push(zero);
+            Add
         }
+        Neg => {
+            operands.insert(1, zero);
+            Sub
+        }
+        B => {
+            operands.insert(0, zero.clone());
+            operands.insert(0, zero);
+            Ibeq
+        }
+        Ibgt | Ibge | Fbgt | Fbge => {
+            operands.swap(0, 1);
+            swap_pseudo_branch_instr(mnemonic)
+        }
+        _ => mnemonic
+    };
+
+    Instruction { label, mnemonic, operands, line, ch }
+}
+
+/// 届かないj/callをlj/lcallに置き換え、ラベルのアドレスと置き換えた命令の位置を返す
+/// 置き換えると後ろのアドレスがずれるので、置き換えるものが無くなるまでアドレスの計算をやり直す (置き換えは一方向なので必ず止まる)
+fn relax_jumps(instructions: &mut [Instruction]) -> (HashMap<String, i64>, Vec<usize>) {
+    let mut addr_map = HashMap::new();
+    let mut relaxed_at = vec![];
+    loop {
+        addr_map.clear();
+        let mut addr_list = vec![];
+        let mut addr_padding = 0_i64;
+
+        let it = instructions.iter().enumerate();
+        for (address, Instruction { label, mnemonic, operands, .. }) in it {
+            let mnemonic = *mnemonic;
+            addr_list.push(address as i64 + addr_padding);
+
+            for s in label {
+                addr_map.insert(s.clone(), address as i64 + addr_padding);
+            }
+
+            if mnemonic == Libeq || mnemonic == Libne || mnemonic == Liblt || mnemonic == Lible ||
+                mnemonic == Lfblt || mnemonic == Lfble {
+                addr_padding += 1;
+            }
+
+            if mnemonic == Lfbps || mnemonic == Lfbng {
+                addr_padding += 2;
+            }
+
+            if mnemonic == Lif {
+                addr_padding += 1;
+            }
+
+            if mnemonic == Ascii {
+                if let Operand::OpString(bytes) = &operands[0] {
+                    addr_padding += bytes.len() as i64 - 1;
+                }
+            }
+
+            if mnemonic == Push || mnemonic == Pop {
+                addr_padding += operands.len() as i64;
+            }
+
+            if mnemonic == Enter {
+                addr_padding += if operands[0] == OpDigit(0) { 3 } else { 4 };
+            }
+
+            if mnemonic == Leave {
+                addr_padding += 3;
+            }
 
-        if mnemonic == Libeq || mnemonic == Libne || mnemonic == Liblt || mnemonic == Lible ||
-            mnemonic == Lfblt || mnemonic == Lfble {
-            addr_padding += 1;
+            if mnemonic == Not {
+                addr_padding += 1;
+            }
+
+            if mnemonic == Lj {
+                addr_padding += 2;
+            }
+
+            if mnemonic == Lcall {
+                addr_padding += 4;
+            }
         }
 
-        if mnemonic == Lfbps || mnemonic == Lfbng {
-            addr_padding += 2;
+        let mut relaxed = false;
+        for (address, instruction) in instructions.iter_mut().enumerate() {
+            if instruction.mnemonic != J && instruction.mnemonic != Call { continue; }
+            if let Operand::OpLabel(label) = &instruction.operands[0] {
+                let relative_addr = addr_map[label] - addr_list[address];
+                if !JUMP_OFFSET.fits(relative_addr) {
+                    instruction.mnemonic = if instruction.mnemonic == J { Lj } else { Lcall };
+                    relaxed_at.push(address);
+                    relaxed = true;
+                }
+            }
         }
+        if !relaxed { break; }
+    }
+    relaxed_at.sort_unstable();
+    (addr_map, relaxed_at)
+}
+
+/// アドレス解決でlj/lcallに置き換わるj/callを警告し、その数を返す
+/// 置き換えると`SCRATCH_REGISTER`が壊れるので、それを使っているプログラムはプログラムが大きくなったときだけ動かなくなる
+pub fn report_relaxed_jumps(instructions: &[Instruction]) -> usize {
+    let mut instructions: Vec<_> = instructions.iter().cloned().map(rewrite_alias).collect();
+    let (_, relaxed_at) = relax_jumps(&mut instructions);
+    for &address in &relaxed_at {
+        let Instruction { mnemonic, operands, line, ch, .. } = &instructions[address];
+        let Operand::OpLabel(label) = &operands[0] else { continue; };
+        let original = if *mnemonic == Lj { J } else { Call };
+        let message = format!(
+            "{original} to \"{label}\" is out of range and is replaced with {mnemonic}, which overwrites {SCRATCH_REGISTER}.",
+        );
+        warn(*line, *ch, &ResolutionWarning::RelaxedJump, message);
     }
+    relaxed_at.len()
+}
+
+/// semantic checkが済んだ命令列に対して、最適化をせずに疑似命令を展開し、アドレス解決をする
+/// ラベルのオペランドはアドレスに置き換わり、定義したラベルはそのアドレスの命令の`label`に残る
+pub fn resolve_without_optimization(
+    instructions: Vec<Instruction>
+) -> Result<Vec<Instruction>, ResolutionError> {
+    let mut instructions: Vec<_> = instructions.into_iter().map(rewrite_alias).collect();
+    let (addr_map, _) = relax_jumps(&mut instructions);
 
     let mut instr = vec![];
-    for Instruction { mnemonic, mut operands, line, ch, .. } in instructions {
+    // ラベルは展開した最初の命令に付け直す (空の.asciiなら次の命令に付く)
+    let mut labels_at = vec![];
+    for Instruction { label, mnemonic, mut operands, line, ch } in instructions {
+        if !label.is_empty() {
+            labels_at.push((instr.len(), label));
+        }
         // if is_arithmetic_imm(mnemonic) {
         //     if let Operand::OpLabel(s) = &operands[2] {
         //         let dest_addr = *addr_map.get(s).unwrap();
         //         if dest_addr >= 256 {
-        //             println!("at line {line}, character {ch}: Warning");
-        //             println!("the number exceeds the size of 8bit integer.");
+        //             eprintln!("at line {line}, character {ch}: Warning");
+        //             eprintln!("the number exceeds the size of 8bit integer.");
         //             return Err(ResolutionError::ImmTooLargeError);
         //         }
         //
@@ -77,10 +257,8 @@ pub fn resolve_without_optimization(
             if let Operand::OpLabel(label) = &operands[2] {
                 let dest_addr = *addr_map.get(label).unwrap();
                 let relative_addr = dest_addr - instr.len() as i64;
-                if !(-1024 <= relative_addr && relative_addr < 1024) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("label \"{}\" is too far to jump.", label.clone());
-                    return Err(ResolutionError::LabelTooFarError);
+                if !BRANCH_OFFSET.fits(relative_addr) {
+                    return Err(report(line, ch, "Error", ResolutionError::LabelTooFarError, format!("label \"{}\" is too far to jump (offset {relative_addr} exceeds {}).", label.clone(), BRANCH_OFFSET.describe())));
                 }
 
                 operands[2] = Operand::OpDigit(relative_addr);
@@ -89,10 +267,8 @@ pub fn resolve_without_optimization(
             if let Operand::OpLabel(label) = &operands[1] {
                 let dest_addr = *addr_map.get(label).unwrap();
                 let relative_addr = dest_addr - instr.len() as i64;
-                if !(-1024 <= relative_addr && relative_addr < 1024) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("label \"{}\" is too far to jump.", label.clone());
-                    return Err(ResolutionError::LabelTooFarError);
+                if !BRANCH_OFFSET.fits(relative_addr) {
+                    return Err(report(line, ch, "Error", ResolutionError::LabelTooFarError, format!("label \"{}\" is too far to jump (offset {relative_addr} exceeds {}).", label.clone(), BRANCH_OFFSET.describe())));
                 }
 
                 operands[1] = Operand::OpDigit(relative_addr);
@@ -101,11 +277,8 @@ pub fn resolve_without_optimization(
             if let Operand::OpLabel(label) = &operands[0] {
                 let dest_addr = *addr_map.get(label).unwrap();
                 let relative_addr = dest_addr - instr.len() as i64;
-                let lim = 1 << 15;
-                if !(-lim <= relative_addr && relative_addr < lim) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("label \"{}\" is too far to jump (over 32,768 lines).", label.clone());
-                    return Err(ResolutionError::LabelTooFarError);
+                if !JUMP_OFFSET.fits(relative_addr) {
+                    return Err(report(line, ch, "Error", ResolutionError::LabelTooFarError, format!("label \"{}\" is too far to jump (over 32,768 lines).", label.clone())));
                 }
 
                 operands[0] = Operand::OpDigit(relative_addr);
@@ -120,6 +293,10 @@ pub fn resolve_without_optimization(
                 let dest_addr = *addr_map.get(label).unwrap();
                 let relative_addr = dest_addr - instr.len() as i64;
 
+                if !JUMP_OFFSET.fits(relative_addr - 1) {
+                    return Err(report(line, ch, "Error", ResolutionError::LabelTooFarError, format!("label \"{}\" is too far to jump (over 32,768 lines).", label.clone())));
+                }
+
                 let mnemonic = neg_pseudo_branch_instr(mnemonic);
                 (operands[0], operands[1]) = (operands[1].clone(), operands[0].clone());
                 operands[2] = OpDigit(2);
@@ -132,6 +309,10 @@ pub fn resolve_without_optimization(
                 let dest_addr = *addr_map.get(label).unwrap();
                 let relative_addr = dest_addr - instr.len() as i64;
 
+                if !JUMP_OFFSET.fits(relative_addr - 2) {
+                    return Err(report(line, ch, "Error", ResolutionError::LabelTooFarError, format!("label \"{}\" is too far to jump (over 32,768 lines).", label.clone())));
+                }
+
                 let mnemonic = neg_pseudo_branch_instr(mnemonic);
                 let reg_saved = operands[0].clone();
                 operands[1] = OpDigit(3);
@@ -141,14 +322,103 @@ pub fn resolve_without_optimization(
                 instr.push(Instruction { label: vec![], mnemonic: J, operands: vec![OpDigit(relative_addr - 2)], line, ch });
             }
             continue;
+        } else if mnemonic == Lif {
+            // movlで下位16bit、movhで上位16bitを読み込む
+            let bits = match operands[1] {
+                Operand::OpFloat(bits) => bits,
+                Operand::OpDigit(n) => (n as f32).to_bits(),
+                _ => unreachable!()
+            };
+            let reg = operands[0].clone();
+            let operands = vec![reg.clone(), OpDigit((bits & 0xffff) as i64)];
+            instr.push(Instruction { label: vec![], mnemonic: Movl, operands, line, ch });
+            let operands = vec![reg, OpDigit((bits >> 16) as i64)];
+            instr.push(Instruction { label: vec![], mnemonic: Movh, operands, line, ch });
+            continue;
+        } else if mnemonic == Ascii {
+            // 1文字を1ワードに置く
+            if let Operand::OpString(bytes) = &operands[0] {
+                for b in bytes {
+                    let operands = vec![OpDigit(*b as i64)];
+                    instr.push(Instruction { label: vec![], mnemonic: Word, operands, line, ch });
+                }
+            }
+            continue;
+        } else if mnemonic == Not {
+            // 2の補数で ~x = -x - 1
+            let rd = operands[0].clone();
+            let operands = vec![rd.clone(), OpRegister(Register::Zero), operands[1].clone()];
+            instr.push(Instruction { label: vec![], mnemonic: Sub, operands, line, ch });
+            let operands = vec![rd.clone(), rd, OpDigit(1)];
+            instr.push(Instruction { label: vec![], mnemonic: Subi, operands, line, ch });
+            continue;
+        } else if mnemonic == Lj || mnemonic == Lcall {
+            // 絶対アドレスをスクラッチレジスタに読み込んでjrで飛ぶ
+            if let Operand::OpLabel(label) = &operands[0] {
+                let dest_addr = *addr_map.get(label).unwrap();
+                let scratch = OpRegister(SCRATCH_REGISTER);
+                if mnemonic == Lcall {
+                    // 戻りアドレスはjrの次
+                    let ret_addr = instr.len() as i64 + 5;
+                    let lr = OpRegister(LINK_REGISTER);
+                    instr.push(Instruction { label: vec![], mnemonic: Movl, operands: vec![lr.clone(), OpDigit(ret_addr & 0xffff)], line, ch });
+                    instr.push(Instruction { label: vec![], mnemonic: Movh, operands: vec![lr, OpDigit(ret_addr >> 16)], line, ch });
+                }
+                instr.push(Instruction { label: vec![], mnemonic: Movl, operands: vec![scratch.clone(), OpDigit(dest_addr & 0xffff)], line, ch });
+                instr.push(Instruction { label: vec![], mnemonic: Movh, operands: vec![scratch.clone(), OpDigit(dest_addr >> 16)], line, ch });
+                instr.push(Instruction { label: vec![], mnemonic: Jr, operands: vec![scratch], line, ch });
+            }
+            continue;
+        } else if mnemonic == Ret {
+            let operands = vec![OpRegister(LINK_REGISTER)];
+            instr.push(Instruction { label: vec![], mnemonic: Jr, operands, line, ch });
+            continue;
+        } else if mnemonic == Push {
+            // スタックは下位アドレスに向かって伸びる。先に書いたレジスタほど低いアドレスに置く
+            let size = operands.len() as i64 * WORD_SIZE;
+            let sp = OpRegister(Register::Sp);
+            instr.push(Instruction { label: vec![], mnemonic: Subi, operands: vec![sp.clone(), sp.clone(), OpDigit(size)], line, ch });
+            for (i, reg) in operands.into_iter().enumerate() {
+                let operands = vec![reg, sp.clone(), OpDigit(i as i64 * WORD_SIZE)];
+                instr.push(Instruction { label: vec![], mnemonic: Sw, operands, line, ch });
+            }
+            continue;
+        } else if mnemonic == Pop {
+            // pushと同じ並びで書けば、同じレジスタに復元される
+            let size = operands.len() as i64 * WORD_SIZE;
+            let sp = OpRegister(Register::Sp);
+            for (i, reg) in operands.into_iter().enumerate() {
+                let operands = vec![reg, sp.clone(), OpDigit(i as i64 * WORD_SIZE)];
+                instr.push(Instruction { label: vec![], mnemonic: Lw, operands, line, ch });
+            }
+            instr.push(Instruction { label: vec![], mnemonic: Addi, operands: vec![sp.clone(), sp, OpDigit(size)], line, ch });
+            continue;
+        } else if mnemonic == Enter {
+            // fpとリンクレジスタを退避し、fpを新しいフレームの底にしてから局所変数の領域を確保する
+            let (sp, fp) = (OpRegister(Register::Sp), OpRegister(Register::Fp));
+            let (lr, zero) = (OpRegister(LINK_REGISTER), OpRegister(Register::Zero));
+            instr.push(Instruction { label: vec![], mnemonic: Subi, operands: vec![sp.clone(), sp.clone(), OpDigit(2 * WORD_SIZE)], line, ch });
+            instr.push(Instruction { label: vec![], mnemonic: Sw, operands: vec![fp.clone(), sp.clone(), OpDigit(0)], line, ch });
+            instr.push(Instruction { label: vec![], mnemonic: Sw, operands: vec![lr, sp.clone(), OpDigit(WORD_SIZE)], line, ch });
+            instr.push(Instruction { label: vec![], mnemonic: Add, operands: vec![fp, sp.clone(), zero], line, ch });
+            if operands[0] != OpDigit(0) {
+                instr.push(Instruction { label: vec![], mnemonic: Subi, operands: vec![sp.clone(), sp, operands[0].clone()], line, ch });
+            }
+            continue;
+        } else if mnemonic == Leave {
+            // enterの逆順
+            let (sp, fp) = (OpRegister(Register::Sp), OpRegister(Register::Fp));
+            let (lr, zero) = (OpRegister(LINK_REGISTER), OpRegister(Register::Zero));
+            instr.push(Instruction { label: vec![], mnemonic: Add, operands: vec![sp.clone(), fp.clone(), zero], line, ch });
+            instr.push(Instruction { label: vec![], mnemonic: Lw, operands: vec![fp, sp.clone(), OpDigit(0)], line, ch });
+            instr.push(Instruction { label: vec![], mnemonic: Lw, operands: vec![lr, sp.clone(), OpDigit(WORD_SIZE)], line, ch });
+            instr.push(Instruction { label: vec![], mnemonic: Addi, operands: vec![sp.clone(), sp, OpDigit(2 * WORD_SIZE)], line, ch });
+            continue;
         } else if mnemonic == Movl || mnemonic == Movh {
             if let Operand::OpLabel(label) = &operands[1] {
                 let dest_addr = *addr_map.get(label).unwrap();
-                let lim = 1 << 16;
-                if !(0 <= dest_addr && dest_addr < lim) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("label \"{}\" is too large for mov* instruction (over 32,768).", label.clone());
-                    return Err(ResolutionError::LabelTooFarError);
+                if !MOV_IMM.fits(dest_addr) {
+                    return Err(report(line, ch, "Error", ResolutionError::LabelTooFarError, format!("label \"{}\" is too large for mov* instruction (over 65,535).", label.clone())));
                 }
 
                 operands[1] = Operand::OpDigit(dest_addr);
@@ -158,5 +428,11 @@ pub fn resolve_without_optimization(
         instr.push(Instruction { label: vec![], mnemonic, operands, line, ch });
     }
 
+    for (address, label) in labels_at {
+        if let Some(i) = instr.get_mut(address) {
+            i.label.extend(label);
+        }
+    }
+
     Ok(instr)
 }
\ No newline at end of file
@@ -1,7 +1,10 @@
 use std::collections::HashSet;
+use crate::diagnostics::{report, ErrorCode};
+use crate::encoder::{isa, Field, ARITH_IMM, MOV_IMM};
 use crate::lexer::{Mnemonic, Register};
 use crate::lexer::Mnemonic::*;
 use crate::parser::{Instruction, Operand};
+use crate::resolver::{LINK_REGISTER, SCRATCH_REGISTER, WORD_SIZE};
 use crate::semantics::operand_kind::*;
 
 #[derive(Debug)]
@@ -12,34 +15,106 @@ pub enum SemanticError {
     InvalidOperandKindError,
     LabelNotFoundError,
     LabelTooFarError,
+    /// linkしたときに、複数のファイルで同じラベルが定義されている
+    DuplicateLabelError,
 }
 
-mod operand_kind {
+impl ErrorCode for SemanticError {
+    fn code(&self) -> &'static str {
+        match self {
+            SemanticError::InvalidOperandNumError => "E0001",
+            SemanticError::InvalidOperandKindError => "E0002",
+            SemanticError::LabelNotFoundError => "E0003",
+            SemanticError::ImmTooLargeError => "E0004",
+            SemanticError::SubstitutionToZeroError => "E0005",
+            SemanticError::LabelTooFarError => "E0006",
+            SemanticError::DuplicateLabelError => "E0007",
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            SemanticError::InvalidOperandNumError => "InvalidOperandNum",
+            SemanticError::InvalidOperandKindError => "InvalidOperandKind",
+            SemanticError::LabelNotFoundError => "LabelNotFound",
+            SemanticError::ImmTooLargeError => "ImmTooLarge",
+            SemanticError::SubstitutionToZeroError => "SubstitutionToZero",
+            SemanticError::LabelTooFarError => "LabelTooFar",
+            SemanticError::DuplicateLabelError => "DuplicateLabel",
+        }
+    }
+}
+
+pub mod operand_kind {
     pub const REGISTER: u8 = 1;
     pub const LABEL: u8 = 2;
     pub const DIGIT: u8 = 4;
+    pub const FLOAT: u8 = 8;
+    pub const STRING: u8 = 16;
+}
+
+/// `REGISTER | LABEL`のようなオペランドの種類を"a register or a label"のように説明する
+pub fn kind_name(kind: u8) -> String {
+    const KIND_TABLE: [(u8, &str); 5] = [
+        (REGISTER, "a register"), (LABEL, "a label"),
+        (DIGIT, "an immediate value"), (FLOAT, "a floating-point number"),
+        (STRING, "a string"),
+    ];
+    let kinds: Vec<_> = KIND_TABLE.iter()
+        .filter(|(k, _)| kind & k != 0)
+        .map(|(_, s)| *s)
+        .collect();
+    kinds.join(" or ")
+}
+
+/// 命令が取るオペランドの種類。pushとpopはレジスタを1つ以上取る
+pub fn operand_kinds(m: Mnemonic) -> &'static [u8] {
+    if is_arithmetic(m) {
+        if is_arithmetic_imm(m) { &[REGISTER, REGISTER, DIGIT] } else { &[REGISTER, REGISTER, REGISTER] }
+    } else if is_arithmetic_ext(m) {
+        &[REGISTER, REGISTER]
+    } else if is_conditional_branch(m) {
+        &[REGISTER, REGISTER, LABEL]
+    } else if is_conditional_branch_ext(m) {
+        &[REGISTER, LABEL]
+    } else if m == J || m == Call || m == B || m == Lj || m == Lcall {
+        &[LABEL]
+    } else if m == Jr || m == Usend || m == Urecv || m == Push || m == Pop {
+        &[REGISTER]
+    } else if m == Lw || m == Sw {
+        &[REGISTER, REGISTER, DIGIT]
+    } else if m == Lif {
+        // 整数を書いた場合は浮動小数点数に変換して読み込む
+        &[REGISTER, FLOAT | DIGIT]
+    } else if m == Ascii {
+        &[STRING]
+    } else if m == Word || m == Enter {
+        &[DIGIT]
+    } else if m == Movl || m == Movh {
+        &[REGISTER, DIGIT | LABEL]
+    } else {
+        // ret, leave, nop
+        &[]
+    }
 }
 
 fn confirm(
-    operands: &Vec<Operand>, kinds: &Vec<u8>, labels: &HashSet<String>,
+    operands: &[Operand], kinds: &[u8], labels: &HashSet<String>,
     line: usize, ch: usize,
 ) -> Result<(), SemanticError> {
     if operands.len() != kinds.len() {
-        println!("at line {line}, character {ch}: Syntax Error");
-        println!("the number of operands must be {}.", kinds.len());
-        return Err(SemanticError::InvalidOperandNumError);
+        return Err(report(line, ch, "Syntax Error", SemanticError::InvalidOperandNumError, format!("the number of operands must be {}.", kinds.len())));
     }
 
-    fn print_err(operand_pos: usize, kind: u8, line: usize, ch: usize) {
+    fn kind_message(operand_pos: usize, kind: u8) -> String {
         const POS_TABLE: [&str; 4] = ["first", "second", "third", "fourth"];
-        const KIND_TABLE: [&str; 8] = [
-            "", "a register", "a label", "a register or a label",
-            "an immediate value", "a register or an immediate value",
-            "a label or an immediate value", ""
-        ];
-
-        println!("at line {line}, character {ch}: Syntax Error");
-        println!("the {} operand must be {}.", POS_TABLE[operand_pos], KIND_TABLE[kind as usize]);
+
+        // pushやpopはオペランドをいくつでも取れる
+        let pos = match POS_TABLE.get(operand_pos) {
+            Some(s) => s.to_string(),
+            None => format!("{}th", operand_pos + 1),
+        };
+        format!("the {pos} operand must be {}.", kind_name(kind))
     }
 
     let it = operands.iter().zip(kinds).enumerate();
@@ -50,56 +125,106 @@ fn confirm(
             if kind & LABEL != 0 {
                 if labels.contains(label) { continue; }
 
-                println!("at line {line}, character {ch}: Syntax Error");
-                println!("label \"{}\" not found.", label.clone());
-                return Err(SemanticError::LabelNotFoundError);
+                return Err(report(line, ch, "Syntax Error", SemanticError::LabelNotFoundError, format!("label \"{}\" not found.", label.clone())));
             }
         } else if let Operand::OpDigit(_) = operand {
             if kind & DIGIT != 0 { continue; }
+        } else if let Operand::OpFloat(_) = operand {
+            if kind & FLOAT != 0 { continue; }
+        } else if let Operand::OpString(_) = operand {
+            if kind & STRING != 0 { continue; }
         }
 
-        print_err(i, *kind, line, ch);
-        return Err(SemanticError::InvalidOperandKindError);
+        return Err(report(line, ch, "Syntax Error", SemanticError::InvalidOperandKindError, kind_message(i, *kind)));
+    }
+
+    Ok(())
+}
+
+/// 即値がエンコード先のフィールドに収まるかを確かめる
+fn check_imm(operand: &Operand, field: Field, line: usize, ch: usize) -> Result<(), SemanticError> {
+    if let Operand::OpDigit(n) = operand {
+        if !field.fits(*n) {
+            return Err(report(line, ch, "Error", SemanticError::ImmTooLargeError, format!("the {} {n} exceeds the size of {}.", field.name, field.describe())));
+        }
     }
 
     Ok(())
 }
 
 pub fn is_arithmetic(m: Mnemonic) -> bool {
-    match m {
+    matches!(m,
         Add | Sub | Addi | Subi | Slli |
-        Fadd | Fsub | Fmul | Fdiv => true,
-        _ => false
-    }
+        Fadd | Fsub | Fmul | Fdiv)
 }
 
 pub fn is_arithmetic_ext(m: Mnemonic) -> bool {
-    match m {
-        Fabs | Fneg | Fsqrt | Itof | Ftoi => true,
-        _ => false
-    }
+    matches!(m,
+        Fabs | Fneg | Fsqrt | Itof | Ftoi |
+        Mov | Fmov | Neg | Not)
 }
 
 pub fn is_arithmetic_imm(m: Mnemonic) -> bool {
-    match m {
-        Addi | Subi | Slli => true,
-        _ => false
-    }
+    matches!(m, Addi | Subi | Slli)
 }
 
 pub fn is_conditional_branch(m: Mnemonic) -> bool {
-    match m {
+    matches!(m,
         Ibeq | Ibne | Ible | Iblt | Fblt | Fble |
-        Libeq | Libne | Lible | Liblt | Lfblt | Lfble => true,
-        _ => false
-    }
+        Libeq | Libne | Lible | Liblt | Lfblt | Lfble |
+        Ibgt | Ibge | Fbgt | Fbge)
 }
 
 pub fn is_conditional_branch_ext(m: Mnemonic) -> bool {
-    match m {
-        Fbps | Fbng | Lfbps | Lfbng => true,
-        _ => false
+    matches!(m, Fbps | Fbng | Lfbps | Lfbng)
+}
+
+/// 最初のオペランドに書き込む命令 (zeroを書き込み先にしても意味が無い)
+pub fn writes_first_operand(m: Mnemonic) -> bool {
+    is_arithmetic(m) || is_arithmetic_ext(m) || m == Urecv || m == Lw || m == Lif
+}
+
+/// 命令が書き込むレジスタ。call (リンクレジスタ) やpush (sp) のような暗黙のものも含む
+pub fn written_registers(instr: &Instruction) -> Vec<Register> {
+    let Instruction { mnemonic: m, operands, .. } = instr;
+    let m = *m;
+    let mut regs = vec![];
+    if writes_first_operand(m) || m == Movl || m == Movh {
+        if let Some(Operand::OpRegister(r)) = operands.first() {
+            regs.push(*r);
+        }
+    } else if m == Pop {
+        regs.extend(operands.iter().filter_map(|o| if let Operand::OpRegister(r) = o { Some(*r) } else { None }));
+    }
+
+    if m == Call || m == Lcall {
+        regs.push(LINK_REGISTER);
+    }
+    if m == Lj || m == Lcall {
+        regs.push(SCRATCH_REGISTER);
+    }
+    if m == Push || m == Pop || m == Enter || m == Leave {
+        regs.push(Register::Sp);
+    }
+    if m == Enter || m == Leave {
+        regs.push(Register::Fp);
     }
+    regs
+}
+
+/// 命令がオペランドとして明示的に読むレジスタ
+pub fn read_registers(instr: &Instruction) -> Vec<Register> {
+    let Instruction { mnemonic: m, operands, .. } = instr;
+    let m = *m;
+    if m == Pop {
+        return vec![];
+    }
+    // movhは書き込み先の下位16bitを残すが、読むとはみなさない
+    let skip = if writes_first_operand(m) || m == Movl || m == Movh { 1 } else { 0 };
+    operands.iter()
+        .skip(skip)
+        .filter_map(|o| if let Operand::OpRegister(r) = o { Some(*r) } else { None })
+        .collect()
 }
 
 pub fn check_semantics(
@@ -108,109 +233,59 @@ pub fn check_semantics(
     for Instruction { mnemonic, operands, line, ch, .. } in instructions {
         let (mnemonic, line, ch) = (*mnemonic, *line, *ch);
 
-        if is_arithmetic(mnemonic) {
-            if is_arithmetic_imm(mnemonic) {
-                confirm(operands, &vec![REGISTER, REGISTER, DIGIT], labels, line, ch)?;
-
-                if let Operand::OpDigit(n) = operands[2] {
-                    if !(0 <= n && n < 256) {
-                        println!("at line {line}, character {ch}: Error");
-                        println!("the number exceeds the size of 8bit integer.");
-                        return Err(SemanticError::ImmTooLargeError);
-                    }
-                }
-            } else {
-                confirm(operands, &vec![REGISTER, REGISTER, REGISTER], labels, line, ch)?;
+        // オペランドの数と種類。pushとpopは数が決まっていないので別に調べる
+        if mnemonic == Push || mnemonic == Pop {
+            if operands.is_empty() {
+                return Err(report(line, ch, "Syntax Error", SemanticError::InvalidOperandNumError, format!("{mnemonic} needs at least one register.")));
             }
+            confirm(operands, &vec![REGISTER; operands.len()], labels, line, ch)?;
+        } else {
+            confirm(operands, operand_kinds(mnemonic), labels, line, ch)?;
+        }
 
-            if let Operand::OpRegister(r) = operands[0] {
-                if let Register::Zero = r {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("substitution to zero register is meaningless.");
-                    return Err(SemanticError::SubstitutionToZeroError);
-                }
-            }
-        } else if is_arithmetic_ext(mnemonic) {
-            confirm(operands, &vec![REGISTER, REGISTER], labels, line, ch)?;
-
-            if let Operand::OpRegister(r) = operands[0] {
-                if let Register::Zero = r {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("substitution to zero register is meaningless.");
-                    return Err(SemanticError::SubstitutionToZeroError);
-                }
-            }
-        } else if is_conditional_branch(mnemonic) {
-            confirm(operands, &vec![REGISTER, REGISTER, LABEL], labels, line, ch)?;
-        } else if is_conditional_branch_ext(mnemonic) {
-            confirm(operands, &vec![REGISTER, LABEL], labels, line, ch)?;
-        } else if mnemonic == J || mnemonic == Call {
-            confirm(operands, &vec![LABEL], labels, line, ch)?;
-            // } else if mnemonic == Ret {
-            //     confirm(operands, &vec![DIGIT], labels, line, ch)?;
-            //
-            //     // immの範囲
-            //     if let Operand::OpDigit(n) = operands[0] {
-            //         if !(-32768 <= n && n < 32768) {
-            //             println!("at line {line}, character {ch}: Error");
-            //             println!("the number exceeds the size of 16bit integer.");
-            //             return Err(SemanticError::ImmTooLargeError);
-            //         }
-            //     }
-        } else if mnemonic == Jr || mnemonic == Usend {
-            confirm(operands, &vec![REGISTER], labels, line, ch)?;
-        } else if mnemonic == Urecv {
-            confirm(operands, &vec![REGISTER], labels, line, ch)?;
-
-            // zeroレジスタ
-            if let Operand::OpRegister(r) = operands[0] {
-                if let Register::Zero = r {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("substitution to zero register is meaningless.");
-                    return Err(SemanticError::SubstitutionToZeroError);
-                }
-            }
+        // immの範囲
+        if is_arithmetic_imm(mnemonic) {
+            check_imm(&operands[2], ARITH_IMM, line, ch)?;
         } else if mnemonic == Lw {
-            confirm(operands, &vec![REGISTER, REGISTER, DIGIT], labels, line, ch)?;
-
-            // immの範囲
-            if let Operand::OpDigit(n) = operands[2] {
-                if !(0 <= n && n < 256) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("the number exceeds the size of 16bit integer.");
-                    return Err(SemanticError::ImmTooLargeError);
+            check_imm(&operands[2], isa().lw_offset(), line, ch)?;
+        } else if mnemonic == Sw {
+            check_imm(&operands[2], isa().sw_offset(), line, ch)?;
+        } else if mnemonic == Movl || mnemonic == Movh {
+            check_imm(&operands[1], MOV_IMM, line, ch)?;
+        } else if mnemonic == Enter {
+            // フレームの大きさはsubiの即値になる
+            check_imm(&operands[0], ARITH_IMM, line, ch)?;
+        } else if mnemonic == Word {
+            // 符号付きでも符号無しでも良い
+            if let Operand::OpDigit(n) = operands[0] {
+                if !(-(1 << 31)..(1 << 32)).contains(&n) {
+                    return Err(report(line, ch, "Error", SemanticError::ImmTooLargeError, "the number exceeds the size of 32bit integer."));
                 }
             }
-
-            // zeroレジスタ
-            if let Operand::OpRegister(r) = operands[0] {
-                if let Register::Zero = r {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("substitution to zero register is meaningless.");
-                    return Err(SemanticError::SubstitutionToZeroError);
+        } else if mnemonic == Push || mnemonic == Pop {
+            // spを退避・復元するとアドレスがずれる
+            for operand in operands {
+                if let Operand::OpRegister(r) = operand {
+                    if *r == Register::Sp {
+                        return Err(report(line, ch, "Error", SemanticError::InvalidOperandKindError, format!("sp cannot be used with {mnemonic}.")));
+                    }
+                    if mnemonic == Pop && *r == Register::Zero {
+                        return Err(report(line, ch, "Error", SemanticError::SubstitutionToZeroError, "substitution to zero register is meaningless."));
+                    }
                 }
             }
-        } else if mnemonic == Sw {
-            confirm(operands, &vec![REGISTER, REGISTER, DIGIT], labels, line, ch)?;
-
-            // immの範囲
-            if let Operand::OpDigit(n) = operands[2] {
-                if !(0 <= n && n < 256) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("the number exceeds the size of 16bit integer.");
-                    return Err(SemanticError::ImmTooLargeError);
-                }
+
+            // spの増減はsubi/addiの即値になる
+            let size = operands.len() as i64 * WORD_SIZE;
+            if !ARITH_IMM.fits(size) {
+                return Err(report(line, ch, "Error", SemanticError::ImmTooLargeError, format!("too many registers for {mnemonic} (the stack adjustment {size} exceeds {}).", ARITH_IMM.describe())));
             }
-        } else if mnemonic == Movl || mnemonic == Movh {
-            confirm(operands, &vec![REGISTER, DIGIT | LABEL], labels, line, ch)?;
-
-            // immの範囲
-            if let Operand::OpDigit(n) = operands[1] {
-                if !(0 <= n && n < 65536) {
-                    println!("at line {line}, character {ch}: Error");
-                    println!("the number exceeds the size of 16bit integer.");
-                    return Err(SemanticError::ImmTooLargeError);
-                }
+        }
+
+        // zeroレジスタ
+        if writes_first_operand(mnemonic) {
+            if let Operand::OpRegister(Register::Zero) = operands[0] {
+                return Err(report(line, ch, "Error", SemanticError::SubstitutionToZeroError, "substitution to zero register is meaningless."));
             }
         }
     }
@@ -0,0 +1,298 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use crate::disassembler::disassemble_word;
+use crate::encoder::get_register_num;
+use crate::lexer::Mnemonic::*;
+use crate::lexer::{Mnemonic, Register};
+use crate::parser::Operand;
+use crate::resolver::LINK_REGISTER;
+use crate::timing::{Timing, TimingModel};
+use crate::uart::{Direction, StreamUart, Uart, UartEvent};
+
+/// 既定のメモリの大きさ (ワード数)
+pub const DEFAULT_MEMORY_WORDS: usize = 1 << 20;
+
+#[derive(Debug)]
+pub enum SimError {
+    /// どの命令にも当てはまらないワードを実行しようとした
+    InvalidInstructionError { pc: u32, word: u32 },
+    /// lw/swのアドレス、またはpcがメモリの外を指している
+    MemoryOutOfRangeError { pc: u32, address: i64 },
+    /// urecvで読むものが無い
+    UartEofError { pc: u32 },
+    /// urecvで待っても入力が届かなかった
+    UartTimeoutError { pc: u32 },
+    StepLimitError { steps: u64 },
+    IoError(io::Error),
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SimError::InvalidInstructionError { pc, word } => {
+                write!(f, "at address {pc}: Runtime Error\n{word:08x} is not a valid instruction.")
+            }
+            SimError::MemoryOutOfRangeError { pc, address } => {
+                write!(f, "at address {pc}: Runtime Error\naddress {address} is out of memory.")
+            }
+            SimError::UartEofError { pc } => {
+                write!(f, "at address {pc}: Runtime Error\nurecv reached the end of the input.")
+            }
+            SimError::UartTimeoutError { pc } => {
+                write!(f, "at address {pc}: Runtime Error\nurecv timed out waiting for input.")
+            }
+            SimError::StepLimitError { steps } => {
+                write!(f, "Runtime Error\nthe program did not halt within {steps} instructions.")
+            }
+            SimError::IoError(e) => write!(f, "Runtime Error\n{e}"),
+        }
+    }
+}
+
+/// 1命令を実行した結果
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StepResult {
+    Continue,
+    /// 自分自身へのジャンプ (`j 0`) は停止とみなす
+    Halt,
+}
+
+/// デコード済みの命令。レジスタは番号、即値はそのままの値で持つ
+#[derive(Debug, Clone, Copy)]
+struct Decoded {
+    mnemonic: Mnemonic,
+    ops: [i64; 3],
+}
+
+fn decode(word: u32) -> Option<Decoded> {
+    let (mnemonic, operands) = disassemble_word(word)?;
+    let mut ops = [0; 3];
+    for (op, operand) in ops.iter_mut().zip(&operands) {
+        *op = match operand {
+            Operand::OpRegister(r) => get_register_num(*r) as i64,
+            Operand::OpDigit(n) => *n,
+            _ => unreachable!(),
+        };
+    }
+    Some(Decoded { mnemonic, ops })
+}
+
+/// 命令セットシミュレータ。命令とデータは同じメモリに置かれ、プログラムは0番地から読み込まれる
+pub struct Machine {
+    pub regs: [u32; 256],
+    pub memory: Vec<u32>,
+    pub pc: u32,
+    /// 実行した命令の数
+    pub steps: u64,
+    /// デコード結果のキャッシュ。swで書き換えられたら捨てる
+    decoded: Vec<Option<Decoded>>,
+    uart: Box<dyn Uart>,
+    /// UARTの送受信の記録。`record_transcript`で記録を始める
+    pub transcript: Option<Vec<UartEvent>>,
+    /// サイクル数の見積もり。`enable_timing`で数え始める
+    pub timing: Option<Timing>,
+}
+
+impl Machine {
+    /// urecvは`input`から読み、usendは`output`に書く
+    pub fn new(program: &[u32], memory_words: usize, input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self::with_uart(program, memory_words, Box::new(StreamUart::new(input, output)))
+    }
+
+    /// spとfpはメモリの末尾を指した状態で始まる
+    pub fn with_uart(program: &[u32], memory_words: usize, uart: Box<dyn Uart>) -> Self {
+        let mut memory = vec![0; memory_words.max(program.len())];
+        memory[..program.len()].copy_from_slice(program);
+
+        let mut machine = Self {
+            regs: [0; 256],
+            decoded: vec![None; memory.len()],
+            memory,
+            pc: 0,
+            steps: 0,
+            uart,
+            transcript: None,
+            timing: None,
+        };
+        let top = machine.memory.len() as u32;
+        machine.set_reg(Register::Sp, top);
+        machine.set_reg(Register::Fp, top);
+        machine
+    }
+
+    pub fn record_transcript(&mut self) {
+        self.transcript.get_or_insert_with(Vec::new);
+    }
+
+    pub fn enable_timing(&mut self, model: TimingModel) {
+        self.timing = Some(Timing::new(model));
+    }
+
+    fn record(&mut self, direction: Direction, byte: u8) {
+        let step = self.steps;
+        if let Some(events) = &mut self.transcript {
+            events.push(UartEvent { step, direction, byte });
+        }
+    }
+
+    pub fn reg(&self, r: Register) -> u32 {
+        self.regs[get_register_num(r) as usize]
+    }
+
+    pub fn set_reg(&mut self, r: Register, value: u32) {
+        self.write(get_register_num(r) as i64, value);
+    }
+
+    fn read(&self, r: i64) -> u32 {
+        self.regs[r as usize]
+    }
+
+    /// zeroへの書き込みは捨てる
+    fn write(&mut self, r: i64, value: u32) {
+        if r != get_register_num(Register::Zero) as i64 {
+            self.regs[r as usize] = value;
+        }
+    }
+
+    /// メモリに書き込む。命令を書き換えた場合に備えて、デコード結果を捨てる
+    pub fn store(&mut self, address: usize, value: u32) {
+        self.memory[address] = value;
+        self.decoded[address] = None;
+    }
+
+    fn address(&self, base: i64, offset: i64) -> Result<usize, SimError> {
+        let address = self.read(base) as i64 + offset;
+        if !(0 <= address && address < self.memory.len() as i64) {
+            return Err(SimError::MemoryOutOfRangeError { pc: self.pc, address });
+        }
+        Ok(address as usize)
+    }
+
+    /// pcの指す命令を1つ実行する
+    pub fn step(&mut self) -> Result<StepResult, SimError> {
+        let pc = self.pc;
+        let index = pc as usize;
+        if index >= self.memory.len() {
+            return Err(SimError::MemoryOutOfRangeError { pc, address: pc as i64 });
+        }
+        let Decoded { mnemonic: m, ops: [a, b, c] } = match self.decoded[index] {
+            Some(d) => d,
+            None => {
+                let word = self.memory[index];
+                let d = decode(word).ok_or(SimError::InvalidInstructionError { pc, word })?;
+                self.decoded[index] = Some(d);
+                d
+            }
+        };
+
+        let f = |x: u32| f32::from_bits(x);
+        let mut next = pc.wrapping_add(1);
+
+        if m == Add {
+            self.write(a, self.read(b).wrapping_add(self.read(c)));
+        } else if m == Sub {
+            self.write(a, self.read(b).wrapping_sub(self.read(c)));
+        } else if m == Addi {
+            self.write(a, self.read(b).wrapping_add(c as u32));
+        } else if m == Subi {
+            self.write(a, self.read(b).wrapping_sub(c as u32));
+        } else if m == Slli {
+            self.write(a, self.read(b).checked_shl(c as u32).unwrap_or(0));
+        } else if m == Fadd {
+            self.write(a, (f(self.read(b)) + f(self.read(c))).to_bits());
+        } else if m == Fsub {
+            self.write(a, (f(self.read(b)) - f(self.read(c))).to_bits());
+        } else if m == Fmul {
+            self.write(a, (f(self.read(b)) * f(self.read(c))).to_bits());
+        } else if m == Fdiv {
+            self.write(a, (f(self.read(b)) / f(self.read(c))).to_bits());
+        } else if m == Fabs {
+            self.write(a, self.read(b) & 0x7fffffff);
+        } else if m == Fneg {
+            self.write(a, self.read(b) ^ 0x80000000);
+        } else if m == Fsqrt {
+            self.write(a, f(self.read(b)).sqrt().to_bits());
+        } else if m == Itof {
+            self.write(a, (self.read(b) as i32 as f32).to_bits());
+        } else if m == Ftoi {
+            // 最近接の整数に丸める
+            self.write(a, f(self.read(b)).round() as i32 as u32);
+        } else if m == Ibeq || m == Ibne || m == Iblt || m == Ible || m == Fblt || m == Fble {
+            let (x, y) = (self.read(a), self.read(b));
+            let taken = match m {
+                Ibeq => x == y,
+                Ibne => x != y,
+                Iblt => (x as i32) < (y as i32),
+                Ible => (x as i32) <= (y as i32),
+                Fblt => f(x) < f(y),
+                _ => f(x) <= f(y),
+            };
+            if taken { next = pc.wrapping_add(c as u32); }
+        } else if m == Fbps || m == Fbng {
+            let x = f(self.read(a));
+            let taken = if m == Fbps { x > 0.0 } else { x < 0.0 };
+            if taken { next = pc.wrapping_add(b as u32); }
+        } else if m == J {
+            next = pc.wrapping_add(a as u32);
+        } else if m == Call {
+            self.set_reg(LINK_REGISTER, pc.wrapping_add(1));
+            next = pc.wrapping_add(a as u32);
+        } else if m == Jr {
+            next = self.read(a);
+        } else if m == Movl {
+            // movlは上位16bitを0にし、movhは下位16bitを残す
+            self.write(a, b as u32);
+        } else if m == Movh {
+            self.write(a, (b as u32) << 16 | (self.read(a) & 0xffff));
+        } else if m == Lw {
+            let address = self.address(b, c)?;
+            self.write(a, self.memory[address]);
+        } else if m == Sw {
+            let address = self.address(b, c)?;
+            self.store(address, self.read(a));
+        } else if m == Urecv {
+            match self.uart.recv() {
+                Ok(Some(byte)) => {
+                    self.record(Direction::Recv, byte);
+                    self.write(a, byte as u32);
+                }
+                Ok(None) => { return Err(SimError::UartEofError { pc }); }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => { return Err(SimError::UartTimeoutError { pc }); }
+                Err(e) => { return Err(SimError::IoError(e)); }
+            }
+        } else if m == Usend {
+            let byte = self.read(a) as u8;
+            self.record(Direction::Send, byte);
+            self.uart.send(byte).map_err(SimError::IoError)?;
+        }
+
+        if let Some(timing) = &mut self.timing {
+            timing.account(m, self.memory[index], next != pc.wrapping_add(1));
+        }
+        self.steps += 1;
+        self.pc = next;
+        if next == pc {
+            return Ok(StepResult::Halt);
+        }
+        Ok(StepResult::Continue)
+    }
+
+    /// 停止するまで実行する。`max_steps`を超えたらエラーにする
+    pub fn run(&mut self, max_steps: Option<u64>) -> Result<(), SimError> {
+        let res = loop {
+            if let Some(max) = max_steps {
+                if self.steps >= max {
+                    break Err(SimError::StepLimitError { steps: max });
+                }
+            }
+            match self.step() {
+                Ok(StepResult::Continue) => {}
+                Ok(StepResult::Halt) => { break Ok(()); }
+                Err(e) => { break Err(e); }
+            }
+        };
+        // エラーで止まった場合も、それまでの出力は書き出す
+        self.uart.flush().map_err(SimError::IoError)?;
+        res
+    }
+}
@@ -0,0 +1,334 @@
+use std::borrow::Cow;
+use crate::lexer::{lookup_keyword, number_token, print_syntax_error, quoted_token, LexItem, LexToken, Mnemonic, NumberKind, Register, SyntaxError};
+
+/// `SliceLexer`が返すトークン。ラベルは入力を借用するのでアロケーションが起きない
+/// 文字列もエスケープシーケンスを含まなければ入力を借用する
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Token<'a> {
+    Mnemonic(Mnemonic),
+    Register(Register),
+    Digit(i64),
+    Float(u32),
+    Str(Cow<'a, [u8]>),
+    Label(&'a str),
+    Colon,
+    Comma,
+    Newline,
+    Eof,
+    Semicolon,
+    /// `#`から行末 (改行の手前) まで。トリビアを残すモードでだけ返す
+    Comment(&'a [u8]),
+    /// 空白とタブの並び。トリビアを残すモードでだけ返す
+    Whitespace(&'a str),
+}
+
+/// トークンの入力中の位置。start, endはバイト単位のオフセットで、line, characterは開始位置
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Token<'_> {
+    /// コメントと空白
+    pub fn is_trivia(&self) -> bool {
+        matches!(self, Token::Comment(_) | Token::Whitespace(_))
+    }
+
+    /// `Parser`が読むトークンにする。トリビアはNone
+    pub fn to_lex_token(self) -> Option<LexToken> {
+        let token = match self {
+            Token::Mnemonic(m) => LexToken::LexMnemonic(m),
+            Token::Register(r) => LexToken::LexRegister(r),
+            Token::Digit(n) => LexToken::LexDigit(n),
+            Token::Float(bits) => LexToken::LexFloat(bits),
+            Token::Str(bytes) => LexToken::LexString(bytes.into_owned()),
+            Token::Label(s) => LexToken::LexLabel(s.to_string()),
+            Token::Colon => LexToken::LexColon,
+            Token::Comma => LexToken::LexComma,
+            Token::Newline => LexToken::LexNewline,
+            Token::Eof => LexToken::LexEof,
+            Token::Semicolon => LexToken::LexSemicolon,
+            Token::Comment(_) | Token::Whitespace(_) => { return None; }
+        };
+        Some(token)
+    }
+}
+
+/// メモリ上に読み込み済みのソースを字句解析する。`Lexer`と同じトークン列を返すが、
+/// 1バイトずつ`Read`から読む代わりにスライスを直接走査するので、巨大な入力でも速い
+pub struct SliceLexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    pub line: usize,
+    pub character: usize,
+    /// コメントと空白もトークンとして返す
+    trivia: bool,
+}
+
+impl<'a> SliceLexer<'a> {
+    pub fn new(src: &'a [u8]) -> Self {
+        Self { src, pos: 0, line: 1, character: 1, trivia: false }
+    }
+
+    /// コメントと空白も`Token::Comment`, `Token::Whitespace`として返すLexerを作る
+    /// Eofまでのトークンのspanをつなげると入力全体になるので、整形やコメント付きの出力に使える
+    pub fn with_trivia(src: &'a [u8]) -> Self {
+        Self { trivia: true, ..Self::new(src) }
+    }
+
+    /// `Parser`に渡せる形に変換する。ラベルの文字列はここで初めて確保される
+    /// トリビアは読み飛ばすので、どちらのモードのLexerからでも同じトークン列になる
+    pub fn tokens(self) -> impl Iterator<Item = LexItem> + 'a {
+        self.filter_map(|res| match res {
+            Ok((token, span)) => token.to_lex_token().map(|t| Ok((t, span.line, span.character))),
+            Err(e) => Some(Err(e)),
+        })
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn consume(&mut self) {
+        self.pos += 1;
+        self.character += 1;
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(a) = self.peek() {
+            if a == b'#' {
+                while let Some(a) = self.peek() {
+                    if a == b'\n' { return; }
+                    self.consume();
+                }
+                return;
+            }
+
+            if !(a == b' ' || a == b'\t') { return; }
+
+            self.consume();
+        }
+    }
+
+    /// 条件を満たすバイトが続く限り読み進め、読んだ範囲を返す
+    fn take_while(&mut self, f: impl Fn(u8) -> bool) -> &'a [u8] {
+        let src = self.src;
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.consume();
+        }
+        &src[start..self.pos]
+    }
+
+    fn get_digit(&mut self) -> Result<Token<'a>, SyntaxError> {
+        let is_minus = self.peek() == Some(b'-');
+        if is_minus {
+            self.consume();
+            self.take_while(|a| a == b' ' || a == b'\t');
+            if !self.peek().is_some_and(|a| a.is_ascii_digit()) {
+                return Err(SyntaxError::MalformedTokenError);
+            }
+        }
+
+        let first = self.peek().unwrap();
+        self.consume();
+
+        let (kind, digits) = match self.peek() {
+            Some(b'x') | Some(b'b') | Some(b'f') if first != b'0' => {
+                return Err(SyntaxError::MalformedTokenError);
+            }
+            Some(b'x') => {
+                self.consume();
+                (NumberKind::Hex, self.take_while(|a| a.is_ascii_hexdigit()))
+            }
+            Some(b'b') => {
+                self.consume();
+                (NumberKind::Binary, self.take_while(|a| a == b'0' || a == b'1'))
+            }
+            Some(b'f') => {
+                self.consume();
+                (NumberKind::FloatBits, self.take_while(|a| a.is_ascii_hexdigit()))
+            }
+            _ => {
+                self.pos -= 1;
+                self.character -= 1;
+                let start = self.pos;
+                self.take_while(|a| a.is_ascii_digit());
+
+                let mut kind = NumberKind::Decimal;
+                if self.peek() == Some(b'.') {
+                    self.consume();
+                    if self.take_while(|a| a.is_ascii_digit()).is_empty() {
+                        return Err(SyntaxError::MalformedTokenError);
+                    }
+                    kind = NumberKind::Float;
+                }
+                if let Some(b'e' | b'E') = self.peek() {
+                    self.consume();
+                    if let Some(b'+' | b'-') = self.peek() {
+                        self.consume();
+                    }
+                    if self.take_while(|a| a.is_ascii_digit()).is_empty() {
+                        return Err(SyntaxError::MalformedTokenError);
+                    }
+                    kind = NumberKind::Float;
+                }
+                (kind, &self.src[start..self.pos])
+            }
+        };
+
+        match number_token(kind, digits, is_minus)? {
+            LexToken::LexFloat(bits) => Ok(Token::Float(bits)),
+            LexToken::LexDigit(n) => Ok(Token::Digit(n)),
+            _ => unreachable!(),
+        }
+    }
+
+    fn get_quoted(&mut self) -> Result<Token<'a>, SyntaxError> {
+        let quote = self.peek().unwrap();
+        self.consume();
+
+        let src = self.src;
+        let start = self.pos;
+        loop {
+            match self.peek() {
+                None | Some(b'\n') => { return Err(SyntaxError::UnterminatedLiteralError); }
+                Some(a) if a == quote => { break; }
+                Some(b'\\') => {
+                    self.consume();
+                    if let None | Some(b'\n') = self.peek() {
+                        return Err(SyntaxError::UnterminatedLiteralError);
+                    }
+                    self.consume();
+                }
+                Some(a) => {
+                    self.pos += 1;
+                    // UTF-8の継続バイトは文字数に数えない
+                    if a & 0xc0 != 0x80 { self.character += 1; }
+                }
+            }
+        }
+        let raw = &src[start..self.pos];
+        self.consume();
+
+        if quote == b'"' && !raw.contains(&b'\\') {
+            return Ok(Token::Str(Cow::Borrowed(raw)));
+        }
+        match quoted_token(quote, raw)? {
+            LexToken::LexString(bytes) => Ok(Token::Str(Cow::Owned(bytes))),
+            LexToken::LexDigit(n) => Ok(Token::Digit(n)),
+            _ => unreachable!(),
+        }
+    }
+
+    /// ASCII以外の文字を1文字分読み、UTF-8としてデコードする
+    fn get_non_ascii_char(&mut self) -> Result<char, SyntaxError> {
+        let a = self.peek().unwrap();
+        let len = match a.leading_ones() {
+            n @ 2..=4 => n as usize,
+            _ => {
+                self.consume();
+                return Err(SyntaxError::InvalidUtf8Error);
+            }
+        };
+
+        // 途中で継続バイトでなくなったら、そこまでで止める (後ろのASCII文字を読み込まないように)
+        let mut end = self.pos + 1;
+        while end < self.pos + len && self.src.get(end).is_some_and(|a| a & 0xc0 == 0x80) {
+            end += 1;
+        }
+        let res = std::str::from_utf8(&self.src[self.pos..end]);
+        self.pos = end;
+        self.character += 1;
+
+        match res {
+            Ok(s) => Ok(s.chars().next().unwrap()),
+            Err(_) => Err(SyntaxError::InvalidUtf8Error),
+        }
+    }
+
+    /// トリビアを残すモードで、空白かコメントがあれば読む
+    fn next_trivia(&mut self) -> Option<Token<'a>> {
+        match self.peek()? {
+            b' ' | b'\t' => {
+                let s = self.take_while(|a| a == b' ' || a == b'\t');
+                // 空白とタブしか含まないのでUTF-8として正しい
+                Some(Token::Whitespace(std::str::from_utf8(s).unwrap()))
+            }
+            b'#' => Some(Token::Comment(self.take_while(|a| a != b'\n'))),
+            _ => None,
+        }
+    }
+
+    fn next_token(&mut self) -> Result<Token<'a>, SyntaxError> {
+        let a = match self.peek() {
+            Some(a) => a,
+            None => { return Ok(Token::Eof); }
+        };
+
+        let token = match a {
+            b':' => Token::Colon,
+            b',' => Token::Comma,
+            b';' => Token::Semicolon,
+            b'\n' => Token::Newline,
+            b'-' | b'0'..=b'9' => { return self.get_digit(); }
+            b'\'' | b'"' => { return self.get_quoted(); }
+            // '.'で始まるものはディレクティブ
+            b'A'..=b'Z' | b'a'..=b'z' | b'_' | b'.' => {
+                self.consume();
+                let start = self.pos - 1;
+                self.take_while(|a| a.is_ascii_alphanumeric() || a == b'_');
+                let ident = &self.src[start..self.pos];
+                // 英数字と'_', '.'しか含まないのでUTF-8として正しい
+                let ident = std::str::from_utf8(ident).unwrap();
+                return match lookup_keyword(ident.as_bytes()) {
+                    Some(LexToken::LexMnemonic(m)) => Ok(Token::Mnemonic(m)),
+                    Some(LexToken::LexRegister(r)) => Ok(Token::Register(r)),
+                    _ if a == b'.' => Err(SyntaxError::UnknownDirectiveError(ident.to_string())),
+                    _ => Ok(Token::Label(ident)),
+                };
+            }
+            _ if !a.is_ascii() => {
+                let c = self.get_non_ascii_char()?;
+                return Err(SyntaxError::InvalidCharacterError(c));
+            }
+            _ => {
+                self.consume();
+                return Err(SyntaxError::InvalidCharacterError(a as char));
+            }
+        };
+
+        self.pos += 1;
+        if token == Token::Newline {
+            self.line += 1;
+            self.character = 1;
+        } else {
+            self.character += 1;
+        }
+
+        Ok(token)
+    }
+}
+
+impl<'a> Iterator for SliceLexer<'a> {
+    type Item = Result<(Token<'a>, Span), SyntaxError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.trivia {
+            self.skip_space();
+        }
+
+        let (start, line, character) = (self.pos, self.line, self.character);
+        let trivia = if self.trivia { self.next_trivia() } else { None };
+        match trivia.map(Ok).unwrap_or_else(|| self.next_token()) {
+            Ok(token) => Some(Ok((token, Span { start, end: self.pos, line, character }))),
+            Err(e) => {
+                print_syntax_error(line, character, self.character, &e);
+                Some(Err(e))
+            }
+        }
+    }
+}
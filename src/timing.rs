@@ -0,0 +1,230 @@
+//! シミュレータで実行した命令列のサイクル数の見積もり
+//! インオーダーのパイプラインで1サイクルに1命令ずつ発行し、結果の待ち・分岐・UARTの転送でだけ止まるとみなす
+
+use std::collections::HashMap;
+use std::fmt;
+use crate::disassembler::disassemble_word;
+use crate::encoder::get_register_num;
+use crate::hazard::{json_count, json_object, machine_reads, HazardModel};
+use crate::lexer::Mnemonic::*;
+use crate::lexer::{Mnemonic, Register};
+use crate::parser::Instruction;
+use crate::semantics::written_registers;
+
+/// 対象のコアのタイミング
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct TimingModel {
+    /// パイプラインの段数。最後の命令を発行してから、これだけ経って終わる
+    pub pipeline_depth: usize,
+    /// 命令ごとのレイテンシとフォワーディングの有無。遅延スロットは使わない
+    pub hazards: HazardModel,
+    /// 分岐が成立したときと、ジャンプで捨てるサイクル数
+    pub branch_penalty: usize,
+    /// UARTで1バイトを送受信するのにかかるサイクル数。0なら待たない
+    pub uart_cycles_per_byte: u64,
+}
+
+impl Default for TimingModel {
+    /// 5段のパイプラインで、分岐の成立はEXで分かる
+    fn default() -> Self {
+        Self { pipeline_depth: 5, hazards: HazardModel::default(), branch_penalty: 2, uart_cycles_per_byte: 0 }
+    }
+}
+
+impl TimingModel {
+    /// JSONで書いた設定を読む。書かなかった項目は既定値のまま
+    /// `HazardModel`の項目も書ける。UARTの速さはクロック周波数とボーレートから計算する (1バイトは10ビット)
+    ///
+    /// `{"pipeline_depth": 5, "branch_penalty": 2, "clock_hz": 10000000, "baud_rate": 115200, "latency": {"fdiv": 10}}`
+    pub fn from_json(src: &str) -> Result<Self, String> {
+        let mut model = Self::default();
+        let (mut clock_hz, mut baud_rate) = (None, None);
+        for (key, v) in json_object(src, "timing model")? {
+            match key.as_str() {
+                "pipeline_depth" => {
+                    model.pipeline_depth = json_count(&key, &v)?;
+                    if model.pipeline_depth == 0 {
+                        return Err("\"pipeline_depth\" must be at least 1.".to_string());
+                    }
+                }
+                "branch_penalty" => model.branch_penalty = json_count(&key, &v)?,
+                "clock_hz" => clock_hz = Some(json_count(&key, &v)? as u64),
+                "baud_rate" => baud_rate = Some(json_count(&key, &v)? as u64),
+                _ => {
+                    if !model.hazards.set_json(&key, &v)? {
+                        return Err(format!("unknown key \"{key}\"."));
+                    }
+                }
+            }
+        }
+        match (clock_hz, baud_rate) {
+            (Some(clock), Some(baud)) if baud > 0 => model.uart_cycles_per_byte = (clock * 10).div_ceil(baud),
+            (None, None) => {}
+            _ => { return Err("\"clock_hz\" and a positive \"baud_rate\" must be given together.".to_string()); }
+        }
+        Ok(model)
+    }
+}
+
+/// パイプラインが止まった原因
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum StallCause {
+    /// この命令の結果を待った
+    Data(Mnemonic),
+    /// 分岐の成立、またはジャンプ
+    Branch,
+    /// 前のバイトを送り終わるのを待った
+    UartSend,
+    /// 次のバイトが届くのを待った
+    UartRecv,
+}
+
+impl fmt::Display for StallCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StallCause::Data(m) => write!(f, "waiting for {m}"),
+            StallCause::Branch => write!(f, "taken branch or jump"),
+            StallCause::UartSend => write!(f, "usend"),
+            StallCause::UartRecv => write!(f, "urecv"),
+        }
+    }
+}
+
+/// 機械語の命令が読み書きするレジスタ。ハザードの検出と同じ規則で求める
+#[derive(Debug, Clone, Default)]
+struct Access {
+    reads: Vec<Register>,
+    written: Vec<Register>,
+}
+
+impl Access {
+    fn of(word: u32) -> Self {
+        let Some((mnemonic, operands)) = disassemble_word(word) else { return Self::default(); };
+        let instr = Instruction { label: vec![], mnemonic, operands, line: 0, ch: 0 };
+        Self { reads: machine_reads(&instr), written: written_registers(&instr) }
+    }
+}
+
+/// 実行した命令を1つずつ受け取って、サイクル数を数える
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub model: TimingModel,
+    pub instructions: u64,
+    /// 原因ごとの止まったサイクル数
+    pub stalls: HashMap<StallCause, u64>,
+    /// 次の命令を発行できる最も早いサイクル
+    next_issue: u64,
+    last_issue: u64,
+    /// 直前の命令で分岐したときに、次の命令の発行を遅らせるサイクル数
+    /// 停止したときの最後のジャンプの分は数えない
+    pending_penalty: u64,
+    /// レジスタごとに、結果を読めるようになるサイクルと、書き込んだ命令
+    ready: Vec<(u64, Mnemonic)>,
+    /// 送信が空くサイクル
+    send_free: u64,
+    /// 受け取ったバイトの数。入力はシミュレータの開始から途切れずに届くとみなす
+    received: u64,
+    /// 命令の語ごとの`Access`。実行のたびに逆アセンブルしないように覚えておく
+    accesses: HashMap<u32, Access>,
+}
+
+impl Timing {
+    pub fn new(model: TimingModel) -> Self {
+        Self {
+            model,
+            instructions: 0,
+            stalls: HashMap::new(),
+            next_issue: 0,
+            last_issue: 0,
+            pending_penalty: 0,
+            ready: vec![(0, Add); 256],
+            send_free: 0,
+            received: 0,
+            accesses: HashMap::new(),
+        }
+    }
+
+    fn stall(&mut self, cause: StallCause, cycles: u64) {
+        if cycles > 0 {
+            *self.stalls.entry(cause).or_insert(0) += cycles;
+        }
+    }
+
+    /// 命令を1つ実行したことを記録する。`word`はその命令の機械語で、`taken`は次に実行する命令が直後の命令でないか
+    pub fn account(&mut self, m: Mnemonic, word: u32, taken: bool) {
+        // 借用が重ならないように、いったん取り出す
+        let mut accesses = std::mem::take(&mut self.accesses);
+        let access = accesses.entry(word).or_insert_with(|| Access::of(word));
+        let mut cycle = self.next_issue;
+        if self.pending_penalty > 0 {
+            self.stall(StallCause::Branch, self.pending_penalty);
+            cycle += self.pending_penalty;
+            self.pending_penalty = 0;
+        }
+        for r in &access.reads {
+            let (ready, producer) = self.ready[get_register_num(*r) as usize];
+            if ready > cycle {
+                self.stall(StallCause::Data(producer), ready - cycle);
+                cycle = ready;
+            }
+        }
+
+        let per_byte = self.model.uart_cycles_per_byte;
+        if m == Usend {
+            if self.send_free > cycle {
+                self.stall(StallCause::UartSend, self.send_free - cycle);
+                cycle = self.send_free;
+            }
+            self.send_free = cycle + per_byte;
+        } else if m == Urecv {
+            self.received += 1;
+            let arrival = self.received * per_byte;
+            if arrival > cycle {
+                self.stall(StallCause::UartRecv, arrival - cycle);
+                cycle = arrival;
+            }
+        }
+
+        for w in &access.written {
+            if *w != Register::Zero {
+                self.ready[get_register_num(*w) as usize] = (cycle + self.model.hazards.distance(m) as u64, m);
+            }
+        }
+        self.accesses = accesses;
+
+        self.instructions += 1;
+        self.last_issue = cycle;
+        self.next_issue = cycle + 1;
+        if taken || m == J || m == Call || m == Jr {
+            self.pending_penalty = self.model.branch_penalty as u64;
+        }
+    }
+
+    /// 最初の命令の発行から最後の命令が終わるまでのサイクル数
+    pub fn cycles(&self) -> u64 {
+        if self.instructions == 0 {
+            return 0;
+        }
+        self.last_issue + self.model.pipeline_depth as u64
+    }
+
+    pub fn total_stalls(&self) -> u64 {
+        self.stalls.values().sum()
+    }
+}
+
+/// 合計と、原因ごとの内訳を多い順に書く
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let cycles = self.cycles();
+        let cpi = if self.instructions == 0 { 0.0 } else { cycles as f64 / self.instructions as f64 };
+        writeln!(f, "{cycles} cycles for {} instructions (CPI {cpi:.3})", self.instructions)?;
+        writeln!(f, "{} stall cycles", self.total_stalls())?;
+        let mut stalls: Vec<_> = self.stalls.iter().map(|(cause, n)| (cause.to_string(), *n)).collect();
+        stalls.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        for (cause, n) in stalls {
+            writeln!(f, "  {n:>12}  {cause}")?;
+        }
+        Ok(())
+    }
+}
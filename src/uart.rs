@@ -0,0 +1,133 @@
+//! シミュレータのUART (`urecv`/`usend`) のモデル
+//! 入力はファイル・標準入力・決めておいたバイト列から読み、出力はファイルか標準出力に書く。`Uart`を実装すれば差し替えられる
+
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+pub trait Uart {
+    /// 1バイト受け取る。入力が終わっていればNone、待っても届かなければ`io::ErrorKind::TimedOut`のエラー
+    fn recv(&mut self) -> io::Result<Option<u8>>;
+    fn send(&mut self, byte: u8) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+enum Input {
+    /// 届くまで待つ
+    Blocking(Box<dyn Read>),
+    /// 別のスレッドで読み、決めた時間だけ待つ
+    Timeout { bytes: Receiver<io::Result<u8>>, timeout: Duration },
+}
+
+/// `Read`と`Write`をつないだUART
+pub struct StreamUart {
+    input: Input,
+    output: Box<dyn Write>,
+}
+
+impl StreamUart {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self { input: Input::Blocking(input), output }
+    }
+
+    /// 受け取るのを`timeout`だけ待つ。入力は別のスレッドで読むので、`Send`でなければならない
+    pub fn with_timeout(mut input: Box<dyn Read + Send>, output: Box<dyn Write>, timeout: Duration) -> Self {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buf = [0; 4096];
+            loop {
+                match input.read(&mut buf) {
+                    // 送り先が無くなったら (シミュレータが終わったら) 読むのをやめる
+                    Ok(0) => { break; }
+                    Ok(n) => {
+                        if buf[..n].iter().any(|b| tx.send(Ok(*b)).is_err()) {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => {
+                        let _ = tx.send(Err(e));
+                        break;
+                    }
+                }
+            }
+        });
+        Self { input: Input::Timeout { bytes: rx, timeout }, output }
+    }
+
+    /// 決めておいたバイト列を入力にする
+    pub fn scripted(script: Vec<u8>, output: Box<dyn Write>) -> Self {
+        Self::new(Box::new(io::Cursor::new(script)), output)
+    }
+}
+
+impl Uart for StreamUart {
+    fn recv(&mut self) -> io::Result<Option<u8>> {
+        match &mut self.input {
+            Input::Blocking(input) => {
+                let mut buf = [0];
+                loop {
+                    match input.read(&mut buf) {
+                        Ok(0) => { return Ok(None); }
+                        Ok(_) => { return Ok(Some(buf[0])); }
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(e) => { return Err(e); }
+                    }
+                }
+            }
+            Input::Timeout { bytes, timeout } => match bytes.recv_timeout(*timeout) {
+                Ok(res) => res.map(Some),
+                Err(RecvTimeoutError::Disconnected) => Ok(None),
+                Err(RecvTimeoutError::Timeout) => Err(io::ErrorKind::TimedOut.into()),
+            },
+        }
+    }
+
+    fn send(&mut self, byte: u8) -> io::Result<()> {
+        self.output.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Direction {
+    Recv,
+    Send,
+}
+
+/// 送受信の記録の1バイト分
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct UartEvent {
+    /// それまでに実行した命令の数
+    pub step: u64,
+    pub direction: Direction,
+    pub byte: u8,
+}
+
+/// `12 recv 0x41 'A'`のように1行に1バイト書く。表示できない文字は16進数だけ
+impl fmt::Display for UartEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.direction {
+            Direction::Recv => "recv",
+            Direction::Send => "send",
+        };
+        write!(f, "{} {direction} 0x{:02x}", self.step, self.byte)?;
+        if self.byte.is_ascii_graphic() || self.byte == b' ' {
+            write!(f, " '{}'", self.byte as char)?;
+        }
+        Ok(())
+    }
+}
+
+/// 送受信の記録を書き出す
+pub fn write_transcript(events: &[UartEvent], out: &mut dyn Write) -> io::Result<()> {
+    for event in events {
+        writeln!(out, "{event}")?;
+    }
+    out.flush()
+}
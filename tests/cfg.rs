@@ -0,0 +1,49 @@
+mod common;
+
+use asm_1st::cfg::{Cfg, Edge};
+use common::resolve;
+
+const PROGRAM: &str = "\
+main:
+    addi r1, zero, 10
+    call sum
+halt: j halt
+sum:
+    addi r2, zero, 0
+loop:
+    add r2, r2, r1
+    subi r1, r1, 1
+    ibne r1, zero, loop
+    ret
+";
+
+#[test]
+fn splits_into_basic_blocks_and_functions() {
+    let program = resolve(PROGRAM);
+    let cfg = Cfg::build(&program);
+
+    let ranges: Vec<_> = cfg.blocks.iter().map(|b| (b.start, b.end)).collect();
+    assert_eq!(ranges, vec![(0, 2), (2, 3), (3, 4), (4, 7), (7, 8)]);
+    assert_eq!(cfg.blocks[0].labels, vec!["main"]);
+    assert_eq!(cfg.blocks[0].call, Some(3));
+
+    assert_eq!(cfg.blocks[0].successors, vec![Edge::FallThrough(1)]);
+    assert_eq!(cfg.blocks[1].successors, vec![Edge::Taken(1)]);
+    assert_eq!(cfg.blocks[3].successors, vec![Edge::FallThrough(4), Edge::Taken(3)]);
+    assert_eq!(cfg.blocks[4].successors, vec![Edge::Unknown]);
+
+    let functions: Vec<_> = cfg.functions.iter().map(|f| (f.name.as_str(), f.blocks.clone())).collect();
+    assert_eq!(functions, vec![("main", vec![0, 1]), ("sum", vec![2, 3, 4])]);
+}
+
+#[test]
+fn exports_each_function_to_dot() {
+    let program = resolve(PROGRAM);
+    let cfg = Cfg::build(&program);
+    let dot = cfg.to_dot(&cfg.functions[1], &program);
+    assert!(dot.starts_with("digraph \"sum\" {\n"), "{dot}");
+    assert!(dot.contains("b3 -> b3 [label=\"taken\"];"), "{dot}");
+    assert!(dot.contains("ibne r1, zero, -2  # -> 4 (loop)"), "{dot}");
+    assert!(dot.contains("b4 -> unknown [style=dashed];"), "{dot}");
+    assert!(dot.ends_with("}\n"));
+}
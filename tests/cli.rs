@@ -0,0 +1,230 @@
+//! コマンドラインの終了コードを確かめる
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Output, Stdio};
+
+fn asm(args: &[&str]) -> i32 {
+    Command::new(env!("CARGO_BIN_EXE_asm_1st"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap()
+        .status
+        .code()
+        .unwrap()
+}
+
+/// テストごとの一時ディレクトリ (終わったら消す)
+struct Scratch(std::path::PathBuf);
+
+impl Scratch {
+    fn new(test: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("asm_1st_cli_{}_{test}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    fn file(&self, name: &str, src: &str) -> String {
+        let path = self.0.join(name);
+        std::fs::write(&path, src).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+#[test]
+fn exit_codes_tell_which_stage_failed() {
+    let dir = Scratch::new("stages");
+    assert_eq!(asm(&["fib_asm.txt"]), 0);
+    assert_eq!(asm(&["check", "fib_asm.txt"]), 0);
+    assert_eq!(asm(&["sim", "fib_asm.txt"]), 0);
+    assert_eq!(asm(&[]), 2);
+    assert_eq!(asm(&["asm", "--format", "octal", "fib_asm.txt"]), 2);
+    assert_eq!(asm(&["asm", "no_such_file.s"]), 3);
+    assert_eq!(asm(&["check", &dir.file("syntax.s", "add r1 r2\n")]), 4);
+    assert_eq!(asm(&["check", &dir.file("semantic.s", "j nowhere\n")]), 5);
+    let far = format!("L: ibeq r1, r2, far\n.ascii \"{}\"\nfar: j L\n", "x".repeat(2000));
+    assert_eq!(asm(&["check", &dir.file("resolution.s", &far)]), 6);
+    let relaxed = dir.file("relaxed.s", &format!("L: j far\n.ascii \"{}\"\nfar: j L\n", "x".repeat(40000)));
+    assert_eq!(asm(&["check", &relaxed]), 0);
+    assert_eq!(asm(&["check", "-W", "error", &relaxed]), 6);
+    assert_eq!(asm(&["sim", &dir.file("runtime.s", ".word 0\n")]), 7);
+}
+
+#[test]
+fn link_combines_files() {
+    let dir = Scratch::new("link");
+    let a = dir.file("a.s", "call f\nL: j L\n");
+    let b = dir.file("b.s", "f: ret\n");
+    assert_eq!(asm(&["link", &a, &b]), 0);
+    assert_eq!(asm(&["link", &a, &a]), 5);
+    assert_eq!(asm(&["link", "-O", &a, &b]), 0);
+}
+
+#[test]
+fn fmt_check_reports_unformatted_files() {
+    let dir = Scratch::new("fmt");
+    assert_eq!(asm(&["fmt", "--check", "fib_asm.txt"]), 1);
+    assert_eq!(asm(&["fmt", "--check", &dir.file("formatted.s", "L:\n    j      L\n")]), 0);
+    assert_eq!(asm(&["asm", "--check", "fib_asm.txt"]), 2);
+}
+
+#[test]
+fn lint_levels_decide_the_exit_code() {
+    let dir = Scratch::new("lint");
+    let suspicious = dir.file("lint.s", "addi r1, r2, 1\nL: j L\n");
+    assert_eq!(asm(&["check", "--lint", &suspicious]), 0);
+    assert_eq!(asm(&["check", "--lint", "-W", "error", &suspicious]), 5);
+    assert_eq!(asm(&["check", "--lint", "-W", "error", "-A", "uninitialized-register", &suspicious]), 0);
+    assert_eq!(asm(&["check", "--lint", "-A", "no-such-lint", &suspicious]), 2);
+}
+
+#[test]
+fn hazard_options() {
+    let dir = Scratch::new("hazards");
+    let load_use = dir.file("hazard.s", "lw r1, sp, 0\nadd r2, r1, r1\nL: j L\n");
+    assert_eq!(asm(&["check", "--hazards", &load_use]), 0);
+    assert_eq!(asm(&["check", "--hazards", "-W", "error", &load_use]), 6);
+    assert_eq!(asm(&["check", "--auto-nop", "-W", "error", &load_use]), 0);
+    assert_eq!(asm(&["check", "--hazard-model", &dir.file("model.json", "{\"forwarding\": 1}"), &load_use]), 2);
+    assert_eq!(asm(&["check", "--hazard-model", "no_such_model.json", &load_use]), 3);
+    assert_eq!(asm(&["check", "--hazard-model", "-", &load_use]), 2);
+    assert_eq!(asm(&["link", "--auto-nop", &load_use]), 2);
+}
+
+#[test]
+fn optimization_keeps_programs_valid() {
+    assert_eq!(asm(&["check", "-O", "fib_asm.txt"]), 0);
+}
+
+#[test]
+fn uart_options() {
+    let dir = Scratch::new("uart");
+    let echo = dir.file("echo.s", "urecv r1\nusend r1\nL: j L\n");
+    assert_eq!(asm(&["sim", "--uart-script", "x", &echo]), 0);
+    assert_eq!(asm(&["sim", "--uart-in", &dir.file("in.txt", "x"), &echo]), 0);
+    assert_eq!(asm(&["sim", "--uart-in", &dir.file("empty.txt", ""), &echo]), 7);
+    assert_eq!(asm(&["sim", "--uart-in", "no_such_input.txt", &echo]), 3);
+    assert_eq!(asm(&["sim", "--uart-timeout", "soon", &echo]), 2);
+}
+
+#[test]
+fn timing_options() {
+    let dir = Scratch::new("timing");
+    assert_eq!(asm(&["sim", "--timing", "fib_asm.txt"]), 0);
+    assert_eq!(asm(&["sim", "--timing-model", &dir.file("timing.json", "{\"pipeline_depth\": 0}"), "fib_asm.txt"]), 2);
+    assert_eq!(asm(&["sim", "--timing-model", "-", "fib_asm.txt"]), 2);
+}
+
+#[test]
+fn debug_rejects_a_program_from_stdin() {
+    assert_eq!(asm(&["debug", "-"]), 2);
+}
+
+/// 標準入力にinputを流し込んで実行する
+fn pipe(args: &[&str], input: &[u8]) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_asm_1st"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child.stdin.take().unwrap().write_all(input).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn works_as_a_pipeline_filter() {
+    let fib = std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/fib_asm.txt")).unwrap();
+
+    let hex = pipe(&["-"], &fib);
+    assert!(hex.status.success());
+    let bin = pipe(&["asm", "--format=bin", "-o", "-", "-"], &fib);
+    assert_eq!(bin.stdout.len(), hex.stdout.len() / 9 * 4);
+
+    // 機械語の形式によらず同じ逆アセンブル結果になる
+    let from_hex = pipe(&["disasm", "-"], &hex.stdout);
+    let from_bin = pipe(&["disasm", "--format", "bin", "-"], &bin.stdout);
+    assert_eq!(from_hex.stdout, from_bin.stdout);
+    assert!(from_hex.stdout.ends_with(b"j 0\n"));
+
+    // 診断は標準エラー出力にだけ出る
+    let err = pipe(&["-"], b"add r1\n");
+    assert_eq!(err.status.code(), Some(5));
+    assert!(err.stdout.is_empty());
+    assert_eq!(String::from_utf8(err.stderr).unwrap(), "at line 1, character 1: Syntax Error\nthe number of operands must be 3.\n");
+}
+
+#[test]
+fn sim_writes_usend_output_and_a_transcript() {
+    let dir = std::env::temp_dir().join(format!("asm_1st_uart_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("out.ppm");
+    let transcript = dir.join("transcript.txt");
+    let src = b"urecv r1\nusend r1\nusend r1\nL: j L\n";
+    let res = pipe(&[
+        "sim", "--uart-script", "P", "-o", out.to_str().unwrap(),
+        "--uart-transcript", transcript.to_str().unwrap(), "-",
+    ], src);
+    assert!(res.status.success());
+    assert!(res.stdout.is_empty());
+    assert_eq!(std::fs::read(&out).unwrap(), b"PP");
+    assert_eq!(
+        std::fs::read_to_string(&transcript).unwrap(),
+        "0 recv 0x50 'P'\n1 send 0x50 'P'\n2 send 0x50 'P'\n",
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn debug_reads_commands_from_stdin() {
+    let out = pipe(&["debug", "fib_asm.txt"], b"break 9\ncontinue\nquit\n");
+    assert!(out.status.success());
+    let out = String::from_utf8(out.stdout).unwrap();
+    assert!(out.contains("breakpoint 1\nstopped at "), "{out}");
+}
+
+#[test]
+fn gdb_listens_on_a_tcp_port() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_asm_1st"))
+        .args(["gdb", "--port", "0", "fib_asm.txt"])
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stderr.take().unwrap()).read_line(&mut line).unwrap();
+    let address = line.trim().strip_prefix("listening on ").unwrap();
+
+    let mut stream = TcpStream::connect(address).unwrap();
+    stream.write_all(b"$p100#01").unwrap();
+    let mut reply = [0; 13];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"+$00000000#80");
+    stream.write_all(b"+$k#6b").unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn json_diagnostics() {
+    let out = pipe(&["check", "--diagnostics-format=json", "-"], b"L: addi r1, zero, 1 # ok\n  j nowhere ; j L\n");
+    assert_eq!(out.status.code(), Some(5));
+    assert_eq!(
+        String::from_utf8(out.stderr).unwrap(),
+        "{\"file\":\"<stdin>\",\"line\":2,\"column\":3,\"end_column\":12,\"severity\":\"error\",\
+         \"code\":\"E0003\",\"name\":\"LabelNotFound\",\"message\":\"label \\\"nowhere\\\" not found.\"}\n",
+    );
+
+    let out = pipe(&["--diagnostics-format", "json", "-"], "add r1,\u{3000}r2\n".as_bytes());
+    assert_eq!(out.status.code(), Some(4));
+    let err = String::from_utf8(out.stderr).unwrap();
+    assert!(err.contains("\"column\":8,\"end_column\":9"), "{err}");
+    assert!(err.contains("\"code\":\"E0203\",\"name\":\"InvalidCharacter\""), "{err}");
+    assert!(err.contains("\\nfull-width space"), "{err}");
+}
@@ -0,0 +1,32 @@
+// テストのファイルごとに使う関数が違う
+#![allow(dead_code)]
+
+pub mod tokens;
+
+use asm_1st::encoder::encode;
+use asm_1st::parser::{Instruction, LabelPositions, Parser};
+use asm_1st::resolver::resolve_without_optimization;
+use asm_1st::semantics::check_semantics;
+use asm_1st::slice_lexer::SliceLexer;
+
+/// ソースを構文解析してsemantic checkまでする。ラベルを定義した位置も返す
+pub fn check(src: &str) -> (Vec<Instruction>, LabelPositions) {
+    let lex = SliceLexer::new(src.as_bytes());
+    let (inst, labels) = Parser::new(lex.tokens()).parse_with_label_positions()
+        .unwrap_or_else(|e| panic!("parse error {e:?} in:\n{src}"));
+    check_semantics(&inst, &labels.keys().cloned().collect())
+        .unwrap_or_else(|e| panic!("semantic error {e:?} in:\n{src}"));
+    (inst, labels)
+}
+
+/// ソースをアドレス解決までする。途中でエラーになったらpanicする
+pub fn resolve(src: &str) -> Vec<Instruction> {
+    let (inst, _) = check(src);
+    resolve_without_optimization(inst)
+        .unwrap_or_else(|e| panic!("resolution error {e:?} in:\n{src}"))
+}
+
+/// ソースを機械語までアセンブルする。途中でエラーになったらpanicする
+pub fn assemble(src: &str) -> Vec<u32> {
+    encode(resolve(src))
+}
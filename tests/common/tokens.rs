@@ -0,0 +1,14 @@
+//! トークン列の比較に使う。fuzz/fuzz_targets/lexer.rsからも`#[path]`で読み込む
+
+use asm_1st::lexer::{LexItem, LexToken};
+
+/// Eofかエラーまでのトークン列を集める
+pub fn collect_tokens(it: impl Iterator<Item = LexItem>) -> Vec<String> {
+    let mut res = vec![];
+    for item in it {
+        let stop = !matches!(item, Ok((ref t, _, _)) if *t != LexToken::LexEof);
+        res.push(format!("{item:?}"));
+        if stop { break; }
+    }
+    res
+}
@@ -0,0 +1,203 @@
+//! デバッグアダプタを起動して、記録しておいたDAPのやり取りを再生する
+
+use std::fs;
+use std::io::{BufReader, Write};
+use std::process::{Command, Stdio};
+use asm_1st::dap::Server;
+use asm_1st::json::{obj, parse, Value};
+use asm_1st::lsp::{read_message, write_message};
+
+const DIR: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/dap");
+
+/// `->`の行を送り、`<-`の行が順に返ってくることを確かめる
+fn replay(transcript: &str) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_asm-dap"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    for (i, line) in transcript.lines().enumerate() {
+        if let Some(message) = line.strip_prefix("-> ") {
+            write_message(&mut stdin, &parse(message).unwrap()).unwrap();
+        } else if let Some(expected) = line.strip_prefix("<- ") {
+            let actual = parse(&read_message(&mut stdout).unwrap().unwrap()).unwrap();
+            assert_eq!(actual, parse(expected).unwrap(), "line {}", i + 1);
+        }
+    }
+    stdin.flush().unwrap();
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn recorded_session() {
+    replay(&fs::read_to_string(format!("{DIR}/hello.txt")).unwrap());
+}
+
+fn request(server: &mut Server, command: &str, arguments: Value) -> Vec<Value> {
+    server.handle(&obj(&[("seq", 1_i64.into()), ("type", "request".into()), ("command", command.into()), ("arguments", arguments)]))
+}
+
+fn launch(server: &mut Server, program: &str) -> Vec<Value> {
+    request(server, "launch", obj(&[("program", program.into()), ("stopOnEntry", true.into())]))
+}
+
+/// 止まるまで実行を進め、その間のイベントを返す
+fn finish(server: &mut Server) -> Vec<Value> {
+    let mut events = vec![];
+    while server.running() {
+        events.extend(server.poll());
+    }
+    events
+}
+
+#[test]
+fn registers_are_variables() {
+    let mut server = Server::new();
+    launch(&mut server, &format!("{DIR}/hello.s"));
+    let replies = request(&mut server, "configurationDone", obj(&[]));
+    assert_eq!(replies[1].get("body").get("reason").as_str(), Some("entry"));
+    request(&mut server, "stepIn", obj(&[]));
+
+    let scopes = request(&mut server, "scopes", obj(&[("frameId", 0_i64.into())]));
+    let reference = scopes[0].get("body").get("scopes").as_array().unwrap()[0].get("variablesReference").clone();
+    let variables = request(&mut server, "variables", obj(&[("variablesReference", reference)]));
+    let variables = variables[0].get("body").get("variables").as_array().unwrap();
+    assert_eq!(variables.len(), 256);
+    let value = |name: &str| variables.iter().find(|v| v.get("name").as_str() == Some(name)).unwrap().get("value").as_str();
+    assert_eq!(value("pc"), Some("1"));
+    assert_eq!(value("r1"), Some("104 (0x00000068, 1.46e-43)"));
+    assert_eq!(value("r252"), Some("0 (0x00000000, 0.0)"));
+}
+
+/// 止まっている行
+fn current_line(server: &mut Server) -> i64 {
+    let replies = request(server, "stackTrace", obj(&[("threadId", 1_i64.into())]));
+    replies[0].get("body").get("stackFrames").as_array().unwrap()[0].get("line").as_i64().unwrap()
+}
+
+#[test]
+fn steps_go_line_by_line() {
+    let mut server = Server::new();
+    launch(&mut server, &format!("{DIR}/hello.s"));
+    request(&mut server, "configurationDone", obj(&[]));
+    assert_eq!(current_line(&mut server), 3);
+
+    let mut lines = vec![];
+    for command in ["next", "next", "stepIn", "stepIn", "next", "stepIn", "next", "next"] {
+        let replies = request(&mut server, command, obj(&[("threadId", 1_i64.into())]));
+        assert!(!server.running());
+        let stopped = replies.last().unwrap();
+        assert_eq!(stopped.get("body").get("reason").as_str(), Some("step"), "{command}");
+        lines.push(current_line(&mut server));
+    }
+    // callは飛ばすか中に入り、enter/leaveは複数の命令でも1行で進む。retでは呼び出し元に戻る
+    assert_eq!(lines, [4, 5, 6, 13, 14, 15, 16, 7]);
+}
+
+#[test]
+fn errors_are_reported() {
+    let mut server = Server::new();
+    let replies = request(&mut server, "next", obj(&[]));
+    assert_eq!(replies[0].get("success"), &Value::Bool(false));
+
+    let replies = launch(&mut server, &format!("{DIR}/missing.s"));
+    assert_eq!(replies[0].get("success"), &Value::Bool(false));
+
+    // 構文エラーはメッセージで知らせる
+    let path = std::env::temp_dir().join(format!("asm_dap_{}.s", std::process::id()));
+    fs::write(&path, "addi r1, zero\n").unwrap();
+    let replies = launch(&mut server, path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    assert_eq!(replies[0].get("success"), &Value::Bool(false));
+    assert!(replies[0].get("message").as_str().unwrap().contains("line 1"), "{}", replies[0]);
+
+    // 実行時エラーは例外として止まり、デバッグコンソールにも書く
+    let path = std::env::temp_dir().join(format!("asm_dap_urecv_{}.s", std::process::id()));
+    fs::write(&path, "urecv r1\nL: j L\n").unwrap();
+    launch(&mut server, path.to_str().unwrap());
+    fs::remove_file(&path).unwrap();
+    request(&mut server, "configurationDone", obj(&[]));
+    let replies = request(&mut server, "continue", obj(&[]));
+    assert_eq!(replies.len(), 1);
+    let events = finish(&mut server);
+    assert_eq!(events[0].get("event").as_str(), Some("output"));
+    assert_eq!(events[0].get("body").get("category").as_str(), Some("stderr"));
+    assert_eq!(events[1].get("body").get("reason").as_str(), Some("exception"));
+}
+
+#[test]
+fn running_program_can_be_paused() {
+    let mut server = Server::new();
+    // 停止しない無限ループ
+    let path = std::env::temp_dir().join(format!("asm_dap_pause_{}.s", std::process::id()));
+    fs::write(&path, "loop:\n    addi r1, r1, 1\n    j loop\n").unwrap();
+    request(&mut server, "launch", obj(&[("program", path.to_str().unwrap().into())]));
+    fs::remove_file(&path).unwrap();
+    request(&mut server, "configurationDone", obj(&[]));
+    assert!(server.running());
+    assert_eq!(server.poll(), vec![]);
+    assert!(server.running());
+
+    // 実行中でも他のリクエストに答える
+    let replies = request(&mut server, "threads", obj(&[]));
+    assert_eq!(replies[0].get("success"), &Value::Bool(true));
+    let replies = request(&mut server, "pause", obj(&[("threadId", 1_i64.into())]));
+    assert_eq!(replies[1].get("body").get("reason").as_str(), Some("pause"));
+    assert!(!server.running());
+    let replies = request(&mut server, "evaluate", obj(&[("expression", "r1".into())]));
+    assert_ne!(replies[0].get("body").get("result").as_str(), Some("0 (0x00000000, 0.0)"));
+
+    // 止まっているときのpauseは何もしない
+    assert_eq!(request(&mut server, "pause", obj(&[])).len(), 1);
+}
+
+#[test]
+fn output_is_sent_while_running() {
+    let mut server = Server::new();
+    // 1文字送ってから止まらずに回り続ける
+    let path = std::env::temp_dir().join(format!("asm_dap_output_{}.s", std::process::id()));
+    fs::write(&path, "    addi r1, zero, 104\n    usend r1\nloop:\n    addi r2, r2, 1\n    j loop\n").unwrap();
+    request(&mut server, "launch", obj(&[("program", path.to_str().unwrap().into())]));
+    fs::remove_file(&path).unwrap();
+    request(&mut server, "configurationDone", obj(&[]));
+    let events = server.poll();
+    assert!(server.running());
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].get("event").as_str(), Some("output"));
+    assert_eq!(events[0].get("body").get("output").as_str(), Some("h"));
+    assert_eq!(server.poll(), vec![]);
+}
+
+#[test]
+fn adapter_pauses_between_messages() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_asm-dap"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut receive = || parse(&read_message(&mut stdout).unwrap().unwrap()).unwrap();
+
+    let path = std::env::temp_dir().join(format!("asm_dap_pause_child_{}.s", std::process::id()));
+    fs::write(&path, "loop:\n    addi r1, r1, 1\n    j loop\n").unwrap();
+    let message = |seq: i64, command: &str, arguments: Value| obj(&[
+        ("seq", seq.into()), ("type", "request".into()), ("command", command.into()), ("arguments", arguments),
+    ]);
+    write_message(&mut stdin, &message(1, "launch", obj(&[("program", path.to_str().unwrap().into())]))).unwrap();
+    receive();
+    receive();
+    write_message(&mut stdin, &message(2, "configurationDone", obj(&[]))).unwrap();
+    assert_eq!(receive().get("command").as_str(), Some("configurationDone"));
+    write_message(&mut stdin, &message(3, "pause", obj(&[]))).unwrap();
+    assert_eq!(receive().get("command").as_str(), Some("pause"));
+    assert_eq!(receive().get("body").get("reason").as_str(), Some("pause"));
+    write_message(&mut stdin, &message(4, "disconnect", obj(&[]))).unwrap();
+    assert_eq!(receive().get("command").as_str(), Some("disconnect"));
+    fs::remove_file(&path).unwrap();
+    assert!(child.wait().unwrap().success());
+}
@@ -0,0 +1,16 @@
+# usendで"hi"と改行を書く
+main:
+    addi r1, zero, 104
+    call put
+    addi r1, zero, 105
+    call put
+    addi r1, zero, 10
+    call put
+end:
+    j end
+
+put:
+    enter 0
+    usend r1
+    leave
+    ret
@@ -0,0 +1,129 @@
+mod common;
+
+use std::io;
+use asm_1st::debugger::{Debugger, Stop};
+use asm_1st::lexer::Register;
+use asm_1st::uart::StreamUart;
+use common::resolve;
+
+/// 再帰でn + (n - 1) + ... + 1を計算し、100番地に書く
+const SUM: &str = "\
+main:
+    addi r1, zero, 4
+    call sum
+    sw r2, zero, 100
+end:
+    j end
+sum:
+    enter 0
+    ibne r1, zero, rec
+    addi r2, zero, 0
+    leave
+    ret
+rec:
+    push r1
+    subi r1, r1, 1
+    call sum
+    pop r1
+    add r2, r2, r1
+    leave
+    ret
+";
+
+fn debugger(src: &str) -> Debugger {
+    Debugger::new(resolve(src), src, 1 << 12, Box::new(StreamUart::scripted(vec![], Box::new(io::sink()))))
+}
+
+#[test]
+fn breakpoints_on_labels_and_lines() {
+    let mut d = debugger(SUM);
+    assert_eq!(d.location("rec"), Ok(15));
+    // 命令の無い行は、次に命令のある行になる
+    assert_eq!(d.location("7"), d.location("sum"));
+    assert_eq!(d.location("*3"), Ok(3));
+    assert!(d.location("nowhere").is_err());
+    assert!(d.location("*1000").is_err());
+
+    let id = d.add_breakpoint(d.location("10").unwrap());
+    assert!(matches!(d.resume(), Stop::Breakpoint(i) if i == id));
+    assert_eq!(d.describe(d.pc()), "9 (sum+5), line 10: addi r2, zero, 0");
+    assert_eq!(d.machine.reg(Register::R(1)), 0);
+
+    // 再帰の底から、mainのcallまでfpをたどれる
+    let frames: Vec<_> = d.backtrace().iter().map(|f| d.symbol(f.address)).collect();
+    assert_eq!(frames, ["sum+5", "rec+3", "rec+3", "rec+3", "rec+3", "main+1"]);
+
+    assert!(d.delete_point(id));
+    assert!(!d.delete_point(id));
+    assert!(matches!(d.resume(), Stop::Halted));
+    assert!(d.halted());
+    assert_eq!(d.machine.memory[100], 10);
+}
+
+#[test]
+fn step_over_and_out_of_calls() {
+    let mut d = debugger(SUM);
+    assert!(matches!(d.step(), Stop::Step));
+    // callは戻ってくるまで実行する。再帰の途中で同じcallの次に戻っても止まらない
+    assert!(matches!(d.step_over(), Stop::Step));
+    assert_eq!(d.pc(), 2);
+    assert_eq!(d.machine.reg(Register::R(2)), 10);
+
+    let mut d = debugger(SUM);
+    d.add_breakpoint(d.location("10").unwrap());
+    d.resume();
+    d.delete_point(1);
+    assert!(matches!(d.step_out(), Stop::Step));
+    assert_eq!(d.symbol(d.pc()), "rec+4");
+    assert_eq!(d.backtrace().len(), 5);
+}
+
+#[test]
+fn watchpoints_and_expressions() {
+    let mut d = debugger(SUM);
+    assert_eq!(d.evaluate("sp"), Ok(1 << 12));
+    assert_eq!(d.evaluate("rec+2"), Ok(17));
+    assert_eq!(d.evaluate("0x10 - 1"), Ok(15));
+    assert!(d.evaluate("nowhere").is_err());
+    assert!(d.evaluate("0x7fffffffffffffff+1").is_err());
+    assert!(d.evaluate("-1-0x7fffffffffffffff-1").is_err());
+
+    let id = d.add_watchpoint(100).unwrap();
+    assert!(d.add_watchpoint(1 << 12).is_err());
+    match d.resume() {
+        Stop::Watchpoint { id: i, address: 100, old: 0, new: 10 } => assert_eq!(i, id),
+        stop => panic!("{stop:?}"),
+    }
+    assert_eq!(d.symbol(d.pc()), "end");
+}
+
+#[test]
+fn commands() {
+    let mut d = debugger(SUM);
+    let mut out = vec![];
+    let input = "break rec\ncontinue\n\ninfo breakpoints\nx 100 1\nnext\nfoo\nquit\ncontinue\n";
+    d.repl(&mut input.as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    let expected = "\
+stopped at 0 (main), line 2: addi r1, zero, 4
+(asm) breakpoint 1 at 15 (rec), line 14: push r1
+(asm) breakpoint 1
+stopped at 15 (rec), line 14: push r1
+(asm) breakpoint 1
+stopped at 15 (rec), line 14: push r1
+(asm) 1 breakpoint at 15 (rec), line 14: push r1
+(asm)      100: 0 (0x00000000, 0.0)
+(asm) stopped at 16 (rec+1), line 14: push r1
+(asm) error: unknown command \"foo\" (try help).
+(asm) ";
+    assert_eq!(out, expected);
+}
+
+#[test]
+fn huge_counts_stop_at_the_end_of_memory() {
+    let mut d = debugger(SUM);
+    let mut out = vec![];
+    d.repl(&mut "x 4094 18446744073709551615\nquit\n".as_bytes(), &mut out).unwrap();
+    let out = String::from_utf8(out).unwrap();
+    assert!(out.ends_with("    4095: 0 (0x00000000, 0.0)\n(asm) "), "{out}");
+}
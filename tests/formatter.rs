@@ -0,0 +1,56 @@
+mod common;
+
+use asm_1st::formatter::format_source;
+use asm_1st::parser::Parser;
+use asm_1st::slice_lexer::SliceLexer;
+use common::assemble;
+
+fn format(src: &str) -> String {
+    let (inst, _) = Parser::new(SliceLexer::new(src.as_bytes()).tokens()).parse().unwrap();
+    format_source(src.as_bytes(), &inst)
+}
+
+const MESSY: &str = "\
+# header
+
+
+  main:   ADDI r1,zero,0xff   # trailing
+loop: Lif r2, 1.5 ; subi r1, r1, 1;x: ibne r1, zero, loop
+\t# indented
+end:
+   .ascii \"a#b\" # c
+  addi r3, zero, '#'
+ HALT: j HALT
+
+
+";
+
+const FORMATTED: &str = "\
+# header
+
+main:
+    addi   r1, zero, 0xff               # trailing
+loop:
+    lif    r2, 1.5; subi r1, r1, 1; x: ibne r1, zero, loop
+    # indented
+end:
+    .ascii \"a#b\"                        # c
+    addi   r3, zero, '#'
+HALT:
+    j      HALT
+";
+
+#[test]
+fn formats_into_the_canonical_layout() {
+    assert_eq!(format(MESSY), FORMATTED);
+}
+
+#[test]
+fn formatting_is_idempotent_and_keeps_the_code() {
+    let fib = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/fib_asm.txt")).unwrap();
+    for src in [MESSY, fib.as_str()] {
+        let formatted = format(src);
+        assert_eq!(format(&formatted), formatted);
+        assert_eq!(assemble(&formatted), assemble(src));
+    }
+}
@@ -0,0 +1,134 @@
+//! fuzzターゲット (fuzz/fuzz_targets) と同じ処理を、回帰用の入力と簡単な変異で生成した入力に対して走らせる
+//! 本格的なfuzzingは`fuzz/`以下をcargo-fuzzで実行する (fuzz/README.md参照)
+
+mod common;
+
+use std::fs;
+use std::io::BufReader;
+use std::path::Path;
+use asm_1st::encoder::encode;
+use asm_1st::lexer::{LexItem, LexToken, Lexer};
+use asm_1st::parser::Parser;
+use asm_1st::resolver::resolve_without_optimization;
+use asm_1st::semantics::check_semantics;
+use asm_1st::slice_lexer::{SliceLexer, Token};
+use common::tokens::collect_tokens;
+
+/// fuzz/fuzz_targets以下と同じ処理。panicしなければ良い
+fn run_pipeline(data: &[u8]) {
+    // 2つのLexerは同じトークン列を返すはず
+    let a = collect_tokens(Lexer::new(BufReader::new(data)));
+    let b = collect_tokens(SliceLexer::new(data).tokens());
+    assert_eq!(a, b, "lexers disagree on {:?}", String::from_utf8_lossy(data));
+
+    // トリビアを残すモードでも、Parserに渡るトークン列は変わらない
+    let c = collect_tokens(SliceLexer::with_trivia(data).tokens());
+    assert_eq!(b, c);
+
+    // エラーが無ければ、トリビアを含めたspanは隙間なく入力全体を覆う
+    let mut pos = 0;
+    for item in SliceLexer::with_trivia(data) {
+        let Ok((token, span)) = item else { break; };
+        assert_eq!(span.start, pos);
+        pos = span.end;
+        if token == Token::Eof {
+            assert_eq!(pos, data.len());
+            break;
+        }
+    }
+
+    let Ok((inst, labels)) = Parser::new(SliceLexer::new(data).tokens()).parse() else { return; };
+    if check_semantics(&inst, &labels).is_err() { return; }
+    let Ok(inst) = resolve_without_optimization(inst) else { return; };
+    encode(inst);
+}
+
+fn inputs_in(dir: &str) -> Vec<Vec<u8>> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join(dir);
+    let mut paths: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap().path()).collect();
+    paths.sort();
+    paths.into_iter().map(|p| fs::read(p).unwrap()).collect()
+}
+
+#[test]
+fn regression_inputs_do_not_panic() {
+    for data in inputs_in("fuzz/regressions") {
+        run_pipeline(&data);
+    }
+}
+
+/// 大きすぎてファイルに置かない回帰用の入力。再帰していた頃のParserはスタックを溢れさせた
+#[test]
+fn huge_inputs_do_not_panic() {
+    let inputs = [
+        format!("j L\n{}j L\n", "L:\n".repeat(100_000)),
+        format!("j L{}\n", ", L".repeat(100_000)),
+        "\n".repeat(200_001),
+    ];
+    for data in inputs {
+        run_pipeline(data.as_bytes());
+    }
+}
+
+/// 途中で切れたUTF-8の後も、2つのLexerは同じ位置から読み直す
+#[test]
+fn truncated_utf8_does_not_swallow_the_next_characters() {
+    let inputs: [&[u8]; 4] = [
+        b"\xe3\x81addi r1, r2, r3\n",
+        b"\xf0\x9f\x98 j L\n",
+        b"L: \xe3\n",
+        b"\xc3",
+    ];
+    for data in inputs {
+        // エラーの後も続けて読み、Eofまで比べる
+        let until_eof = |it: &mut dyn Iterator<Item = LexItem>| {
+            let mut res = vec![];
+            for item in it {
+                let eof = matches!(item, Ok((LexToken::LexEof, _, _)));
+                res.push(format!("{item:?}"));
+                if eof { break; }
+            }
+            res
+        };
+        let a = until_eof(&mut Lexer::new(BufReader::new(data)));
+        let b = until_eof(&mut SliceLexer::new(data).tokens());
+        assert_eq!(a, b, "lexers disagree on {:?}", String::from_utf8_lossy(data));
+        run_pipeline(data);
+    }
+}
+
+/// 依存クレートを増やしたくないので、テスト用の簡単な疑似乱数 (xorshift64)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0 as usize
+    }
+}
+
+#[test]
+fn mutated_corpus_does_not_panic() {
+    // 壊れ方としてよくあるものを混ぜ込む
+    const DICT: [&[u8]; 24] = [
+        b"-", b"- ", b"0x", b"0b", b"0f", b"1.", b"1e", b"'", b"\"", b"\\", b"\\x", b":", b",", b";",
+        b"\n", b"#", b"\xe3\x80\x80", b"\xff", b"r999", b"libeq", b"lif", b".ascii", b".word", b"99999999999999999999",
+    ];
+
+    let corpus = inputs_in("fuzz/seeds");
+    let mut rng = Rng(0x9e3779b97f4a7c15);
+    for _ in 0..3000 {
+        let mut data = corpus[rng.next() % corpus.len()].clone();
+        for _ in 0..1 + rng.next() % 4 {
+            let pos = rng.next() % (data.len() + 1);
+            match rng.next() % 3 {
+                0 => { data.splice(pos..pos, DICT[rng.next() % DICT.len()].iter().copied()); }
+                1 if pos < data.len() => { data[pos] = rng.next() as u8; }
+                _ => { data.truncate(pos); }
+            }
+        }
+        run_pipeline(&data);
+    }
+}
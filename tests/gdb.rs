@@ -0,0 +1,159 @@
+mod common;
+
+use std::io;
+use asm_1st::debugger::Debugger;
+use asm_1st::gdb::{encode_packet, target_xml, GdbStub};
+use asm_1st::lexer::Register;
+use asm_1st::uart::StreamUart;
+use common::resolve;
+
+/// 1から4まで足して、途中の値を100番地に書く
+const SUM: &str = "\
+main:
+    addi r1, zero, 4
+    addi r2, zero, 0
+loop:
+    add r2, r2, r1
+    sw r2, zero, 100
+    subi r1, r1, 1
+    ibne r1, zero, loop
+end:
+    j end
+";
+
+fn stub(src: &str) -> GdbStub {
+    GdbStub::new(Debugger::new(resolve(src), src, 1 << 12, Box::new(StreamUart::scripted(vec![], Box::new(io::sink())))))
+}
+
+/// 応答が1つのパケットに対して、その中身を返す
+fn ask(stub: &mut GdbStub, packet: &str) -> String {
+    let (mut replies, done) = stub.handle(packet);
+    assert!(!done);
+    assert_eq!(replies.len(), 1, "{packet}: {replies:?}");
+    replies.remove(0)
+}
+
+#[test]
+fn target_description() {
+    let mut s = stub(SUM);
+    assert!(ask(&mut s, "qSupported:multiprocess+;xmlRegisters=i386").contains("qXfer:features:read+"));
+
+    // 小さく区切って読んでも元に戻る
+    let mut xml = String::new();
+    loop {
+        let chunk = ask(&mut s, &format!("qXfer:features:read:target.xml:{:x},80", xml.len()));
+        xml.push_str(&chunk[1..]);
+        if chunk.starts_with('l') {
+            break;
+        }
+        assert!(chunk.starts_with('m'));
+    }
+    assert_eq!(xml, target_xml());
+    assert!(xml.contains("<reg name=\"r252\" bitsize=\"32\" type=\"int\" regnum=\"252\"/>"));
+    assert!(xml.contains("<reg name=\"sp\" bitsize=\"32\" type=\"int\" regnum=\"253\"/>"));
+    assert!(xml.contains("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"256\"/>"));
+}
+
+#[test]
+fn registers_and_memory() {
+    let mut s = stub(SUM);
+    assert_eq!(ask(&mut s, "s"), "S05");
+    assert_eq!(ask(&mut s, "p1"), "04000000");
+    // pcはバイト単位
+    assert_eq!(ask(&mut s, "p100"), "04000000");
+    let g = ask(&mut s, "g");
+    assert_eq!(g.len(), 257 * 8);
+    assert_eq!(&g[8..16], "04000000");
+
+    assert_eq!(ask(&mut s, "P2=2a000000"), "OK");
+    assert_eq!(ask(&mut s, "p2"), "2a000000");
+    // zeroへの書き込みは捨てる
+    assert_eq!(ask(&mut s, "Pff=01000000"), "OK");
+    assert_eq!(ask(&mut s, "pff"), "00000000");
+
+    // ワード100はバイト400 (0x190) から
+    assert_eq!(ask(&mut s, "M191,2:3412"), "OK");
+    assert_eq!(ask(&mut s, "m190,4"), "00341200");
+    assert_eq!(s.debugger.machine.memory[100], 0x0012_3400);
+    assert_eq!(ask(&mut s, "m100000,4"), "E01");
+    assert_eq!(ask(&mut s, "vMustReplyEmpty"), "");
+}
+
+#[test]
+fn malformed_packets_are_errors() {
+    let mut s = stub(SUM);
+    // 文字の途中で切らない
+    let g = format!("{}é{}", "0".repeat(7), "0".repeat(257 * 8 - 9));
+    assert_eq!(ask(&mut s, &format!("G{g}")), "E01");
+    assert_eq!(ask(&mut s, "Gzz"), "E01");
+    // アドレスと長さを足すと溢れる
+    assert_eq!(ask(&mut s, &format!("m{:x},2", usize::MAX)), "E01");
+    assert_eq!(ask(&mut s, &format!("M{:x},2:0000", usize::MAX)), "E01");
+    // 長さが大きすぎる場合は最後まで返す
+    let xml = ask(&mut s, &format!("qXfer:features:read:target.xml:1,{:x}", usize::MAX));
+    assert!(xml.starts_with("l?xml"), "{xml}");
+}
+
+#[test]
+fn breakpoints_watchpoints_and_exit() {
+    let mut s = stub(SUM);
+    // loopは2番地 (バイト8)
+    assert_eq!(ask(&mut s, "Z0,8,4"), "OK");
+    assert_eq!(ask(&mut s, "c"), "T05swbreak:;");
+    assert_eq!(ask(&mut s, "p100"), "08000000");
+    assert_eq!(ask(&mut s, "c"), "T05swbreak:;");
+    // 2回目は4を足した後
+    assert_eq!(ask(&mut s, "p2"), "04000000");
+    assert_eq!(ask(&mut s, "z0,8,4"), "OK");
+
+    assert_eq!(ask(&mut s, "Z2,190,4"), "OK");
+    assert_eq!(ask(&mut s, "c"), "T05watch:190;");
+    assert_eq!(ask(&mut s, "m190,4"), "07000000");
+    assert_eq!(ask(&mut s, "z2,190,4"), "OK");
+    assert_eq!(ask(&mut s, "c"), "W00");
+    assert_eq!(ask(&mut s, "?"), "W00");
+    assert_eq!(s.handle("k"), (vec![], true));
+}
+
+#[test]
+fn serves_packets_with_acks() {
+    let mut s = stub(SUM);
+    let mut input = vec![];
+    input.extend(encode_packet("p1"));
+    // チェックサムが合わなければ`-`を返して読み直す
+    input.extend(b"$s#00");
+    input.extend(encode_packet("s"));
+    input.extend(encode_packet("QStartNoAckMode"));
+    input.extend(b"+");
+    input.extend(encode_packet("p1"));
+    input.extend(encode_packet("D"));
+    input.extend(encode_packet("p1"));
+
+    let mut output = vec![];
+    s.serve(io::Cursor::new(input), &mut output).unwrap();
+    let expected = [
+        "+", "$00000000#80",
+        "-", "+", "$S05#b8",
+        "+", "$OK#9a",
+        "$04000000#84",
+        "$OK#9a",
+    ].concat();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+}
+
+#[test]
+fn continue_can_be_interrupted() {
+    // 停止しない無限ループ
+    let mut s = stub("loop:\n    addi r1, r1, 1\n    j loop\n");
+    let mut input = vec![];
+    input.extend(encode_packet("QStartNoAckMode"));
+    input.extend(encode_packet("c"));
+    input.push(0x03);
+    input.extend(encode_packet("k"));
+
+    let mut output = vec![];
+    s.serve(io::Cursor::new(input), &mut output).unwrap();
+    let expected = ["+", "$OK#9a", "$T02#b6"].concat();
+    assert_eq!(String::from_utf8(output).unwrap(), expected);
+    assert!(s.debugger.machine.reg(Register::R(1)) > 0);
+}
@@ -0,0 +1,44 @@
+mod common;
+
+use std::fs;
+use common::assemble;
+
+/// `fib_asm.txt`の機械語。src/encoder.rsの`get_op_funct`の命令表 (add 0x08, addi 0x28, iblt 0x90, j 0xf8) から手で組み立てたもの
+/// 上位8bitが命令、残りはオペランドのフィールド
+const FIB: [u32; 11] = [
+    0x28_00_ff_01, // addi r0, zero, 1
+    0x28_01_ff_01, // addi r1, zero, 1
+    0x28_02_ff_00, // addi r2, zero, 0
+    0x28_0a_ff_00, // addi r10, zero, 0
+    0x28_0b_ff_14, // addi r11, zero, 20
+    0x08_02_ff_01, // add r2, zero, r1
+    0x08_01_ff_00, // add r1, zero, r0
+    0x08_00_01_02, // add r0, r1, r2
+    0x28_0a_0a_01, // addi r10, r10, 1
+    0x97_fc_0a_0b, // iblt r10, r11, LOOP (命令の下位3bitとその次の8bitが、11bitのオフセット-4)
+    0xf8_00_00_00, // j HALT (オフセット0)
+];
+
+#[test]
+fn fib_matches_the_opcode_table() {
+    let src = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/fib_asm.txt")).unwrap();
+    assert_eq!(assemble(&src), FIB);
+}
+
+/// README.mdに載っている`cargo run ./fib_asm.txt`の出力例を取り出す
+fn readme_expected_hex() -> Vec<u32> {
+    let readme = fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/README.md")).unwrap();
+    let example = readme.find("cargo run ./fib_asm.txt").expect("README has no example");
+    let block = &readme[example..];
+    let start = block.find("```text\n").unwrap() + "```text\n".len();
+    let end = start + block[start..].find("```").unwrap();
+
+    block[start..end].lines()
+        .map(|line| u32::from_str_radix(line.trim(), 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn readme_example_matches_the_opcode_table() {
+    assert_eq!(readme_expected_hex(), FIB);
+}
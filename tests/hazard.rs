@@ -0,0 +1,83 @@
+mod common;
+
+use asm_1st::hazard::{find_hazards, insert_nops, HazardKind, HazardModel};
+use asm_1st::lexer::{Mnemonic, Register};
+use asm_1st::parser::Operand;
+use common::{check, resolve};
+
+/// ハザードの (種類, アドレス, 止まるサイクル数)
+fn hazards(src: &str, model: &HazardModel) -> Vec<(HazardKind, usize, usize)> {
+    let program = resolve(src);
+    find_hazards(&program, model).iter().map(|h| (h.kind, h.address, h.stall)).collect()
+}
+
+const LOOP: &str = "\
+main:
+    lw r1, sp, 0
+    add r2, r1, r1
+    fdiv r3, r2, r2
+    fadd r4, r3, r3
+    ibne r4, zero, main
+end:
+    j end
+";
+
+#[test]
+fn load_use_and_long_latency_results_are_reported() {
+    use HazardKind::DataHazard;
+    let model = HazardModel::default();
+    assert_eq!(hazards(LOOP, &model), [(DataHazard, 1, 1), (DataHazard, 3, 7), (DataHazard, 4, 1)]);
+
+    let program = resolve(LOOP);
+    let first = &find_hazards(&program, &model)[0];
+    assert_eq!((first.producer, first.register), (Some(0), Some(Register::R(1))));
+
+    // 結果を使うまでに間があれば止まらない
+    assert_eq!(hazards("lw r1, sp, 0\naddi r2, zero, 1\nadd r3, r1, r1\n", &model), []);
+    // jより前の命令とは依存関係を調べない
+    assert_eq!(hazards("lw r1, sp, 0\nL: j L\nadd r3, r1, r1\n", &model), []);
+}
+
+#[test]
+fn the_model_decides_the_stalls() {
+    let src = "lw r1, sp, 0\nadd r2, r1, r1\n";
+    let no_forwarding = HazardModel::from_json(r#"{"forwarding": false, "register_file_delay": 1}"#).unwrap();
+    assert_eq!(hazards(src, &no_forwarding), [(HazardKind::DataHazard, 1, 2)]);
+    let fast_load = HazardModel::from_json(r#"{"latency": {"lw": 1}}"#).unwrap();
+    assert_eq!(hazards(src, &fast_load), []);
+
+    let delay_slot = HazardModel::from_json(r#"{"branch_delay_slots": 2}"#).unwrap();
+    assert_eq!(hazards("L: j L\nnop\n", &delay_slot), [(HazardKind::DelaySlot, 0, 1)]);
+}
+
+#[test]
+fn invalid_models_are_rejected() {
+    for src in [
+        "[]",
+        r#"{"forwarding": 1}"#,
+        r#"{"branch_delay_slots": -1}"#,
+        r#"{"latency": {"lw": 0}}"#,
+        r#"{"latency": {"load": 2}}"#,
+        r#"{"pipeline": 5}"#,
+        "{",
+    ] {
+        assert!(HazardModel::from_json(src).is_err(), "{src}");
+    }
+}
+
+#[test]
+fn inserted_nops_remove_the_hazards_and_keep_the_labels() {
+    let model = HazardModel::from_json(r#"{"branch_delay_slots": 1}"#).unwrap();
+    let (program, inserted, unfixed) = insert_nops(check(LOOP).0, &model).unwrap();
+    // データハザードに1 + 7 + 1個、ibneとjの遅延スロットに1個ずつ
+    assert_eq!(inserted, 11);
+    assert!(unfixed.is_empty());
+    assert!(find_hazards(&program, &model).is_empty());
+    assert_eq!(program.len(), 6 + inserted);
+
+    // 飛び先はnopの分だけずれ、ラベルの付いた命令を指したまま
+    let branch = program.iter().position(|i| i.mnemonic == Mnemonic::Ibne).unwrap();
+    let main = program.iter().position(|i| i.label.contains(&"main".to_string())).unwrap();
+    assert_eq!(program[main].mnemonic, Mnemonic::Lw);
+    assert_eq!(program[branch].operands[2], Operand::OpDigit(main as i64 - branch as i64));
+}
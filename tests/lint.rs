@@ -0,0 +1,94 @@
+mod common;
+
+use std::collections::HashSet;
+use asm_1st::diagnostics::{collect, Severity};
+use asm_1st::lint::{lint, Lint};
+use common::check;
+
+/// 警告の (行, 番号) を集める
+fn warnings(src: &str, allowed: &[Lint]) -> Vec<(usize, &'static str)> {
+    let (inst, labels) = check(src);
+    let allowed: HashSet<Lint> = allowed.iter().copied().collect();
+    let (count, diagnostics) = collect(|| lint(&inst, &labels, &allowed));
+    assert_eq!(count, diagnostics.len());
+    assert!(diagnostics.iter().all(|d| d.severity == Severity::Warning));
+    diagnostics.iter().map(|d| (d.line, d.code)).collect()
+}
+
+const SUSPICIOUS: &str = "\
+main:
+    addi r1, zero, 1
+    add r2, r1, r3
+    addi sp, zero, 100
+    addi sp, sp, 4
+    mov fp, sp
+    slli r2, r2, 40
+    ibeq r1, r2, next
+next:
+    j end
+    addi r1, r1, 1
+unused:
+    call f
+end:
+    j end
+f:
+    ret
+";
+
+#[test]
+fn each_kind_of_suspicious_code_is_reported() {
+    assert_eq!(warnings(SUSPICIOUS, &[]), vec![
+        (3, "W0003"), (4, "W0004"), (7, "W0006"),
+        (8, "W0005"), (11, "W0002"), (12, "W0001"),
+    ]);
+}
+
+#[test]
+fn entry_label_is_not_unused() {
+    assert_eq!(warnings("main:\n    j main\n", &[]), vec![]);
+    assert_eq!(warnings("main:\nhalt: j halt\n", &[]), vec![]);
+    // 先頭の命令のラベルは名前によらない
+    assert_eq!(warnings("start:\nhalt: j halt\n", &[]), vec![]);
+}
+
+#[test]
+fn unused_label_is_reported_at_its_definition() {
+    let src = "main:\n    j main\n\n  unused:\n\n    j main\n";
+    let (inst, labels) = check(src);
+    let (_, diagnostics) = collect(|| lint(&inst, &labels, &HashSet::new()));
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!((diagnostics[0].line, diagnostics[0].column, diagnostics[0].code), (4, 3, "W0001"));
+}
+
+#[test]
+fn warnings_can_be_allowed_one_by_one() {
+    assert_eq!(
+        warnings(SUSPICIOUS, &[Lint::UnusedLabel, Lint::ShiftOverflow]),
+        vec![(3, "W0003"), (4, "W0004"), (8, "W0005"), (11, "W0002")],
+    );
+    assert_eq!(warnings(SUSPICIOUS, &Lint::ALL), vec![]);
+    for l in Lint::ALL {
+        assert_eq!(Lint::from_flag_name(l.flag_name()), Some(l));
+    }
+}
+
+#[test]
+fn ordinary_code_has_no_warnings() {
+    let fib = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/fib_asm.txt")).unwrap();
+    assert_eq!(warnings(&fib, &[]), vec![]);
+
+    // スタックフレームを作って戻す形はspやfpに書き込んでも良い
+    let frame = "\
+    call f
+halt: j halt
+f:
+    enter 2
+    push r1, r2
+    addi fp, sp, 1
+    lw r1, fp, 0
+    pop r1, r2
+    leave
+    ret
+";
+    assert_eq!(warnings(frame, &[]), vec![]);
+}
@@ -0,0 +1,162 @@
+//! 言語サーバを起動して、標準入出力でLSPのメッセージをやり取りする
+
+use std::io::{self, BufReader, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use asm_1st::json::{obj, parse, Value};
+use asm_1st::lsp::{read_message, write_message};
+
+/// テスト用の小さなLSPクライアント
+struct Client {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    next_id: i64,
+}
+
+impl Client {
+    fn start() -> Self {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_asm-lsp"))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        Self { child, stdin, stdout, next_id: 1 }
+    }
+
+    fn notify(&mut self, method: &str, params: Value) {
+        write_message(&mut self.stdin, &obj(&[("jsonrpc", "2.0".into()), ("method", method.into()), ("params", params)])).unwrap();
+    }
+
+    fn receive(&mut self) -> Value {
+        parse(&read_message(&mut self.stdout).unwrap().unwrap()).unwrap()
+    }
+
+    /// リクエストを送り、対応するレスポンスを返す
+    fn request(&mut self, method: &str, params: Value) -> Value {
+        let id = self.next_id;
+        self.next_id += 1;
+        let message = obj(&[("jsonrpc", "2.0".into()), ("id", id.into()), ("method", method.into()), ("params", params)]);
+        write_message(&mut self.stdin, &message).unwrap();
+        loop {
+            let reply = self.receive();
+            if reply.get("id").as_i64() == Some(id) {
+                return reply;
+            }
+        }
+    }
+}
+
+const URI: &str = "file:///test.s";
+
+fn at(line: usize, character: usize) -> Value {
+    obj(&[
+        ("textDocument", obj(&[("uri", URI.into())])),
+        ("position", obj(&[("line", line.into()), ("character", character.into())])),
+    ])
+}
+
+fn open(client: &mut Client, text: &str) -> Value {
+    let doc = obj(&[("uri", URI.into()), ("languageId", "asm".into()), ("version", 1_i64.into()), ("text", text.into())]);
+    client.notify("textDocument/didOpen", obj(&[("textDocument", doc)]));
+    let diagnostics = client.receive();
+    assert_eq!(diagnostics.get("method").as_str(), Some("textDocument/publishDiagnostics"));
+    diagnostics.get("params").get("diagnostics").clone()
+}
+
+/// 位置の一覧を (行, 文字位置) の組にする
+fn positions(locations: &Value) -> Vec<(i64, i64)> {
+    locations.as_array().unwrap().iter()
+        .map(|l| {
+            let start = l.get("range").get("start");
+            (start.get("line").as_i64().unwrap(), start.get("character").as_i64().unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn language_server_over_stdio() {
+    let mut client = Client::start();
+    let init = client.request("initialize", obj(&[("capabilities", obj(&[]))]));
+    let capabilities = init.get("result").get("capabilities");
+    assert_eq!(capabilities.get("textDocumentSync").as_i64(), Some(1));
+    assert_eq!(capabilities.get("hoverProvider").as_bool(), Some(true));
+    client.notify("initialized", obj(&[]));
+
+    // 未定義のラベルは診断として届く
+    let diagnostics = open(&mut client, "loop: addi r1, r1, 1\n    j nowhere\n");
+    let diagnostics = diagnostics.as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(diagnostics[0].get("code").as_str(), Some("E0003 LabelNotFound"));
+    assert_eq!(diagnostics[0].get("range").get("start").get("line").as_i64(), Some(1));
+
+    // 直せば診断は空になる
+    let text = "loop: addi r1, r1, 1\n    ibne r1, r2, loop\n    j loop\n";
+    let change = obj(&[
+        ("textDocument", obj(&[("uri", URI.into()), ("version", 2_i64.into())])),
+        ("contentChanges", vec![obj(&[("text", text.into())])].into()),
+    ]);
+    client.notify("textDocument/didChange", change);
+    let diagnostics = client.receive();
+    assert_eq!(diagnostics.get("params").get("diagnostics").as_array().map(|d| d.len()), Some(0));
+
+    let definition = client.request("textDocument/definition", at(2, 7));
+    assert_eq!(positions(definition.get("result")), vec![(0, 0)]);
+
+    let references = client.request("textDocument/references", at(0, 2));
+    assert_eq!(positions(references.get("result")), vec![(0, 0), (1, 17), (2, 6)]);
+
+    let hover = client.request("textDocument/hover", at(0, 7));
+    let contents = hover.get("result").get("contents").get("value").as_str().unwrap().to_string();
+    assert!(contents.starts_with("**addi** — a register, a register, an immediate value"), "{contents}");
+    assert!(contents.contains("0x28000000"), "{contents}");
+
+    let hover = client.request("textDocument/hover", at(1, 18));
+    let contents = hover.get("result").get("contents").get("value").as_str().unwrap();
+    assert_eq!(contents, "**loop** — label defined at line 1");
+
+    let hover = client.request("textDocument/hover", at(1, 13));
+    let contents = hover.get("result").get("contents").get("value").as_str().unwrap();
+    assert_eq!(contents, "**r2** — register number 2");
+
+    let completion = client.request("textDocument/completion", at(2, 4));
+    let labels: Vec<_> = completion.get("result").as_array().unwrap().iter()
+        .map(|item| item.get("label").as_str().unwrap().to_string())
+        .collect();
+    for expected in ["addi", "lcall", "sp", "r252", "loop"] {
+        assert!(labels.iter().any(|l| l == expected), "{expected} is not offered");
+    }
+
+    let unknown = client.request("workspace/symbol", obj(&[]));
+    assert_eq!(unknown.get("error").get("code").as_i64(), Some(-32601));
+
+    let shutdown = client.request("shutdown", Value::Null);
+    assert_eq!(*shutdown.get("result"), Value::Null);
+    let late = client.request("textDocument/hover", at(0, 0));
+    assert_eq!(late.get("error").get("code").as_i64(), Some(-32600));
+    client.notify("exit", Value::Null);
+    assert!(client.child.wait().unwrap().success());
+}
+
+#[test]
+fn exit_without_shutdown_fails() {
+    let mut client = Client::start();
+    client.request("initialize", obj(&[]));
+    client.notify("exit", Value::Null);
+    assert_eq!(client.child.wait().unwrap().code(), Some(1));
+}
+
+#[test]
+fn broken_headers_are_invalid_data() {
+    let read = |input: &str| read_message(&mut input.as_bytes()).map_err(|e| e.kind());
+    assert_eq!(read("Content-Length: 2\r\n\r\n{}"), Ok(Some("{}".to_string())));
+    assert_eq!(read(""), Ok(None));
+    assert_eq!(read("Content-Length: lots\r\n\r\n{}"), Err(io::ErrorKind::InvalidData));
+    assert_eq!(read("Content-Length: 99999999999999\r\n\r\n{}"), Err(io::ErrorKind::InvalidData));
+
+    // サーバはabortせずに失敗して終わる
+    let mut client = Client::start();
+    client.stdin.write_all(b"Content-Length: 18446744073709551615\r\n\r\n").unwrap();
+    assert!(!client.child.wait().unwrap().success());
+}
@@ -0,0 +1,113 @@
+mod common;
+
+use std::io;
+use asm_1st::encoder::encode;
+use asm_1st::lexer::{Mnemonic, Register};
+use asm_1st::parser::Instruction;
+use asm_1st::peephole::optimize;
+use asm_1st::resolver::resolve_without_optimization;
+use asm_1st::simulator::Machine;
+
+fn instructions(src: &str) -> Vec<Instruction> {
+    common::check(src).0
+}
+
+/// 残った命令の (ラベル, ニーモニック)
+fn optimized(src: &str) -> (Vec<(Vec<String>, Mnemonic)>, usize) {
+    let (inst, removed) = optimize(instructions(src));
+    (inst.into_iter().map(|i| (i.label, i.mnemonic)).collect(), removed)
+}
+
+fn labels(names: &[&str]) -> Vec<String> {
+    names.iter().map(|s| s.to_string()).collect()
+}
+
+#[test]
+fn redundant_instructions_are_removed_and_labels_move_forward() {
+    let src = "\
+main:
+    movl r1, 5
+    movh r1, 0
+    add r1, zero, r1
+a:  addi r2, r2, 0
+    j next
+next:
+    ibeq r1, r2, skip
+skip:
+    mov r3, r3
+end:
+    j end
+";
+    let (inst, removed) = optimized(src);
+    assert_eq!(removed, 6);
+    assert_eq!(inst, [
+        (labels(&["main"]), Mnemonic::Movl),
+        (labels(&["a", "next", "skip", "end"]), Mnemonic::J),
+    ]);
+}
+
+#[test]
+fn instructions_with_an_effect_are_kept() {
+    let src = "\
+    nop
+    addi r1, r2, 0
+    add r1, r1, r1
+    movl r1, 5
+    movh r1, 1
+    movl r2, 5
+m:  movh r2, 0
+    movl r3, 5
+    movh r4, 0
+    call f
+f:  ret
+";
+    let (inst, removed) = optimized(src);
+    assert_eq!(removed, 0);
+    assert_eq!(inst.len(), 11);
+
+    // 最後の命令のラベルは移す先が無い
+    let (inst, removed) = optimized("L: j L\nend: addi r1, r1, 0\n");
+    assert_eq!((inst.len(), removed), (2, 0));
+}
+
+#[test]
+fn optimized_program_computes_the_same_result() {
+    // fibのループに、取り除ける命令を混ぜたもの。ループの先頭のラベルも消える命令に付いている
+    let src = "\
+    movl r10, 0
+    movh r10, 0
+    movl r11, 20
+    movh r11, 0
+    addi r0, zero, 1
+    addi r1, zero, 1
+    addi r2, zero, 0
+LOOP:
+    add r0, zero, r0
+    add r2, zero, r1
+    add r1, zero, r0
+    j SUM
+SUM:
+    add r0, r1, r2
+    addi r10, r10, 0
+    addi r10, r10, 1
+    iblt r10, r11, NEXT
+NEXT:
+    iblt r10, r11, LOOP
+HALT:
+    j HALT
+";
+    let run = |inst: Vec<Instruction>| {
+        let binary = encode(resolve_without_optimization(inst).unwrap());
+        let mut machine = Machine::new(&binary, 1 << 12, Box::new(io::empty()), Box::new(io::sink()));
+        machine.run(Some(1_000_000)).unwrap();
+        let regs = [0, 1, 2, 10].map(|i| machine.reg(Register::R(i)));
+        (regs, binary.len())
+    };
+    let (before, len) = run(instructions(src));
+    let (inst, removed) = optimize(instructions(src));
+    let (after, optimized_len) = run(inst);
+    assert!(removed > 0);
+    assert_eq!(before, after);
+    assert_eq!(before[0], 17711);
+    assert_eq!(optimized_len + removed, len);
+}
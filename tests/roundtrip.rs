@@ -0,0 +1,321 @@
+//! ランダムに生成した正しい命令列を parse → semantic check → アドレス解決 → encode し、
+//! disassembleした結果が元の命令と一致することを確かめる
+
+mod common;
+
+use asm_1st::diagnostics::collect;
+use asm_1st::disassembler::{disassemble_word, format_instruction};
+use asm_1st::encoder::{set_isa, Isa, ARITH_IMM, LW_OFFSET, MOV_IMM, SW_OFFSET, Field};
+use asm_1st::lexer::{Mnemonic, Register};
+use asm_1st::lexer::Mnemonic::*;
+use asm_1st::parser::{Operand, Parser};
+use asm_1st::parser::Operand::*;
+use asm_1st::resolver::{is_pseudo_instr, report_relaxed_jumps, LINK_REGISTER, SCRATCH_REGISTER, WORD_SIZE};
+use asm_1st::semantics::{check_semantics, is_arithmetic, is_arithmetic_ext, is_arithmetic_imm, is_conditional_branch, is_conditional_branch_ext};
+use asm_1st::slice_lexer::SliceLexer;
+use common::assemble;
+
+/// 依存クレートを増やしたくないので、テスト用の簡単な疑似乱数 (xorshift64)
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, lo: i64, hi: i64) -> i64 {
+        lo + (self.next() % (hi - lo + 1) as u64) as i64
+    }
+
+    fn register(&mut self) -> Register {
+        match self.range(0, 9) {
+            0 => Register::Zero,
+            1 => Register::Sp,
+            2 => Register::Fp,
+            // r253..r255はsp, fp, zeroと同じ番号になるので使わない
+            _ => Register::R(self.range(0, 252) as u8),
+        }
+    }
+
+    /// 書き込み先になるレジスタ (zero以外)
+    fn dest_register(&mut self) -> Register {
+        loop {
+            let r = self.register();
+            if r != Register::Zero { return r; }
+        }
+    }
+
+    fn imm(&mut self, field: Field) -> Operand {
+        OpDigit(self.range(field.min(), field.max()))
+    }
+}
+
+fn machine_mnemonics() -> Vec<Mnemonic> {
+    Mnemonic::ALL.into_iter()
+        .filter(|m| !is_pseudo_instr(*m) && *m != Word)
+        .collect()
+}
+
+/// 命令を1つ生成する。ラベルを取るオペランドは、len個の命令のどれかを指すラベルにする
+fn generate(rng: &mut Rng, mnemonic: Mnemonic, len: usize) -> Vec<Operand> {
+    let reg = |r: Register| OpRegister(r);
+    let label = |rng: &mut Rng| OpLabel(format!("L{}", rng.range(0, len as i64 - 1)));
+
+    if is_arithmetic(mnemonic) {
+        let rd = reg(rng.dest_register());
+        let rs = reg(rng.register());
+        if is_arithmetic_imm(mnemonic) {
+            vec![rd, rs, rng.imm(ARITH_IMM)]
+        } else {
+            vec![rd, rs, reg(rng.register())]
+        }
+    } else if is_arithmetic_ext(mnemonic) {
+        vec![reg(rng.dest_register()), reg(rng.register())]
+    } else if is_conditional_branch(mnemonic) {
+        vec![reg(rng.register()), reg(rng.register()), label(rng)]
+    } else if is_conditional_branch_ext(mnemonic) {
+        vec![reg(rng.register()), label(rng)]
+    } else if mnemonic == J || mnemonic == Call {
+        vec![label(rng)]
+    } else if mnemonic == Jr || mnemonic == Usend {
+        vec![reg(rng.register())]
+    } else if mnemonic == Urecv {
+        vec![reg(rng.dest_register())]
+    } else if mnemonic == Movl || mnemonic == Movh {
+        vec![reg(rng.register()), rng.imm(MOV_IMM)]
+    } else if mnemonic == Lw {
+        vec![reg(rng.dest_register()), reg(rng.register()), rng.imm(LW_OFFSET)]
+    } else if mnemonic == Sw {
+        vec![reg(rng.register()), reg(rng.register()), rng.imm(SW_OFFSET)]
+    } else {
+        unreachable!("{mnemonic} is not a machine instruction")
+    }
+}
+
+#[test]
+fn every_machine_instruction_round_trips() {
+    let mnemonics = machine_mnemonics();
+    let mut rng = Rng(0x2545f4914f6cdd1d);
+
+    for _ in 0..50 {
+        let len = 200;
+        let program: Vec<_> = (0..len)
+            .map(|i| {
+                // 最初の一巡で全てのニーモニックを必ず1回は使う
+                let m = if i < mnemonics.len() {
+                    mnemonics[i]
+                } else {
+                    mnemonics[rng.range(0, mnemonics.len() as i64 - 1) as usize]
+                };
+                (m, generate(&mut rng, m, len))
+            })
+            .collect();
+
+        let src: String = program.iter().enumerate()
+            .map(|(i, (m, operands))| format!("L{i}: {}\n", format_instruction(*m, operands)))
+            .collect();
+        let binary = assemble(&src);
+        assert_eq!(binary.len(), program.len());
+
+        for (i, ((m, operands), word)) in program.into_iter().zip(binary).enumerate() {
+            // ラベルは相対アドレスに解決されているはず
+            let expected: Vec<_> = operands.into_iter()
+                .map(|op| match op {
+                    OpLabel(s) => OpDigit(s[1..].parse::<i64>().unwrap() - i as i64),
+                    op => op,
+                })
+                .collect();
+
+            let actual = disassemble_word(word);
+            assert_eq!(
+                actual, Some((m, expected.clone())),
+                "line {}: {} was encoded as {word:08x}", i + 1, format_instruction(m, &expected),
+            );
+        }
+    }
+}
+
+fn decode_all(binary: &[u32]) -> Vec<(Mnemonic, Vec<Operand>)> {
+    binary.iter().map(|w| disassemble_word(*w).unwrap()).collect()
+}
+
+#[test]
+fn long_branches_expand_to_negated_branch_and_jump() {
+    let r = |n| OpRegister(Register::R(n));
+    let zero = OpRegister(Register::Zero);
+
+    for (pseudo, negated) in [(Libeq, Ibne), (Libne, Ibeq), (Liblt, Ible), (Lible, Iblt), (Lfblt, Fble), (Lfble, Fblt)] {
+        let binary = assemble(&format!("L0: {pseudo} r1, r2, L0"));
+        assert_eq!(decode_all(&binary), vec![
+            (negated, vec![r(2), r(1), OpDigit(2)]),
+            (J, vec![OpDigit(-1)]),
+        ], "{pseudo}");
+    }
+
+    for (pseudo, negated) in [(Lfbps, Fbng), (Lfbng, Fbps)] {
+        let binary = assemble(&format!("L0: {pseudo} r3, L0"));
+        assert_eq!(decode_all(&binary), vec![
+            (negated, vec![r(3), OpDigit(3)]),
+            (Ibeq, vec![r(3), zero.clone(), OpDigit(2)]),
+            (J, vec![OpDigit(-2)]),
+        ], "{pseudo}");
+    }
+}
+
+#[test]
+fn memory_offsets_follow_the_selected_isa() {
+    let r = |n| OpRegister(Register::R(n));
+    let src = "lw r1, r2, -1\nsw r1, r2, -128\n";
+    let checked = |src: &str| {
+        let (inst, labels) = Parser::new(SliceLexer::new(src.as_bytes()).tokens()).parse().unwrap();
+        collect(|| check_semantics(&inst, &labels).is_ok()).0
+    };
+    // 既定のコアはオフセットを符号拡張しない
+    assert!(!checked(src));
+    assert_eq!(decode_all(&assemble("lw r1, r2, 255\n")), vec![(Lw, vec![r(1), r(2), OpDigit(255)])]);
+
+    // テストはスレッドごとに走るので、他のテストには影響しない
+    set_isa(Isa::from_name("1st-signed-offset").unwrap());
+    assert!(checked(src));
+    assert!(!checked("lw r1, r2, 128\n"));
+    assert_eq!(decode_all(&assemble(src)), vec![
+        (Lw, vec![r(1), r(2), OpDigit(-1)]),
+        (Sw, vec![r(1), r(2), OpDigit(-128)]),
+    ]);
+    set_isa(Isa::default());
+    assert_eq!(Isa::from_name("2nd"), None);
+}
+
+#[test]
+fn data_and_float_pseudo_instructions() {
+    let r = |n| OpRegister(Register::R(n));
+    let binary = assemble("lif r4, 1.5\nlif r5, 0fdeadbeef\n.ascii \"Hi\"\n.word -1\n");
+    assert_eq!(decode_all(&binary[..4]), vec![
+        (Movl, vec![r(4), OpDigit(0x0000)]),
+        (Movh, vec![r(4), OpDigit(0x3fc0)]),
+        (Movl, vec![r(5), OpDigit(0xbeef)]),
+        (Movh, vec![r(5), OpDigit(0xdead)]),
+    ]);
+    assert_eq!(&binary[4..], &[b'H' as u32, b'i' as u32, 0xffffffff]);
+}
+
+#[test]
+fn calling_convention_pseudo_instructions() {
+    let r = |n| OpRegister(Register::R(n));
+    let (sp, fp, zero) = (OpRegister(Register::Sp), OpRegister(Register::Fp), OpRegister(Register::Zero));
+    let lr = OpRegister(LINK_REGISTER);
+    let w = WORD_SIZE;
+
+    let binary = assemble("f: enter 3\npush r1, r2\npop r1, r2\nleave\nret\nenter 0; call f\n");
+    assert_eq!(decode_all(&binary), vec![
+        (Subi, vec![sp.clone(), sp.clone(), OpDigit(2 * w)]),
+        (Sw, vec![fp.clone(), sp.clone(), OpDigit(0)]),
+        (Sw, vec![lr.clone(), sp.clone(), OpDigit(w)]),
+        (Add, vec![fp.clone(), sp.clone(), zero.clone()]),
+        (Subi, vec![sp.clone(), sp.clone(), OpDigit(3)]),
+        (Subi, vec![sp.clone(), sp.clone(), OpDigit(2 * w)]),
+        (Sw, vec![r(1), sp.clone(), OpDigit(0)]),
+        (Sw, vec![r(2), sp.clone(), OpDigit(w)]),
+        (Lw, vec![r(1), sp.clone(), OpDigit(0)]),
+        (Lw, vec![r(2), sp.clone(), OpDigit(w)]),
+        (Addi, vec![sp.clone(), sp.clone(), OpDigit(2 * w)]),
+        (Add, vec![sp.clone(), fp.clone(), zero.clone()]),
+        (Lw, vec![fp.clone(), sp.clone(), OpDigit(0)]),
+        (Lw, vec![lr.clone(), sp.clone(), OpDigit(w)]),
+        (Addi, vec![sp.clone(), sp.clone(), OpDigit(2 * w)]),
+        (Jr, vec![lr]),
+        // enter 0は領域を確保しない
+        (Subi, vec![sp.clone(), sp.clone(), OpDigit(2 * w)]),
+        (Sw, vec![fp.clone(), sp.clone(), OpDigit(0)]),
+        (Sw, vec![OpRegister(LINK_REGISTER), sp.clone(), OpDigit(w)]),
+        (Add, vec![fp, sp, zero]),
+        // 展開後のアドレスで解決される
+        (Call, vec![OpDigit(-20)]),
+    ]);
+}
+
+#[test]
+fn convenience_pseudo_instructions() {
+    let r = |n| OpRegister(Register::R(n));
+    let zero = OpRegister(Register::Zero);
+
+    let binary = assemble("L0: nop\nmov r1, r2\nfmov r3, r4\nneg r5, r6\nnot r7, r8\nb L0\nibgt r1, r2, L0\nibge r1, r2, L0\nfbgt r1, r2, L0\nfbge r1, r2, L0\n");
+    assert_eq!(decode_all(&binary), vec![
+        (Add, vec![zero.clone(), zero.clone(), zero.clone()]),
+        (Add, vec![r(1), r(2), zero.clone()]),
+        (Add, vec![r(3), r(4), zero.clone()]),
+        (Sub, vec![r(5), zero.clone(), r(6)]),
+        (Sub, vec![r(7), zero.clone(), r(8)]),
+        (Subi, vec![r(7), r(7), OpDigit(1)]),
+        // notが2命令に展開された分、後ろのアドレスがずれる
+        (Ibeq, vec![zero.clone(), zero, OpDigit(-6)]),
+        (Iblt, vec![r(2), r(1), OpDigit(-7)]),
+        (Ible, vec![r(2), r(1), OpDigit(-8)]),
+        (Fblt, vec![r(2), r(1), OpDigit(-9)]),
+        (Fble, vec![r(2), r(1), OpDigit(-10)]),
+    ]);
+}
+
+#[test]
+fn far_jumps_are_relaxed_to_absolute_jumps() {
+    let (lr, s) = (OpRegister(LINK_REGISTER), OpRegister(SCRATCH_REGISTER));
+    let data = "x".repeat(40000);
+
+    // j/callは届かないのでlj/lcallに置き換えられる
+    let binary = assemble(&format!("L0: j far\n.ascii \"{data}\"\nfar: call L0\nlj L0\nlcall far\n"));
+    let far = 3 + 40000;
+    assert_eq!(binary.len(), far + 13);
+    assert_eq!(decode_all(&binary[..3]), vec![
+        (Movl, vec![s.clone(), OpDigit(far as i64)]),
+        (Movh, vec![s.clone(), OpDigit(0)]),
+        (Jr, vec![s.clone()]),
+    ]);
+    assert_eq!(decode_all(&binary[far..]), vec![
+        (Movl, vec![lr.clone(), OpDigit(far as i64 + 5)]),
+        (Movh, vec![lr.clone(), OpDigit(0)]),
+        (Movl, vec![s.clone(), OpDigit(0)]),
+        (Movh, vec![s.clone(), OpDigit(0)]),
+        (Jr, vec![s.clone()]),
+        (Movl, vec![s.clone(), OpDigit(0)]),
+        (Movh, vec![s.clone(), OpDigit(0)]),
+        (Jr, vec![s.clone()]),
+        // 届く場合でもlcallは展開される
+        (Movl, vec![lr.clone(), OpDigit(far as i64 + 13)]),
+        (Movh, vec![lr, OpDigit(0)]),
+        (Movl, vec![s.clone(), OpDigit(far as i64)]),
+        (Movh, vec![s.clone(), OpDigit(0)]),
+        (Jr, vec![s]),
+    ]);
+}
+
+#[test]
+fn relaxed_jumps_are_warned() {
+    // 届かないj/callだけを警告し、書いたとおりのlj/lcallや届くjは警告しない
+    let src = format!("L0: j far\nj L0\n.ascii \"{}\"\nfar: call L0\nlj L0\nlcall far\n", "x".repeat(40000));
+    let (inst, labels) = Parser::new(SliceLexer::new(src.as_bytes()).tokens()).parse().unwrap();
+    check_semantics(&inst, &labels).unwrap();
+    let (count, diagnostics) = collect(|| report_relaxed_jumps(&inst));
+    assert_eq!(count, 2);
+    let warnings: Vec<_> = diagnostics.iter().map(|d| (d.line, d.column, d.code)).collect();
+    assert_eq!(warnings, vec![(1, 5, "W0201"), (4, 6, "W0201")]);
+    assert_eq!(diagnostics[0].message, "j to \"far\" is out of range and is replaced with lj, which overwrites r251.");
+    assert_eq!(diagnostics[1].message, "call to \"L0\" is out of range and is replaced with lcall, which overwrites r251.");
+}
+
+#[test]
+fn every_pseudo_instruction_is_covered() {
+    // 疑似命令を追加したら、上のテストにも追加すること
+    let covered = [
+        Libeq, Libne, Liblt, Lible, Lfblt, Lfble, Lfbps, Lfbng, Lif, Ascii, Word,
+        Ret, Push, Pop, Enter, Leave,
+        Nop, Mov, Fmov, Neg, Not, B, Ibgt, Ibge, Fbgt, Fbge, Lj, Lcall,
+    ];
+    for m in Mnemonic::ALL {
+        if is_pseudo_instr(m) || m == Word {
+            assert!(covered.contains(&m), "{m} is not covered by the round-trip tests");
+        }
+    }
+}
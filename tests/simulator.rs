@@ -0,0 +1,154 @@
+mod common;
+
+use std::cell::RefCell;
+use std::io::{self, Read};
+use std::rc::Rc;
+use std::time::Duration;
+use asm_1st::encoder::{set_isa, Isa};
+use asm_1st::lexer::Register;
+use asm_1st::simulator::{Machine, SimError};
+use asm_1st::uart::{write_transcript, Direction, StreamUart, Uart, UartEvent};
+use common::assemble;
+
+fn run(src: &str, input: &'static [u8]) -> Result<Machine, SimError> {
+    let binary = assemble(src);
+    let mut machine = Machine::new(&binary, 1 << 12, Box::new(input), Box::new(io::sink()));
+    machine.run(Some(1_000_000))?;
+    Ok(machine)
+}
+
+#[test]
+fn signed_memory_offsets_reach_below_the_base() {
+    set_isa(Isa { signed_memory_offset: true });
+    let machine = run("addi r1, zero, 10\naddi r2, zero, 42\nsw r2, r1, -3\nlw r3, r1, -3\nend: j end\n", b"");
+    set_isa(Isa::default());
+    let machine = machine.unwrap();
+    assert_eq!(machine.memory[7], 42);
+    assert_eq!(machine.reg(Register::R(3)), 42);
+}
+
+#[test]
+fn fib_computes_fibonacci_numbers() {
+    let src = std::fs::read_to_string(concat!(env!("CARGO_MANIFEST_DIR"), "/fib_asm.txt")).unwrap();
+    let machine = run(&src, b"").unwrap();
+
+    let (mut a, mut b) = (1_u32, 1_u32);
+    for _ in 0..20 {
+        (a, b) = (a + b, a);
+    }
+    assert_eq!(machine.reg(Register::R(0)), a);
+    assert_eq!(machine.reg(Register::R(1)), b);
+}
+
+#[test]
+fn calls_stack_and_floats() {
+    let src = "\
+        lif r1, 1.5
+        lif r2, 2.25
+        call f
+        ftoi r4, r3
+        push r4
+        pop r5
+        halt: j halt
+        f: enter 1
+        fmul r3, r1, r2
+        leave
+        ret
+    ";
+    let machine = run(src, b"").unwrap();
+    assert_eq!(f32::from_bits(machine.reg(Register::R(3))), 3.375);
+    assert_eq!(machine.reg(Register::R(5)), 3);
+    // enter/leaveとpush/popでspは元に戻る
+    assert_eq!(machine.reg(Register::Sp), 1 << 12);
+}
+
+#[test]
+fn runtime_errors() {
+    assert!(matches!(run("urecv r1\nL: j L\n", b""), Err(SimError::UartEofError { pc: 0 })));
+    assert!(run("lw r1, zero, 0\nL: j L\n.word 0\n", b"").is_ok());
+    assert!(matches!(run("addi r1, zero, 1\n.word 0\n", b""), Err(SimError::InvalidInstructionError { pc: 1, word: 0 })));
+    assert!(matches!(run("L: j L2\nL2: j L\n", b""), Err(SimError::StepLimitError { .. })));
+}
+
+/// 3バイト受け取って、そのまま送り返す
+const ECHO: &str = "\
+    addi r3, zero, 3
+L:  urecv r1
+    usend r1
+    addi r2, r2, 1
+    ibne r2, r3, L
+end:
+    j end
+";
+
+/// 受け取るたびに次の文字を返し、送られたものを共有のバッファに貯める
+struct Counter {
+    next: u8,
+    sent: Rc<RefCell<Vec<u8>>>,
+}
+
+impl Uart for Counter {
+    fn recv(&mut self) -> io::Result<Option<u8>> {
+        self.next += 1;
+        Ok(Some(self.next))
+    }
+
+    fn send(&mut self, byte: u8) -> io::Result<()> {
+        self.sent.borrow_mut().push(byte);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn the_uart_can_be_replaced() {
+    let binary = assemble(ECHO);
+    let sent = Rc::new(RefCell::new(vec![]));
+    let uart = Counter { next: b'a' - 1, sent: sent.clone() };
+    let mut machine = Machine::with_uart(&binary, 1 << 12, Box::new(uart));
+    machine.run(Some(1000)).unwrap();
+    assert_eq!(*sent.borrow(), b"abc");
+}
+
+#[test]
+fn scripted_input_and_transcript() {
+    let binary = assemble(ECHO);
+    let uart = StreamUart::scripted(b"hi\n".to_vec(), Box::new(io::sink()));
+    let mut machine = Machine::with_uart(&binary, 1 << 12, Box::new(uart));
+    machine.record_transcript();
+    machine.run(Some(1000)).unwrap();
+
+    let events = machine.transcript.unwrap();
+    assert_eq!(events.len(), 6);
+    assert_eq!(events[0], UartEvent { step: 1, direction: Direction::Recv, byte: b'h' });
+    assert_eq!(events[1], UartEvent { step: 2, direction: Direction::Send, byte: b'h' });
+    let mut text = vec![];
+    write_transcript(&events[4..], &mut text).unwrap();
+    assert_eq!(String::from_utf8(text).unwrap(), "9 recv 0x0a\n10 send 0x0a\n");
+}
+
+/// 何も読めないまま待ち続ける入力
+struct Stalled;
+
+impl Read for Stalled {
+    fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+        std::thread::sleep(Duration::from_secs(60));
+        Ok(0)
+    }
+}
+
+#[test]
+fn urecv_times_out_or_reaches_the_end() {
+    let binary = assemble(ECHO);
+    let uart = StreamUart::with_timeout(Box::new(Stalled), Box::new(io::sink()), Duration::from_millis(50));
+    let mut machine = Machine::with_uart(&binary, 1 << 12, Box::new(uart));
+    assert!(matches!(machine.run(Some(1000)), Err(SimError::UartTimeoutError { pc: 1 })));
+
+    // 入力が尽きたら、待たずに終わりとみなす
+    let uart = StreamUart::with_timeout(Box::new(&b"a"[..]), Box::new(io::sink()), Duration::from_secs(60));
+    let mut machine = Machine::with_uart(&binary, 1 << 12, Box::new(uart));
+    assert!(matches!(machine.run(Some(1000)), Err(SimError::UartEofError { pc: 1 })));
+}
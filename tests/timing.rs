@@ -0,0 +1,105 @@
+mod common;
+
+use std::io;
+use asm_1st::lexer::Mnemonic;
+use asm_1st::simulator::Machine;
+use asm_1st::timing::{StallCause, Timing, TimingModel};
+use asm_1st::uart::StreamUart;
+use common::assemble;
+
+fn time(src: &str, model: TimingModel, input: &[u8]) -> Timing {
+    let binary = assemble(src);
+    let uart = StreamUart::scripted(input.to_vec(), Box::new(io::sink()));
+    let mut machine = Machine::with_uart(&binary, 1 << 12, Box::new(uart));
+    machine.enable_timing(model);
+    machine.run(Some(1_000_000)).unwrap();
+    let timing = machine.timing.unwrap();
+    // 1サイクルに1命令ずつ発行し、止まった分と最後の命令がパイプラインを抜ける分だけ延びる
+    let depth = timing.model.pipeline_depth as u64;
+    assert_eq!(timing.cycles(), timing.instructions - 1 + timing.total_stalls() + depth);
+    timing
+}
+
+fn stalls(timing: &Timing, cause: StallCause) -> u64 {
+    timing.stalls.get(&cause).copied().unwrap_or(0)
+}
+
+#[test]
+fn straight_line_code_without_dependencies_does_not_stall() {
+    let timing = time("addi r1, zero, 1\naddi r2, zero, 2\nL: j L\n", TimingModel::default(), b"");
+    assert_eq!(timing.instructions, 3);
+    assert_eq!(timing.total_stalls(), 0);
+    assert_eq!(timing.cycles(), 3 - 1 + 5);
+}
+
+#[test]
+fn results_are_waited_for_according_to_the_model() {
+    let src = "\
+    lw r1, zero, 0
+    add r2, r1, r1
+    fdiv r3, r2, r2
+    fadd r4, r3, r3
+L:  j L
+";
+    let timing = time(src, TimingModel::default(), b"");
+    assert_eq!(stalls(&timing, StallCause::Data(Mnemonic::Lw)), 1);
+    assert_eq!(stalls(&timing, StallCause::Data(Mnemonic::Fdiv)), 7);
+    assert_eq!(timing.total_stalls(), 8);
+
+    let slow = TimingModel::from_json(r#"{"forwarding": false, "register_file_delay": 1, "latency": {"fdiv": 20}}"#).unwrap();
+    let timing = time(src, slow, b"");
+    assert_eq!(stalls(&timing, StallCause::Data(Mnemonic::Lw)), 2);
+    assert_eq!(stalls(&timing, StallCause::Data(Mnemonic::Add)), 1);
+    assert_eq!(stalls(&timing, StallCause::Data(Mnemonic::Fdiv)), 20);
+}
+
+#[test]
+fn taken_branches_and_jumps_pay_the_penalty() {
+    let src = "\
+    addi r2, zero, 3
+L:  addi r1, r1, 1
+    ibne r1, r2, L
+    call f
+end:
+    j end
+f:  ret
+";
+    let model = TimingModel::from_json(r#"{"branch_penalty": 3}"#).unwrap();
+    let timing = time(src, model, b"");
+    // ibneが2回成立し、callとret (jr) で2回。停止するjは数えない
+    assert_eq!(stalls(&timing, StallCause::Branch), 4 * 3);
+}
+
+#[test]
+fn the_uart_is_limited_by_the_baud_rate() {
+    let src = "\
+    urecv r1
+    usend r1
+    usend r1
+L:  j L
+";
+    // 1バイトは10ビットなので、1バイトに100サイクル
+    let model = TimingModel::from_json(r#"{"clock_hz": 1000, "baud_rate": 100}"#).unwrap();
+    assert_eq!(model.uart_cycles_per_byte, 100);
+    let timing = time(src, model, b"x");
+    assert_eq!(stalls(&timing, StallCause::UartRecv), 100);
+    // 2回目のusendは1回目の次のサイクルに発行できるので、残りの99サイクルを待つ
+    assert_eq!(stalls(&timing, StallCause::UartSend), 99);
+
+    let report = timing.to_string();
+    assert!(report.starts_with(&format!("{} cycles for 4 instructions", timing.cycles())), "{report}");
+    assert!(report.contains("100  urecv\n"), "{report}");
+}
+
+#[test]
+fn invalid_models_are_rejected() {
+    for src in [
+        r#"{"pipeline_depth": 0}"#,
+        r#"{"clock_hz": 1000}"#,
+        r#"{"clock_hz": 1000, "baud_rate": 0}"#,
+        r#"{"latency": {"lw": "slow"}}"#,
+        r#"{"stages": 5}"#,
+    ] {
+        assert!(TimingModel::from_json(src).is_err(), "{src}");
+    }
+}